@@ -1,60 +1,236 @@
-extern crate unicode_segmentation;
 
-use std::collections::VecDeque;
-use std::iter::FromIterator;
 
-use std::io::{Result};
+use std::io::{BufWriter, ErrorKind, Result};
 use std::io::prelude::*;
 
+use std::env;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::process;
 
 use super::{Coord, Direction, Range, Yank};
 
-use editor::command::{Command, Span, Motion, Line, Column};
+use editor::columns::{self, Layout, Row};
+use editor::command::{Command, Span, Motion, Line, Column, Operator, SkipThing};
+use editor::file::{self, Encoding, Format};
+use editor::motions;
+use editor::registers::Register;
+use editor::search::{Offset, Regex};
+use editor::swap::Swap;
+use editor::text::Text;
+use editor::undo::{self, Change, History, Leaf, Step, Travel};
 use std::cmp;
+use std::collections::HashMap;
 
-use self::unicode_segmentation::UnicodeSegmentation;
+
+// how many spaces `<` takes off when a line isn't indented with a tab
+const SHIFTWIDTH: usize = 8;
 
 #[derive(Debug)]
 pub struct Buffer {
 	pub name: PathBuf,
 	point: Coord,
-	mark: Option<Coord>,
+	marks: HashMap<char, Coord>,
+	// the screen column `j` and `k` try to keep to
+	curswant: Option<usize>,
+	// how lines are laid out in the window
+	layout: Layout,
+	// the first screen column shown when lines don't wrap, as `offset`
+	// is the first line shown
+	leftcol: usize,
 	window: (usize, usize),
 	offset: usize,
-	pub lines: VecDeque<String>,
+	pub lines: Text,
+	history: History,
+	// the undo state the file was last written (or read) at
+	saved: usize,
+	// how the file's lines end, so they're written back the same way
+	pub format: Format,
+	saved_format: Format,
+	// opened without a swap file because another vex has it open, so
+	// only `:w!` writes it
+	pub readonly: bool,
+	// some of the file didn't decode, so writing it back would lose those
+	// bytes; only `:w!` or reading it in another encoding gets past that
+	pub undecodable: bool,
+	// how many times the text has changed, and what that was when the
+	// swap file was last brought up to date
+	changes: usize,
+	swap: Option<PathBuf>,
+	swapped: Option<usize>,
+	// lines `:g` has still to get to, which move along with the text
+	// around them and go if it's deleted
+	marked: Vec<usize>,
+	// whether checkpoints are being held off, so a `:g` is one undo step
+	grouping: bool,
 }
 
 impl Buffer {
     /// Constructs a new 
 	pub fn new(name: &Path, width: usize, height: usize) -> Buffer {
+		let mut lines = Text::new();
+		lines.push_back("".to_owned());
 		Buffer {
 			name: name.to_path_buf(),
 			point: (0, 0),
-			mark: None,
+			marks: HashMap::new(),
+			curswant: None,
+			layout: Layout::new(),
+			leftcol: 0,
 	    	window: (width, height),
 	    	offset: 0,
-	    	lines: VecDeque::new(),
+	    	lines,
+	    	history: History::new(),
+	    	saved: 0,
+	    	format: Format::new(),
+	    	saved_format: Format::new(),
+	    	readonly: false,
+	    	undecodable: false,
+	    	changes: 0,
+	    	swap: None,
+	    	swapped: None,
+	    	marked: Vec::new(),
+	    	grouping: false,
 		}
 	}
+	// A buffer that isn't for any file yet.
 	pub fn new_empty(width: usize, height: usize) -> Buffer {
-		let mut lines = VecDeque::with_capacity(height);
-		lines.push_front("".to_owned());
-		Buffer {
-			name: PathBuf::from("untitled"),
-			point: (0, 0),
-			mark: None,
-	    	window: (width, height),
-	    	offset: 0,
-	    	lines: lines,
-		}
+		Buffer::new(Path::new(""), width, height)
 	}
-	pub fn load_reader<B>(&mut self, reader: B) where B: BufRead {
-	    let lines = reader.lines().filter_map(|l| l.ok());
-		self.lines = VecDeque::from_iter(lines);
+	// Reads in a file, decoding it from `encoding` or whatever it seems to
+	// be in, or byte for byte if it's `binary`. Returns whether any of it
+	// couldn't be decoded.
+	pub fn load_reader<R>(&mut self, reader: R, encoding: Option<&'static Encoding>, binary: bool) -> Result<bool>
+	    where R: Read
+	{
+	    let (lines, format, invalid) = Format::read(reader, encoding, binary)?;
+		self.lines = lines.into_iter().collect();
+		if self.lines.len() == 0 {
+		    // even an empty file has a line to put the cursor on
+		    self.lines.push_back(String::new());
+		}
+		self.format = format.clone();
+		self.saved_format = format;
+		self.undecodable = invalid;
+		Ok(invalid)
 	}
 	pub fn name(&self) -> &str {
-	    self.name.file_name().unwrap().to_str().unwrap()
+	    match self.name.file_name() {
+	        Some(name) => name.to_str().unwrap_or("?"),
+	        None => "[No Name]",
+	    }
+	}
+	pub fn has_name(&self) -> bool {
+	    !self.name.as_os_str().is_empty()
+	}
+	// Whether the text has changed since it was last written, going by
+	// where it is in the undo history, so undoing back to what was
+	// written counts as unchanged.
+	pub fn is_modified(&self) -> bool {
+	    self.history.is_pending() || self.history.seq() != self.saved || self.format != self.saved_format
+	}
+	// Notes that the text as it is now is what's in the file.
+	pub fn set_saved(&mut self) {
+	    self.history.commit();
+	    self.saved = self.history.seq();
+	    self.saved_format = self.format.clone();
+	}
+	// Keeps a copy of the text in the swap file at `path` from now on.
+	// Takes on the swap file at `path`, writing it straight away so another
+	// vex opening the file can tell it's being edited.
+	pub fn set_swap(&mut self, path: PathBuf) -> Result<()> {
+	    self.swap = Some(path);
+	    self.swapped = None;
+	    self.write_swap()
+	}
+	pub fn has_swap(&self) -> bool {
+	    self.swap.is_some()
+	}
+	// Brings the swap file up to date, if there is one and it's behind.
+	pub fn write_swap(&mut self) -> Result<()> {
+	    if let Some(ref path) = self.swap {
+	        if self.swapped != Some(self.changes) {
+	            Swap::write(path, &self.name, self.lines.iter())?;
+	            self.swapped = Some(self.changes);
+	        }
+	    }
+	    Ok(())
+	}
+	// Done with the buffer, so it won't need recovering.
+	pub fn remove_swap(&mut self) {
+	    if let Some(path) = self.swap.take() {
+	        let _ = fs::remove_file(path);
+	    }
+	}
+	// Saves the text somewhere it can be got back from when vex has to stop
+	// without a chance to ask: the swap file if there is one, otherwise a
+	// file beside the original, or in the temp directory for a buffer with
+	// no name. Says where it went.
+	pub fn preserve(&mut self) -> Result<PathBuf> {
+	    if let Some(path) = self.swap.clone() {
+	        self.swapped = None;
+	        self.write_swap()?;
+	        return Ok(path);
+	    }
+	    let stem = if self.has_name() { self.name.clone() } else { env::temp_dir().join("vex") };
+	    let mode = fs::metadata(&self.name).map_or(0o600, |meta| file::mode(&meta) & 0o666);
+	    // always a new file, so nothing already there, like a symlink
+	    // someone's left in the temp directory, gets written through
+	    let mut tries = 0;
+	    let (path, f) = loop {
+	        let mut name = stem.clone().into_os_string();
+	        name.push(format!(".{}", process::id()));
+	        if tries > 0 {
+	            name.push(format!(".{}", tries));
+	        }
+	        name.push(".recover");
+	        let path = PathBuf::from(name);
+	        match file::create(&path, mode) {
+	            Ok(f) => break (path, f),
+	            Err(ref e) if e.kind() == ErrorKind::AlreadyExists && tries < 100 => tries += 1,
+	            Err(e) => return Err(e),
+	        }
+	    };
+	    let mut w = BufWriter::new(f);
+	    let last = self.lines.len() - 1;
+	    self.write_lines(&mut w, 0, last)?;
+	    w.into_inner()?.sync_all()?;
+	    Ok(path)
+	}
+	// Puts back the text from a swap file, as a change that can be undone.
+	pub fn recover(&mut self, lines: Vec<String>) {
+	    let count = self.lines.len();
+	    self.splice(0, count, lines);
+	    self.history.commit();
+	    self.point = (0, 0);
+	    self.offset = 0;
+	}
+	// Swaps the text for a hex dump of the bytes it makes, or back again,
+	// as one change that can be undone.
+	pub fn toggle_hex(&mut self) -> Result<()> {
+	    let mut lines = self.format.toggle_hex(self.lines.iter())?;
+	    if lines.is_empty() {
+	        lines.push(String::new());
+	    }
+	    let count = self.lines.len();
+	    self.history.commit();
+	    self.splice(0, count, lines);
+	    self.history.record_hex();
+	    self.history.commit();
+	    self.point = (0, 0);
+	    self.offset = 0;
+	    Ok(())
+	}
+	// Writes lines `first` to `last` the way the file had them, returning
+	// how many bytes that took.
+	pub fn write_lines<W: Write>(&self, w: &mut W, first: usize, last: usize) -> Result<usize> {
+	    let end = self.lines.len() - 1;
+	    // a buffer with nothing in it is an empty file, not one empty line
+	    if first == 0 && end == 0 && self.lines[0].is_empty() {
+	        return self.format.write(w, [].iter(), true, true);
+	    }
+	    let lines = self.lines.iter_at(first).take(last + 1 - first);
+	    self.format.write(w, lines, first == 0, last == end)
 	}
 	pub fn point(&self) -> Coord {
 	    self.point
@@ -65,60 +241,395 @@ impl Buffer {
 	pub fn offset(&self) -> usize {
 	    self.offset
 	}
-    pub fn delete_line(&mut self, i: usize) {
-        self.lines.remove(i);
-        let numlines = self.lines.len() - 1;
-        if self.point.1 > numlines {
-        	self.point.1 = numlines;
-        }
-        if self.point.1 < self.offset {
-        	self.offset = self.point.1;
-        }
-    }
+	// The point and the first line shown, to put back with `set_view`
+	// after showing something else for a while.
+	pub fn view(&self) -> (Coord, usize) {
+	    (self.point, self.offset)
+	}
+	pub fn set_view(&mut self, view: (Coord, usize)) {
+	    self.point = view.0;
+	    self.offset = view.1;
+	    self.window_to_point();
+	}
+	// Moves the point to `at`, scrolling to show it.
+	pub fn show(&mut self, at: Coord) {
+	    self.point = at;
+	    self.window_to_point();
+	}
+	pub fn mark(&self, name: char) -> Option<Coord> {
+	    self.marks.get(&name).cloned()
+	}
+	pub fn set_mark(&mut self, name: char, at: Coord) {
+	    self.marks.insert(name, at);
+	}
+	// The next line after `from` (or before it, going backwards) that `re`
+	// matches, wrapping around the end of the buffer.
+	pub fn find_line(&self, re: &Regex, from: usize, forward: bool) -> Option<usize> {
+	    let len = self.lines.len();
+	    (1..len + 1)
+	        .map(|i| if forward { (from + i) % len } else { (from + len - i) % len })
+	        .find(|&l| re.is_match(&self.lines[l]))
+	}
+	// The next match of `re` after `from` (or the last one before it,
+	// going backwards) as where it starts and ends, and whether the search
+	// went round the end of the buffer to find it, which it only does with
+	// `wrap`.
+	pub fn find(&self, re: &Regex, from: Coord, forward: bool, wrap: bool) -> Option<(Coord, Coord, bool)> {
+	    let len = self.lines.len();
+	    let (col, l) = from;
+	    // the point's line is looked at twice: the part past the point first,
+	    // and the rest once everything else has been
+	    for i in 0..len + 1 {
+	        let wrapped = if forward { l + i >= len } else { i > l };
+	        if wrapped && !wrap {
+	            return None;
+	        }
+	        let n = if forward { (l + i) % len } else { (l + len - i) % len };
+	        let line = &self.lines[n];
+	        let found = if forward {
+	            let start = if i == 0 { columns::next(line, col) } else { 0 };
+	            re.find_at(line, start).filter(|m| i < len || m.start() <= col)
+	        } else {
+	            re.find_iter(line)
+	                .filter(|m| if i == 0 { m.start() < col } else { i < len || m.start() >= col })
+	                .last()
+	        };
+	        if let Some(m) = found {
+	            return Some(((m.start(), n), (m.end(), n), wrapped));
+	        }
+	    }
+	    None
+	}
+	// How many matches of `re` there are, and which of them is the one
+	// starting at `at`, counting no higher than `max` so a big buffer
+	// doesn't hold things up.
+	pub fn count_matches(&self, re: &Regex, at: Coord, max: usize) -> (usize, usize) {
+	    let mut which = 0;
+	    let mut total = 0;
+	    for (l, line) in self.lines.iter().enumerate() {
+	        for m in re.find_iter(line) {
+	            total += 1;
+	            if (l, m.start()) <= (at.1, at.0) {
+	                which += 1;
+	            }
+	            if total > max {
+	                return (which, total);
+	            }
+	        }
+	    }
+	    (which, total)
+	}
+	// Where a search with `offset` leaves the point, for a match from
+	// `start` to `end`.
+	pub fn offset_match(&self, start: Coord, end: Coord, offset: Offset) -> Coord {
+	    let (from, n) = match offset {
+	        Offset::Lines(n) => {
+	            let l = cmp::max((start.1 as isize).saturating_add(n), 0) as usize;
+	            return (0, cmp::min(l, self.lines.len() - 1));
+	        }
+	        Offset::Start(n) => (start, n),
+	        // from the last character of the match
+	        Offset::End(n) => {
+	            let line = &self.lines[end.1];
+	            (if end.0 > start.0 { (columns::prev(line, end.0), end.1) } else { start }, n)
+	        }
+	    };
+	    let line = &self.lines[from.1];
+	    let mut col = columns::snap(line, from.0);
+	    // stopping at either end of the line, however far the offset goes
+	    for _ in 0..n.unsigned_abs() {
+	        let next = if n > 0 { columns::next(line, col) } else { columns::prev(line, col) };
+	        if next == col {
+	            break;
+	        }
+	        col = next;
+	    }
+	    (cmp::min(col, columns::last(line)), from.1)
+	}
+	// The keyword at or after the point on its line, for `*` and `#`, and
+	// where it starts.
+	pub fn word_at_point(&self) -> Option<(&str, Coord)> {
+	    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+	    let line = &self.lines[self.point.1];
+	    let at = cmp::min(self.point.0, line.len());
+	    let found = at + line[at..].find(is_word)?;
+	    let start = line[..found].char_indices().rev()
+	        .find(|&(_, c)| !is_word(c))
+	        .map_or(0, |(i, c)| i + c.len_utf8());
+	    let end = line[found..].find(|c| !is_word(c)).map_or(line.len(), |i| found + i);
+	    Some((&line[start..end], (start, self.point.1)))
+	}
+	pub fn leftcol(&self) -> usize {
+	    self.leftcol
+	}
+	pub fn layout(&self) -> &Layout {
+	    &self.layout
+	}
+	pub fn tabstop(&self) -> usize {
+	    self.layout.tabstop
+	}
+	pub fn set_layout(&mut self, layout: Layout) {
+	    self.layout = layout;
+	    self.window_to_point();
+	}
+	// The screen rows line `l` takes up.
+	pub fn rows(&self, l: usize) -> Vec<Row> {
+	    self.layout.rows(&self.lines[l], self.window.0)
+	}
+	// Where the point is drawn, relative to the top left of the window.
+	pub fn cursor(&self) -> (usize, usize) {
+	    let (x, l) = self.point;
+	    let line = &self.lines[l];
+	    let x = columns::snap(line, x);
+	    let rows = self.rows(l);
+	    let r = Layout::row_of(&rows, x);
+	    let col = columns::display_col(line, x, self.layout.tabstop);
+	    let above: usize = (self.offset..l).map(|i| self.rows(i).len()).sum();
+	    let x = (col - rows[r].col + self.layout.indent(r)).saturating_sub(self.leftcol);
+	    (x, above + r)
+	}
     pub fn insert(&mut self, ch: char) {
         let (col, line)  = self.point;
-        self.lines.get_mut(line).unwrap().insert(col, ch);
-        self.point.0 += 1;
+        let mut text = self.lines[line].clone();
+        text.insert(col, ch);
+        self.splice(line, 1, vec![text]);
+        self.point.0 += ch.len_utf8();
+        self.window_to_point();
     }
     pub fn newline(&mut self) {
         let (c, l)  = self.point;
-        // this shouldn't panic because we bounds check line
-        let text = self.lines.get(l).unwrap().to_owned();
-        // there are a couple edge cases to speed this up:
-        if c == 0 {
-            // we might be at the beginning of a line
-            self.lines.insert(l, String::new());
-        } else if c == text.len() - 1 {
-            // we might be at the end of the line
-            self.lines.insert(l + 1, String::new());
-        } else {
-            // otherwise just split this line
-            // let text = text.to_owned();
-            let (_, b) = text.split_at(c);
-            self.lines.get_mut(l).unwrap().truncate(c);
-            self.lines.insert(l + 1, b.to_owned());
-        }
+        let mut head = self.lines[l].clone();
+        let tail = head.split_off(c);
+        self.splice(l, 1, vec![head, tail]);
         self.point.1 += 1;
         self.point.0 = 0;
+        self.window_to_point();
+    }
+
+    // Replaces `count` lines from `first` on with `lines`, recording the
+    // change so it can be undone. Every edit to the text goes through here.
+    fn splice(&mut self, first: usize, count: usize, lines: Vec<String>) {
+        let removed = self.replace_lines(first, count, lines.clone());
+        let change = Change { first, removed, inserted: lines };
+        self.history.record(change, self.point);
+        self.curswant = None;
+    }
+
+    // Puts `lines` in place of `count` lines from `first`, as part of the
+    // current undo step.
+    pub fn change_lines(&mut self, first: usize, count: usize, lines: Vec<String>) {
+        self.splice(first, count, lines);
+    }
+
+    fn replace_lines(&mut self, first: usize, count: usize, lines: Vec<String>) -> Vec<String> {
+        // marks on lines that go away go with them, and ones below move
+        // up or down along with their lines
+        let added = lines.len();
+        self.marks.retain(|_, m| m.1 < first + added || m.1 >= first + count);
+        for m in self.marks.values_mut() {
+            if m.1 >= first + count {
+                m.1 = m.1 + added - count;
+            }
+        }
+        self.marked.retain(|&l| l < first + added || l >= first + count);
+        for l in self.marked.iter_mut() {
+            if *l >= first + count {
+                *l = *l + added - count;
+            }
+        }
+        self.changes += 1;
+        self.lines.splice(first, count, lines)
+    }
+
+    // Ends the current undo step; the next change starts a new one.
+    pub fn checkpoint(&mut self) {
+        if !self.grouping {
+            self.history.commit();
+        }
+    }
+
+    // Makes everything until `group_changes(false)` one undo step,
+    // whatever checkpoints come in between.
+    pub fn group_changes(&mut self, group: bool) {
+        self.grouping = group;
+        self.checkpoint();
+    }
+
+    // Marks lines for `:g` to work through.
+    pub fn mark_lines(&mut self, lines: Vec<usize>) {
+        self.marked = lines;
+    }
+
+    // The first line still marked, unmarking it.
+    pub fn next_marked(&mut self) -> Option<usize> {
+        if self.marked.is_empty() {
+            None
+        } else {
+            Some(self.marked.remove(0))
+        }
+    }
+
+    // Undo and redo return false once there's nothing left to undo or redo.
+    pub fn undo(&mut self, count: usize) -> bool {
+        let mut steps = Vec::new();
+        for _ in 0..count {
+            match self.history.undo() {
+                Some(group) => steps.push(Step::Undo(group)),
+                None => break,
+            }
+        }
+        self.travel(steps)
+    }
+
+    pub fn redo(&mut self, count: usize) -> bool {
+        let mut steps = Vec::new();
+        for _ in 0..count {
+            match self.history.redo() {
+                Some(group) => steps.push(Step::Redo(group)),
+                None => break,
+            }
+        }
+        self.travel(steps)
+    }
+
+    // Moves through every state the text has been in, in the order they
+    // were made, hopping between branches of the undo tree as needed.
+    pub fn earlier(&mut self, travel: Travel) -> bool {
+        let steps = self.history.earlier(travel);
+        self.travel(steps)
+    }
+
+    pub fn later(&mut self, travel: Travel) -> bool {
+        let steps = self.history.later(travel);
+        self.travel(steps)
+    }
+
+    pub fn undo_list(&self) -> Vec<Leaf> {
+        self.history.leaves()
+    }
+
+    // Saves the undo history, tied to the text as it is now.
+    pub fn write_undo<W: Write>(&self, w: &mut W) -> Result<()> {
+        self.history.write(w, undo::hash_lines(self.lines.iter()))
+    }
+
+    // Picks up a saved undo history, so long as it was saved for this
+    // same text. Returns whether it was.
+    pub fn read_undo<R: BufRead>(&mut self, r: R) -> Result<bool> {
+        match History::read(r, undo::hash_lines(self.lines.iter()))? {
+            Some(history) => {
+                self.history = history;
+                self.saved = self.history.seq();
+                Ok(true)
+            }
+            None => Ok(false)
+        }
+    }
+
+    fn travel(&mut self, steps: Vec<Step>) -> bool {
+        if steps.is_empty() {
+            return false;
+        }
+        for step in steps {
+            match step {
+                Step::Undo(group) => {
+                    self.format.hex ^= group.hex;
+                    for change in group.changes.into_iter().rev() {
+                        self.replace_lines(change.first, change.inserted.len(), change.removed);
+                    }
+                    self.point = group.point;
+                }
+                Step::Redo(group) => {
+                    self.format.hex ^= group.hex;
+                    for change in group.changes.into_iter() {
+                        self.replace_lines(change.first, change.removed.len(), change.inserted);
+                    }
+                    self.point = group.point;
+                }
+            }
+        }
+        self.clamp_point();
+        self.window_to_point();
+        true
     }
 
-    pub fn yank_del(&mut self, r: Range, mode: Yank) {
-        
+    // Puts back the line that was changed last the way it was before the
+    // changes to it started. This is itself a change, so doing it twice
+    // undoes it.
+    pub fn undo_line(&mut self) -> bool {
+        match self.history.line() {
+            Some((l, text)) if l < self.lines.len() => {
+                self.point = (0, l);
+                self.splice(l, 1, vec![text]);
+                self.history.commit();
+                self.window_to_point();
+                true
+            }
+            _ => false
+        }
+    }
+
+    // Copies the text a motion from one end of `r` to the other covers,
+    // deleting it too for YankDel.
+    pub fn yank_del(&mut self, r: Range, span: Span, mode: Yank) -> Register {
+        let (start, end) = ordered(r.0, r.1);
+        let region = self.region(start, end, span);
+        let text = self.text(region);
+        if let Yank::YankDel = mode {
+            match region {
+                Region::Chars(start, end) => self.delete_chars(start, end),
+                Region::Lines(first, last) => self.delete_lines(first, last),
+            }
+        }
+        text
+    }
+
+    // Puts a register's text after (or before) the point [count] times.
+    pub fn put(&mut self, text: &Register, before: bool, count: usize) {
+        match *text {
+            Register::Lines(ref lines) => {
+                let at = if before { self.point.1 } else { self.point.1 + 1 };
+                let copies = lines.iter().cycle().take(lines.len() * count);
+                self.splice(at, 0, copies.cloned().collect());
+                self.point.1 = at;
+                self.begin();
+            }
+            Register::Chars(ref text) => {
+                if text.is_empty() {
+                    return;
+                }
+                let at = if before { self.point.0 } else { self.next_col(self.point) };
+                let text = text.repeat(count);
+                let end = self.insert_text((at, self.point.1), &text);
+                // a put within a line leaves the point on its last character,
+                // otherwise at the start of it
+                if text.contains('\n') {
+                    self.point.0 = at;
+                } else {
+                    self.point = end;
+                    self.left(1);
+                }
+            }
+        }
+        self.window_to_point();
     }
 
     // Navigation
-    // TODO: graphemes
     pub fn left(&mut self, c: usize) {
-		let col = self.point.0.saturating_sub(c);
+    	let line = self.lines.get(self.point.1).unwrap();
+        let mut col = self.point.0;
+        for _ in 0..c {
+            col = columns::prev(line, col);
+        }
         self.point.0 = col;
     }
-    // TODO: graphemes
     pub fn right(&mut self, c: usize) {
-        let (_, l) = self.point;
-    	let line = self.lines.get(l).unwrap();
-        let len = line.len();
-        let col = self.point.0 + c;
-        self.point.0 = cmp::min(len, col);
+    	let line = self.lines.get(self.point.1).unwrap();
+        let mut col = self.point.0;
+        for _ in 0..c {
+            col = columns::next(line, col);
+        }
+        self.point.0 = col;
     }
 
     pub fn begin(&mut self) {
@@ -127,10 +638,8 @@ impl Buffer {
 		self.point.0 = idx ;
     }
     pub fn end(&mut self) {
-    	let line : &str = &self.lines.get(self.point.1).unwrap();
-    	let graphemes = UnicodeSegmentation::graphemes(line, true);
-    	let width = graphemes.count() - 1;
-		self.point.0 = width;
+    	let line = self.lines.get(self.point.1).unwrap();
+		self.point.0 = columns::last(line);
     }
 
     pub fn scroll(&mut self, c: usize, dir: Direction) {
@@ -155,68 +664,147 @@ impl Buffer {
     }
 
     pub fn page_back(&mut self, c: usize) {
-		let cnt = (c * (self.window.1 - 2)) - 1;
+		let cnt = (c * self.window.1.saturating_sub(2)).saturating_sub(1);
 		self.scroll(cnt, Direction::Up);
     }
 
     pub fn page_fwd(&mut self, c: usize) {
-		let cnt = (c * (self.window.1 - 2)) - 1;
+		let cnt = (c * self.window.1.saturating_sub(2)).saturating_sub(1);
 		self.scroll(cnt, Direction::Down);
     }
 
-    pub fn prev(&mut self, c: usize) {
-		let curr = self.point.1;
-		self.point.1 = curr.saturating_sub(c);
+    // Moves the point by screen rows rather than lines, keeping to the same
+    // screen column, for `gj` and `gk`. Without wrapping this is `j` and `k`.
+    fn move_rows(&mut self, n: usize, dir: Direction) {
+        let (x, mut l) = self.point;
+        let mut rows = self.rows(l);
+        let mut r = Layout::row_of(&rows, x);
+        let want = {
+            let col = columns::display_col(&self.lines[l], x, self.layout.tabstop);
+            col - rows[r].col + self.layout.indent(r)
+        };
+        for _ in 0..n {
+            match dir {
+                Direction::Down if r + 1 < rows.len() => r += 1,
+                Direction::Down if l + 1 < self.lines.len() => {
+                    l += 1;
+                    rows = self.rows(l);
+                    r = 0;
+                }
+                Direction::Up if r > 0 => r -= 1,
+                Direction::Up if l > 0 => {
+                    l -= 1;
+                    rows = self.rows(l);
+                    r = rows.len() - 1;
+                }
+                _ => break,
+            }
+        }
+        let row = rows[r];
+        let line = &self.lines[l];
+        let target = want.saturating_sub(self.layout.indent(r)) + row.col;
+        let mut col = columns::at_display(line, target, self.layout.tabstop);
+        // stay on the row, even if it's too short to reach the column
+        if col >= row.end {
+            col = if row.end == line.len() { columns::last(line) } else { columns::prev(line, row.end) };
+        }
+        self.point = (cmp::max(col, row.start), l);
     }
-    pub fn next(&mut self, c: usize) {
-        let height = self.window.1;
-    	let len = self.lines.len() - 1;
-        let next = self.point.1 + c;
-		self.point.1 = cmp::min(len, next);
-		if self.point.1 > self.offset + height - 1 {
-			self.offset += 1;
-		}	
+
+    // Goes to the first non-blank of line `l`, as `:l` does.
+    pub fn goto_line(&mut self, l: usize) {
+        self.do_motion(&Motion::Goto(Column::Begin, Line::Specific(l)));
     }
+
     pub fn resize(&mut self, w: usize, h: usize) {
         self.window = (w, h);
         self.window_to_point();
     }
 
+    // The last line that fits in the window, wrapped lines and all.
     fn last_line(&self) -> usize {
-    	let endline = self.lines.len() - self.offset;
-    	self.offset + cmp::min(self.window.1 - 1, endline)
+        let mut rows = 0;
+        let mut l = self.offset;
+        while l < self.lines.len() {
+            rows += self.rows(l).len();
+            if rows > self.window.1 {
+                break;
+            }
+            l += 1;
+        }
+        cmp::max(self.offset, l.saturating_sub(1))
     }
 
     fn window_to_point(&mut self) {
         let l = self.point.1;
-        let h = self.window.1 - 1;
-        let offset = self.offset;
-        if l < offset {
+        let h = self.window.1;
+        if l < self.offset {
             // move the screen up to the point
             self.offset = l;
-        } else if l > offset + h {
-            self.offset = l - h; 
+        } else if l >= self.offset + h {
+            self.offset = l + 1 - h;
+        }
+        // wrapped lines take up more than one row
+        while self.offset < l && self.last_line() < l {
+            self.offset += 1;
+        }
+        self.leftcol_to_point();
+    }
+    // Without wrapping, keeps the point's column on screen by scrolling
+    // sideways as little as it takes.
+    fn leftcol_to_point(&mut self) {
+        if self.layout.wrap {
+            self.leftcol = 0;
+            return;
+        }
+        let (col, width) = {
+            let line = &self.lines[self.point.1];
+            let x = columns::snap(line, self.point.0);
+            let col = columns::display_col(line, x, self.layout.tabstop);
+            let g = &line[x..columns::next(line, x)];
+            (col, cmp::max(columns::width(g, col, self.layout.tabstop), 1))
+        };
+        if col < self.leftcol {
+            self.leftcol = col;
+        } else if col + width > self.leftcol + self.window.0 {
+            self.leftcol = (col + width).saturating_sub(self.window.0);
         }
     }
     fn point_to_window(&mut self) {
         let l = self.point.1;
-        let (_, h) = self.window;
         let offset = self.offset;
         if l < offset {
             // move the point to the first line
             self.point.1 = offset;
             self.begin();
-        } else if l >= offset + h {
+        } else if l > self.last_line() {
             // move the point to the last line
             self.point.1 = self.last_line();
             self.begin();
         }
     }
 
-    pub fn do_cmd(&mut self, count: usize, cmd: &Command) {
-        let (c, l) = self.point;
+    pub fn do_cmd(&mut self, _count: usize, cmd: &Command) -> Option<Register> {
+        // only the state knows where searches and finds go, so one that
+        // hasn't been turned into a Goto yet does nothing
         match cmd.motion {
+            Motion::Search(_) | Motion::SearchAgain(_) | Motion::SearchWord(_) |
+            Motion::Find(_) | Motion::FindAgain(_) => return None,
+            _ => {}
+        }
+        match cmd.operator {
+            Some(op) => self.operate(op, cmd),
+            None => {
+                self.do_motion(&cmd.motion);
+                None
+            }
+        }
+    }
+
+    fn do_motion(&mut self, motion: &Motion) {
+        match *motion {
             Motion::Goto(ref col, ref line) => {
+                let from = self.point;
                 match *line {
                     Line::Current => { /* do nothing */ }
                     Line::Up(i) => {
@@ -230,9 +818,7 @@ impl Buffer {
                     }
                     Line::Specific(i) => {
                         let len = self.lines.len() - 1;
-                        if i < len {
-                            self.point.1 = i;
-                        }
+                        self.point.1 = cmp::min(len, i);
                     }
                     Line::Last => {
                         let len = self.lines.len() - 1;
@@ -240,11 +826,25 @@ impl Buffer {
                     }
                 };
                 match *col {
-                    Column::Current => { /* do nothing */ }
+                    Column::Current => {
+                        // going up or down keeps to the same screen column,
+                        // or to the one the last sideways motion wanted
+                        if self.point.1 != from.1 {
+                            let want = match self.curswant {
+                                Some(want) => want,
+                                None => columns::display_col(&self.lines[from.1], from.0, self.layout.tabstop),
+                            };
+                            let line = self.lines.get(self.point.1).unwrap();
+                            let col = columns::at_display(line, want, self.layout.tabstop);
+                            self.point.0 = cmp::min(col, columns::last(line));
+                            self.curswant = Some(want);
+                        }
+                    }
                     Column::Specific(i) => {
-                        let line = self.lines.get(l).unwrap();
-                        if i < line.len() {
-                            self.point.0 = i;
+                        let line = self.lines.get(self.point.1).unwrap();
+                        let col = columns::grapheme_col(line, i);
+                        if col < line.len() {
+                            self.point.0 = col;
                         }
                     }
                     Column::Left(i) => { self.left(i); }
@@ -255,10 +855,16 @@ impl Buffer {
                         self.point.0 = idx;
                     }
                     Column::End => {
-                        let line = self.lines.get(l).unwrap();
-                        self.point.0 = line.len() - 1;
+                        let line = self.lines.get(self.point.1).unwrap();
+                        self.point.0 = columns::last(line);
                     }
                 };
+                match *col {
+                    Column::Current => {}
+                    // `$` sticks to the end of every line `j` and `k` go to
+                    Column::End => self.curswant = Some(usize::MAX),
+                    _ => self.curswant = None,
+                }
                 // println!("{:?} {:?}", col, line);
                 self.window_to_point();
             }
@@ -278,10 +884,365 @@ impl Buffer {
                 }
                 self.point_to_window();
             }
+            Motion::Page(ref line) => {
+                match *line {
+                    Line::Up(i) => self.page_back(i),
+                    Line::Down(i) => self.page_fwd(i),
+                    _ => unreachable!()
+                }
+            }
+            Motion::HalfPage(ref line) => {
+                let half = self.window.1 / 2;
+                match *line {
+                    Line::Up(i) => self.scroll(i * half, Direction::Up),
+                    Line::Down(i) => self.scroll(i * half, Direction::Down),
+                    _ => unreachable!()
+                }
+            }
+            Motion::Row(ref line) => {
+                match *line {
+                    Line::Up(i) => self.move_rows(i, Direction::Up),
+                    Line::Down(i) => self.move_rows(i, Direction::Down),
+                    _ => unreachable!()
+                }
+                self.curswant = None;
+                self.window_to_point();
+            }
+            Motion::Next(..) | Motion::Prev(..) | Motion::NextEnd(..) | Motion::PrevEnd(..) => {
+                self.point = self.skip(motion, false);
+                self.clamp_point();
+                self.curswant = None;
+                self.window_to_point();
+            }
+            // the state finds where searches and finds go
+            Motion::Search(_) | Motion::SearchAgain(_) | Motion::SearchWord(_) |
+            Motion::Find(_) | Motion::FindAgain(_) => {}
+        }
+    }
+
+    // Where a word, sentence or paragraph motion from the point ends up.
+    // Under an operator `w` stops at the end of the line.
+    fn skip(&self, motion: &Motion, operator: bool) -> Coord {
+        let at = self.point;
+        let lines = &self.lines;
+        match *motion {
+            Motion::Next(SkipThing::Sentence, n) => motions::sentence(lines, at, true, n),
+            Motion::Prev(SkipThing::Sentence, n) => motions::sentence(lines, at, false, n),
+            Motion::Next(SkipThing::Paragraph, n) => motions::paragraph(lines, at, true, n),
+            Motion::Prev(SkipThing::Paragraph, n) => motions::paragraph(lines, at, false, n),
+            Motion::Next(thing, n) => motions::next_word(lines, at, thing == SkipThing::BigWord, n, operator),
+            Motion::Prev(thing, n) => motions::prev_word(lines, at, thing == SkipThing::BigWord, n),
+            Motion::NextEnd(thing, n) => motions::word_end(lines, at, thing == SkipThing::BigWord, n, false),
+            Motion::PrevEnd(thing, n) => motions::prev_word_end(lines, at, thing == SkipThing::BigWord, n),
+            _ => unreachable!()
+        }
+    }
+
+    // Applies an operator to the text between the point and wherever the
+    // command's motion would have taken it, returning any text it yanked.
+    fn operate(&mut self, op: Operator, cmd: &Command) -> Option<Register> {
+        let from = self.point;
+        let mut span = cmd.span;
+        let on_word = self.lines[from.1][from.0..].chars().next().is_some_and(|c| !c.is_whitespace());
+        let to = match cmd.motion {
+            // `cw` on a word changes just the word, like `ce`
+            Motion::Next(thing, n) if op == Operator::Change && on_word &&
+                    (thing == SkipThing::Word || thing == SkipThing::BigWord) => {
+                span = Span::Inclusive;
+                motions::word_end(&self.lines, from, thing == SkipThing::BigWord, n, true)
+            }
+            Motion::Next(..) | Motion::Prev(..) | Motion::NextEnd(..) | Motion::PrevEnd(..) => {
+                self.skip(&cmd.motion, true)
+            }
+            _ => {
+                self.do_motion(&cmd.motion);
+                self.point
+            }
+        };
+        self.point = from;
+
+        let (start, end) = ordered(from, to);
+        let region = self.region(start, end, span);
+        let (first, last) = region.lines();
+        let mut yanked = None;
+        match (op, region) {
+            (Operator::Delete, Region::Chars(..)) => {
+                yanked = Some(self.yank_del((start, end), span, Yank::YankDel));
+                self.point = start;
+            }
+            (Operator::Delete, Region::Lines(..)) => {
+                yanked = Some(self.yank_del((start, end), span, Yank::YankDel));
+                self.point.1 = first;
+                self.clamp_point();
+                self.begin();
+            }
+            (Operator::Change, Region::Chars(..)) => {
+                yanked = Some(self.yank_del((start, end), span, Yank::YankDel));
+                self.point = start;
+            }
+            (Operator::Change, Region::Lines(..)) => {
+                yanked = Some(self.yank_del((start, end), span, Yank::YankOnly));
+                self.splice(first, last + 1 - first, vec![String::new()]);
+                self.point = (0, first);
+            }
+            (Operator::Yank, _) => {
+                yanked = Some(self.yank_del((start, end), span, Yank::YankOnly));
+                self.point = start;
+            }
+            (Operator::ShiftRight, _) | (Operator::ShiftLeft, _) => {
+                self.shift_lines(first, last, op == Operator::ShiftRight);
+                self.point.1 = first;
+                self.begin();
+            }
+        }
+        if op != Operator::Change {
+            self.clamp_point();
+        }
+        self.window_to_point();
+        yanked
+    }
+
+    // Works out exactly which text a motion from `start` to `end` covers.
+    fn region(&self, start: Coord, end: Coord, span: Span) -> Region {
+        match span {
+            Span::Linewise => Region::Lines(start.1, end.1),
+            Span::Inclusive => {
+                Region::Chars(start, (self.next_col(end), end.1))
+            }
+            Span::Exclusive => {
+                // an exclusive motion that ends in column 0 of a later line
+                // really ends on the last character of the line before it,
+                // and if it also started before the first non-blank it
+                // becomes linewise.
+                if end.0 == 0 && end.1 > start.1 {
+                    let line = &self.lines[start.1];
+                    let first = line.find(|c: char| !c.is_whitespace()).unwrap_or(line.len());
+                    if start.0 <= first {
+                        return Region::Lines(start.1, end.1 - 1);
+                    }
+                    let prev = self.lines[end.1 - 1].len();
+                    return Region::Chars(start, (prev, end.1 - 1));
+                }
+                Region::Chars(start, end)
+            }
+        }
+    }
+
+    // The column just after the character at `c`.
+    fn next_col(&self, c: Coord) -> usize {
+        columns::next(&self.lines[c.1], c.0)
+    }
+
+    fn text(&self, region: Region) -> Register {
+        match region {
+            Region::Chars((sc, sl), (ec, el)) => {
+                let ec = cmp::min(ec, self.lines[el].len());
+                if sl == el {
+                    let sc = cmp::min(sc, ec);
+                    return Register::Chars(self.lines[sl][sc..ec].to_owned());
+                }
+                let sc = cmp::min(sc, self.lines[sl].len());
+                let mut text = self.lines[sl][sc..].to_owned();
+                for line in self.lines.iter_at(sl + 1).take(el - sl - 1) {
+                    text.push('\n');
+                    text.push_str(line);
+                }
+                text.push('\n');
+                text.push_str(&self.lines[el][..ec]);
+                Register::Chars(text)
+            }
+            Region::Lines(first, last) => {
+                let last = cmp::min(last, self.lines.len() - 1);
+                let lines = self.lines.iter_at(first).take(last + 1 - first);
+                Register::Lines(lines.cloned().collect())
+            }
+        }
+    }
+
+    // Inserts text that may contain newlines, returning the coord just
+    // past the end of it.
+    fn insert_text(&mut self, at: Coord, text: &str) -> Coord {
+        let (col, line) = at;
+        let mut lines: Vec<String> = text.split('\n').map(|l| l.to_owned()).collect();
+        lines[0].insert_str(0, &self.lines[line][..col]);
+        let end = (lines[lines.len() - 1].len(), line + lines.len() - 1);
+        lines.last_mut().unwrap().push_str(&self.lines[line][col..]);
+        self.splice(line, 1, lines);
+        end
+    }
+
+    // Removes the text from `start` up to (but not including) `end`,
+    // joining lines if the two are on different lines.
+    fn delete_chars(&mut self, start: Coord, end: Coord) {
+        let (sc, sl) = start;
+        let (ec, el) = end;
+        let ec = cmp::min(ec, self.lines[el].len());
+        let sc = cmp::min(sc, self.lines[sl].len());
+        let sc = if sl == el { cmp::min(sc, ec) } else { sc };
+        let mut line = self.lines[sl][..sc].to_owned();
+        line.push_str(&self.lines[el][ec..]);
+        self.splice(sl, el + 1 - sl, vec![line]);
+    }
+
+    // Removes whole lines, keeping at least one (empty) line around.
+    fn delete_lines(&mut self, first: usize, last: usize) {
+        let last = cmp::min(last, self.lines.len() - 1);
+        if first > last {
+            return;
+        }
+        let count = last + 1 - first;
+        if count == self.lines.len() {
+            self.splice(0, count, vec![String::new()]);
+        } else {
+            self.splice(first, count, Vec::new());
+        }
+    }
+
+    // The ex line commands, which leave the point on the first non-blank
+    // of the last line they touch.
+
+    pub fn yank_lines(&self, first: usize, last: usize) -> Register {
+        self.text(Region::Lines(first, last))
+    }
+
+    pub fn delete_range(&mut self, first: usize, last: usize) -> Register {
+        let text = self.yank_lines(first, last);
+        self.delete_lines(first, last);
+        self.goto_line(cmp::min(first, self.lines.len() - 1));
+        text
+    }
+
+    // Copies lines to just after line `to` (counting from 1, so 0 is the
+    // top).
+    pub fn copy_lines(&mut self, first: usize, last: usize, to: usize) {
+        let lines = match self.yank_lines(first, last) {
+            Register::Lines(lines) => lines,
+            Register::Chars(_) => unreachable!(),
+        };
+        let count = lines.len();
+        self.splice(to, 0, lines);
+        self.goto_line(to + count - 1);
+    }
+
+    // Moves lines to just after line `to`, which mustn't be among them.
+    // Their marks go with them.
+    pub fn move_lines(&mut self, first: usize, last: usize, to: usize) {
+        let count = last + 1 - first;
+        if to >= first && to <= last + 1 {
+            return self.goto_line(last);
+        }
+        let moving = |l: usize| l >= first && l <= last;
+        let marks: Vec<(char, Coord)> = self.marks.iter()
+            .filter(|&(_, m)| moving(m.1))
+            .map(|(&c, &(col, l))| (c, (col, l - first)))
+            .collect();
+        let marked: Vec<usize> = self.marked.iter().cloned().filter(|&l| moving(l)).map(|l| l - first).collect();
+        let lines = self.lines.iter_at(first).take(count).cloned().collect();
+        self.splice(first, count, Vec::new());
+        let at = if to > last { to - count } else { to };
+        self.splice(at, 0, lines);
+        self.marks.extend(marks.into_iter().map(|(c, (col, l))| (c, (col, at + l))));
+        self.marked.extend(marked.into_iter().map(|l| at + l));
+        self.marked.sort();
+        self.goto_line(at + count - 1);
+    }
+
+    // Joins lines into the first of them. Unless it's `raw`, the leading
+    // white space of each line joined on goes, and a space goes in its
+    // place unless there's white space there already or the line starts
+    // with `)`. The point ends up where the last line was joined.
+    pub fn join_lines(&mut self, first: usize, last: usize, raw: bool) {
+        let last = cmp::min(last, self.lines.len() - 1);
+        let mut joined = self.lines[first].clone();
+        let mut at = 0;
+        for line in self.lines.iter_at(first + 1).take(last - first) {
+            at = joined.len();
+            if raw {
+                joined.push_str(line);
+                continue;
+            }
+            let line = line.trim_start();
+            if !line.is_empty() && !joined.is_empty() && !joined.ends_with(char::is_whitespace) && !line.starts_with(')') {
+                joined.push(' ');
+            }
+            joined.push_str(line);
+        }
+        self.splice(first, last + 1 - first, vec![joined]);
+        self.point = (at, first);
+        self.clamp_point();
+        self.window_to_point();
+    }
+
+    pub fn shift_range(&mut self, first: usize, last: usize, right: bool, times: usize) {
+        for _ in 0..times {
+            self.shift_lines(first, last, right);
+        }
+        self.goto_line(cmp::min(last, self.lines.len() - 1));
+    }
+
+    // Puts lines just after line `after` (counting from 1).
+    pub fn put_lines(&mut self, after: usize, lines: Vec<String>) {
+        let count = lines.len();
+        self.splice(after, 0, lines);
+        self.goto_line(after + count - 1);
+    }
+
+    fn shift_lines(&mut self, first: usize, last: usize, right: bool) {
+        let last = cmp::min(last, self.lines.len() - 1);
+        let mut lines: Vec<String> = self.lines.iter_at(first).take(last + 1 - first).cloned().collect();
+        for line in lines.iter_mut() {
+            if line.is_empty() {
+                continue;
+            }
+            if right {
+                line.insert(0, '\t');
+            } else if line.starts_with('\t') {
+                line.remove(0);
+            } else {
+                let spaces = line.chars().take(SHIFTWIDTH).take_while(|&c| c == ' ').count();
+                line.drain(..spaces);
+            }
+        }
+        self.splice(first, lines.len(), lines);
+    }
+
+    // Keeps the point on an actual character of an actual line, as normal
+    // mode expects.
+    fn clamp_point(&mut self) {
+        let last = self.lines.len() - 1;
+        self.point.1 = cmp::min(self.point.1, last);
+        let line = &self.lines[self.point.1];
+        if self.point.0 >= line.len() {
+            self.point.0 = columns::last(line);
+        } else {
+            self.point.0 = columns::snap(line, self.point.0);
+        }
+    }
+}
+
+// The text covered by an operator.
+#[derive(Clone, Copy)]
+enum Region {
+    // [start, end) characterwise
+    Chars(Coord, Coord),
+    // whole lines, inclusive
+    Lines(usize, usize),
+}
+
+impl Region {
+    fn lines(&self) -> (usize, usize) {
+        match *self {
+            Region::Chars(start, end) => (start.1, end.1),
+            Region::Lines(first, last) => (first, last),
         }
     }
 }
 
+// Coords are (column, line) so they don't compare in text order.
+fn ordered(a: Coord, b: Coord) -> (Coord, Coord) {
+    if (a.1, a.0) <= (b.1, b.0) { (a, b) } else { (b, a) }
+}
+
 #[test]
 fn new_buffer() {
 	let buf = Buffer::new_empty(80, 24);
@@ -293,7 +1254,7 @@ fn basic_navigation() {
 	let mut buf = Buffer::new_empty(80, 24);
 	assert_eq!(buf.point, (0, 0));
 
-	buf.next(1);
+	buf.do_cmd(1, &Command::goto(Span::Exclusive, Column::Current, Line::Down(1)));
 	assert_eq!(buf.point, (0, 0));
 }
 
@@ -367,8 +1328,352 @@ fn basic_line_nav() {
 #[test]
 fn basic_scroll_nav() {
     let mut buf = Buffer::new_empty(80, 24);
-    for _ in 1..10 {
-        buf.lines.get_mut(0).unwrap().push_str(" hello world this is a line");
+    for _ in 1..100 {
+        buf.lines.push_back(String::from(" hello world this is a line"));
     }
-    assert!(false, "should write tests for this");
+    let fwd  = Command { motion: Motion::Page(Line::Down(1)), ..Command::scroll(Line::Current) };
+    let back = Command { motion: Motion::Page(Line::Up(1)), ..Command::scroll(Line::Current) };
+    let half = Command { motion: Motion::HalfPage(Line::Down(1)), ..Command::scroll(Line::Current) };
+    let line = Command::scroll(Line::Down(1));
+
+    buf.do_cmd(1, &line);
+    assert_eq!(buf.offset, 1);
+    assert_eq!(buf.point, (1, 1));
+
+    buf.do_cmd(1, &fwd);
+    assert_eq!(buf.offset, 22);
+    assert_eq!(buf.point, (0, 22));
+
+    buf.do_cmd(1, &half);
+    assert_eq!(buf.offset, 34);
+
+    buf.do_cmd(1, &back);
+    assert_eq!(buf.offset, 13);
+    // the point stays on screen
+    assert_eq!(buf.point, (0, 34));
+
+    let three = Command { motion: Motion::Page(Line::Up(3)), ..Command::scroll(Line::Current) };
+    buf.do_cmd(1, &three);
+    assert_eq!(buf.offset, 0);
+    assert_eq!(buf.point, (0, 23));
+}
+
+#[cfg(test)]
+fn buffer_with(lines: &[&str]) -> Buffer {
+    let mut buf = Buffer::new_empty(80, 24);
+    buf.lines = lines.iter().map(|l| l.to_string()).collect();
+    buf
+}
+
+#[test]
+fn operator_delete() {
+    let mut buf = buffer_with(&["one", "two", "three", "four"]);
+    let mut dd = Command::goto(Span::Linewise, Column::Current, Line::Down(1));
+    dd.operator = Some(Operator::Delete);
+    buf.do_cmd(1, &dd);
+    assert_eq!(buf.lines, vec!["three", "four"]);
+    assert_eq!(buf.point, (0, 0));
+
+    let mut d_end = Command::goto(Span::Inclusive, Column::End, Line::Current);
+    d_end.operator = Some(Operator::Delete);
+    buf.point = (2, 0);
+    buf.do_cmd(1, &d_end);
+    assert_eq!(buf.lines, vec!["th", "four"]);
+    assert_eq!(buf.point, (1, 0));
+
+    let mut dh = Command::goto(Span::Exclusive, Column::Left(1), Line::Current);
+    dh.operator = Some(Operator::Delete);
+    buf.do_cmd(1, &dh);
+    assert_eq!(buf.lines, vec!["h", "four"]);
+    assert_eq!(buf.point, (0, 0));
+
+    // deleting everything leaves a single empty line
+    let mut dg = Command::goto(Span::Linewise, Column::Begin, Line::Last);
+    dg.operator = Some(Operator::Delete);
+    buf.do_cmd(1, &dg);
+    assert_eq!(buf.lines, vec![""]);
+}
+
+#[test]
+fn operator_change_and_shift() {
+    let mut buf = buffer_with(&["  one", "two"]);
+    let mut cc = Command::goto(Span::Linewise, Column::Current, Line::Down(0));
+    cc.operator = Some(Operator::Change);
+    buf.do_cmd(1, &cc);
+    assert_eq!(buf.lines, vec!["", "two"]);
+
+    let mut shift = Command::goto(Span::Linewise, Column::Current, Line::Down(1));
+    shift.operator = Some(Operator::ShiftRight);
+    buf.do_cmd(1, &shift);
+    assert_eq!(buf.lines, vec!["", "\ttwo"]);
+
+    shift.operator = Some(Operator::ShiftLeft);
+    buf.do_cmd(1, &shift);
+    assert_eq!(buf.lines, vec!["", "two"]);
+}
+
+#[test]
+fn yank_and_put() {
+    let mut buf = buffer_with(&["one two", "three"]);
+    let text = buf.yank_del(((0, 0), (3, 0)), Span::Exclusive, Yank::YankDel);
+    assert_eq!(text, Register::Chars("one".to_owned()));
+    assert_eq!(buf.lines, vec![" two", "three"]);
+
+    buf.point = (3, 0);
+    buf.put(&text, false, 2);
+    assert_eq!(buf.lines, vec![" twooneone", "three"]);
+    assert_eq!(buf.point, (9, 0));
+
+    let lines = buf.yank_del(((0, 1), (0, 1)), Span::Linewise, Yank::YankOnly);
+    assert_eq!(lines, Register::Lines(vec!["three".to_owned()]));
+    buf.put(&lines, true, 1);
+    assert_eq!(buf.lines, vec!["three", " twooneone", "three"]);
+    assert_eq!(buf.point, (0, 0));
+
+    // characterwise text can span lines too
+    let text = buf.yank_del(((2, 0), (2, 1)), Span::Inclusive, Yank::YankDel);
+    assert_eq!(text, Register::Chars("ree\n tw".to_owned()));
+    assert_eq!(buf.lines, vec!["thooneone", "three"]);
+}
+
+#[test]
+fn undo_and_redo() {
+    let mut buf = buffer_with(&["one", "two", "three"]);
+    let mut dd = Command::goto(Span::Linewise, Column::Current, Line::Down(0));
+    dd.operator = Some(Operator::Delete);
+    buf.point = (1, 1);
+    buf.do_cmd(1, &dd);
+    buf.checkpoint();
+    buf.point = (0, 0);
+    for c in "hey ".chars() {
+        buf.insert(c);
+    }
+    buf.newline();
+    buf.checkpoint();
+    assert_eq!(buf.lines, vec!["hey ", "one", "three"]);
+
+    assert!(buf.undo(1));
+    assert_eq!(buf.lines, vec!["one", "three"]);
+    assert_eq!(buf.point, (0, 0));
+    assert!(buf.undo(1));
+    assert_eq!(buf.lines, vec!["one", "two", "three"]);
+    assert_eq!(buf.point, (1, 1));
+    assert!(!buf.undo(1));
+
+    assert!(buf.redo(2));
+    assert_eq!(buf.lines, vec!["hey ", "one", "three"]);
+    assert!(!buf.redo(1));
+}
+
+#[test]
+fn undo_line() {
+    let mut buf = buffer_with(&["one", "two"]);
+    buf.point = (3, 1);
+    buf.insert('s');
+    buf.checkpoint();
+    buf.insert('!');
+    buf.checkpoint();
+    assert_eq!(buf.lines, vec!["one", "twos!"]);
+
+    assert!(buf.undo_line());
+    assert_eq!(buf.lines, vec!["one", "two"]);
+    // U undoes itself
+    assert!(buf.undo_line());
+    assert_eq!(buf.lines, vec!["one", "twos!"]);
+    // and can be undone like any other change
+    assert!(buf.undo(1));
+    assert_eq!(buf.lines, vec!["one", "two"]);
+}
+
+#[test]
+fn undo_branches() {
+    let mut buf = buffer_with(&["one"]);
+    buf.point = (3, 0);
+    buf.insert('!');
+    buf.checkpoint();
+    buf.undo(1);
+    buf.point = (3, 0);
+    buf.insert('?');
+    buf.checkpoint();
+    assert_eq!(buf.lines, vec!["one?"]);
+
+    assert!(buf.earlier(Travel::Changes(1)));
+    assert_eq!(buf.lines, vec!["one!"]);
+    assert!(buf.earlier(Travel::Changes(1)));
+    assert_eq!(buf.lines, vec!["one"]);
+    assert!(!buf.earlier(Travel::Changes(1)));
+    assert!(buf.later(Travel::Changes(2)));
+    assert_eq!(buf.lines, vec!["one?"]);
+    assert_eq!(buf.undo_list().len(), 2);
+}
+
+#[test]
+fn undo_survives_reload() {
+    let mut buf = buffer_with(&["one"]);
+    buf.point = (3, 0);
+    buf.insert('!');
+    buf.checkpoint();
+    let mut file = Vec::new();
+    buf.write_undo(&mut file).unwrap();
+
+    let mut other = buffer_with(&["one"]);
+    assert!(!other.read_undo(&file[..]).unwrap());
+
+    let mut reloaded = buffer_with(&["one!"]);
+    assert!(reloaded.read_undo(&file[..]).unwrap());
+    assert!(reloaded.undo(1));
+    assert_eq!(reloaded.lines, vec!["one"]);
+}
+
+#[test]
+#[ignore] // timing only means anything under cargo test --release -- --ignored
+fn edit_million_line_file() {
+    use std::time::{Duration, Instant};
+
+    let mut buf = Buffer::new_empty(80, 24);
+    let file: String = (0..1000000).map(|i| format!("line {}\n", i)).collect();
+    buf.load_reader(file.as_bytes(), None, false).unwrap();
+    assert_eq!(buf.lines.len(), 1000000);
+
+    let start = Instant::now();
+    let mut dd = Command::goto(Span::Linewise, Column::Current, Line::Down(0));
+    dd.operator = Some(Operator::Delete);
+    for i in 0..1000 {
+        let line = (i * 997) % buf.lines.len();
+        buf.do_cmd(1, &Command::goto(Span::Exclusive, Column::Begin, Line::Specific(line)));
+        buf.insert('x');
+        buf.newline();
+        buf.checkpoint();
+        buf.do_cmd(1, &dd);
+        buf.checkpoint();
+    }
+    assert!(buf.undo(2000));
+    let elapsed = start.elapsed();
+    assert_eq!(buf.lines.len(), 1000000);
+    assert_eq!(buf.lines[500000], "line 500000");
+    assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+}
+
+#[test]
+fn columns_follow_graphemes() {
+    let mut buf = buffer_with(&["caf\u{e9}", "\u{4e2d}\u{6587}ab", "x"]);
+    let fwd  = Command::goto(Span::Exclusive, Column::Right(1), Line::Current);
+    let back = Command::goto(Span::Exclusive, Column::Left(1), Line::Current);
+    let down = Command::goto(Span::Linewise, Column::Current, Line::Down(1));
+    let up   = Command::goto(Span::Linewise, Column::Current, Line::Up(1));
+    let end  = Command::goto(Span::Inclusive, Column::End, Line::Current);
+
+    // typing after a two byte character
+    buf.do_cmd(1, &end);
+    assert_eq!(buf.point, (3, 0));
+    buf.do_cmd(1, &fwd);
+    assert_eq!(buf.point, (5, 0));
+    buf.insert('!');
+    assert_eq!(buf.lines[0], "caf\u{e9}!");
+    buf.do_cmd(1, &back);
+    buf.do_cmd(1, &back);
+    assert_eq!(buf.point, (3, 0));
+
+    // screen column 3 is the second half of 文, so `j` lands on it
+    buf.do_cmd(1, &down);
+    assert_eq!(buf.point, (3, 1));
+    buf.do_cmd(1, &fwd);
+    assert_eq!(buf.point, (6, 1));
+    // and from there the wanted column carries on through a short line
+    buf.do_cmd(1, &fwd);
+    buf.do_cmd(1, &down);
+    assert_eq!(buf.point, (0, 2));
+    buf.do_cmd(1, &up);
+    assert_eq!(buf.point, (7, 1));
+
+    // `$` sticks to the end of the line
+    buf.do_cmd(1, &end);
+    buf.do_cmd(1, &up);
+    assert_eq!(buf.point, (5, 0));
+}
+
+#[test]
+fn wrap_and_side_scroll() {
+    let long = "a".repeat(50);
+    let mut buf = buffer_with(&[&long, "b", "c", "d"]);
+    buf.resize(20, 3);
+    let col = |n| Command::goto(Span::Exclusive, Column::Specific(n), Line::Current);
+    let down = Command::goto(Span::Linewise, Column::Current, Line::Down(1));
+    let up = Command::goto(Span::Linewise, Column::Current, Line::Up(1));
+    let gj = |n| Command { motion: Motion::Row(Line::Down(n)), ..col(0) };
+    let gk = Command { motion: Motion::Row(Line::Up(1)), ..col(0) };
+
+    // the long line takes up the whole window
+    buf.do_cmd(1, &col(45));
+    assert_eq!(buf.cursor(), (5, 2));
+    buf.do_cmd(1, &down);
+    assert_eq!(buf.offset, 1);
+    assert_eq!(buf.cursor(), (0, 0));
+    buf.do_cmd(1, &up);
+    assert_eq!(buf.offset, 0);
+
+    buf.do_cmd(1, &col(5));
+    buf.do_cmd(1, &gj(1));
+    assert_eq!(buf.point, (25, 0));
+    assert_eq!(buf.cursor(), (5, 1));
+    buf.do_cmd(1, &gk);
+    assert_eq!(buf.point, (5, 0));
+    buf.do_cmd(1, &gj(3));
+    assert_eq!(buf.point, (0, 1));
+
+    // without wrapping the window slides along to the point instead
+    let mut layout = buf.layout().clone();
+    layout.wrap = false;
+    buf.set_layout(layout);
+    buf.do_cmd(1, &up);
+    buf.do_cmd(1, &col(45));
+    assert_eq!(buf.leftcol, 26);
+    assert_eq!(buf.cursor(), (19, 0));
+    buf.do_cmd(1, &col(30));
+    assert_eq!(buf.leftcol, 26);
+    buf.do_cmd(1, &col(0));
+    assert_eq!(buf.leftcol, 0);
+    buf.do_cmd(1, &gj(1));
+    assert_eq!(buf.point, (0, 1));
+    assert_eq!(buf.offset, 0);
+}
+
+#[test]
+fn operator_skips() {
+    let op = |motion, op| Command { count: 1, register: None, operator: Some(op), span: Span::Exclusive, motion };
+    let mut buf = buffer_with(&["one two", "three.", "", "four"]);
+    buf.do_cmd(1, &op(Motion::Next(SkipThing::Word, 1), Operator::Delete));
+    assert_eq!(buf.lines, vec!["two", "three.", "", "four"]);
+
+    // the last word on a line stops at its end
+    buf.do_cmd(1, &op(Motion::Next(SkipThing::Word, 1), Operator::Delete));
+    assert_eq!(buf.lines, vec!["", "three.", "", "four"]);
+
+    // `cw` leaves the white space after the word
+    let mut buf = buffer_with(&["one two"]);
+    buf.do_cmd(1, &op(Motion::Next(SkipThing::Word, 1), Operator::Change));
+    assert_eq!(buf.lines, vec![" two"]);
+
+    let mut buf = buffer_with(&["one two", "three", "", "four"]);
+    buf.point = (4, 0);
+    let mut de = op(Motion::NextEnd(SkipThing::Word, 1), Operator::Delete);
+    de.span = Span::Inclusive;
+    buf.do_cmd(1, &de);
+    assert_eq!(buf.lines, vec!["one ", "three", "", "four"]);
+
+    buf.point = (0, 0);
+    buf.do_cmd(1, &op(Motion::Next(SkipThing::Paragraph, 1), Operator::Delete));
+    assert_eq!(buf.lines, vec!["", "four"]);
+
+    // a find the state hasn't resolved leaves everything as it was
+    buf.point = (0, 1);
+    assert_eq!(buf.do_cmd(1, &op(Motion::FindAgain(false), Operator::Delete)), None);
+    assert_eq!(buf.lines, vec!["", "four"]);
+    buf.do_cmd(1, &Command { operator: None, ..op(Motion::SearchAgain(false), Operator::Delete) });
+    assert_eq!(buf.point, (0, 1));
+
+    // `yb` from the start of a line yanks back to the empty line above
+    buf.point = (0, 1);
+    buf.do_cmd(1, &op(Motion::Prev(SkipThing::Word, 1), Operator::Yank));
+    assert_eq!(buf.point, (0, 0));
 }
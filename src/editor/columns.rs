@@ -0,0 +1,260 @@
+extern crate unicode_segmentation;
+extern crate unicode_width;
+
+use self::unicode_segmentation::UnicodeSegmentation;
+use self::unicode_width::UnicodeWidthStr;
+use std::cmp;
+
+// A column within a line can be counted three ways: as a byte offset into
+// the string, as an index in grapheme clusters (what the user thinks of as
+// characters), and as the cell it's drawn in on screen. The buffer keeps
+// the point as a byte offset that always sits on a grapheme boundary; these
+// convert between that and the other two.
+
+// The start of the grapheme after the one at `col`, or the end of the line.
+pub fn next(line: &str, col: usize) -> usize {
+    line[col..].graphemes(true).next().map_or(line.len(), |g| col + g.len())
+}
+
+// The start of the grapheme before `col`, or 0.
+pub fn prev(line: &str, col: usize) -> usize {
+    line[..col].graphemes(true).next_back().map_or(0, |g| col - g.len())
+}
+
+// The start of the last grapheme, where normal mode's `$` lands.
+pub fn last(line: &str) -> usize {
+    prev(line, line.len())
+}
+
+// Moves `col` back to the start of the grapheme it's in, in case it's
+// landed in the middle of one.
+pub fn snap(line: &str, col: usize) -> usize {
+    if col >= line.len() {
+        return line.len();
+    }
+    line.grapheme_indices(true)
+        .take_while(|&(i, _)| i <= col)
+        .last()
+        .map_or(0, |(i, _)| i)
+}
+
+// How many graphemes come before `col`.
+pub fn grapheme_index(line: &str, col: usize) -> usize {
+    line[..col].graphemes(true).count()
+}
+
+// The byte offset of the `n`th grapheme, or the end of the line.
+pub fn grapheme_col(line: &str, n: usize) -> usize {
+    line.grapheme_indices(true).nth(n).map_or(line.len(), |(i, _)| i)
+}
+
+// How many cells a grapheme takes when drawn at screen column `at`. Tabs
+// stretch to the next multiple of `tabstop`, wide characters take two
+// cells and combining marks ride along with the character they're on.
+pub fn width(grapheme: &str, at: usize, tabstop: usize) -> usize {
+    if grapheme == "\t" {
+        tabstop - at % tabstop
+    } else if let Some(shown) = control(grapheme) {
+        shown.len()
+    } else {
+        UnicodeWidthStr::width(grapheme)
+    }
+}
+
+// What's drawn for a control character, which would otherwise mess up the
+// terminal: `^@` and the like for ASCII ones, and the code in angle
+// brackets, like `<85>`, for the rest.
+pub fn control(grapheme: &str) -> Option<String> {
+    let mut chars = grapheme.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c < ' ' && c != '\t' => Some(format!("^{}", (c as u8 + b'@') as char)),
+        (Some('\u{7f}'), None) => Some(String::from("^?")),
+        (Some(c), None) if ('\u{80}'..'\u{a0}').contains(&c) => Some(format!("<{:x}>", c as u32)),
+        _ => None,
+    }
+}
+
+// The screen column `col` is drawn at.
+pub fn display_col(line: &str, col: usize, tabstop: usize) -> usize {
+    line[..col].graphemes(true).fold(0, |at, g| at + width(g, at, tabstop))
+}
+
+// The grapheme drawn over screen column `target`, or the end of the line
+// if it's too short to reach it.
+pub fn at_display(line: &str, target: usize, tabstop: usize) -> usize {
+    let mut at = 0;
+    for (i, g) in line.grapheme_indices(true) {
+        at += width(g, at, tabstop);
+        if at > target {
+            return i;
+        }
+    }
+    line.len()
+}
+
+// How lines are laid out on screen.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layout {
+    pub tabstop: usize,
+    // lines too wide for the window carry on onto the next screen row,
+    // rather than running off the side of it
+    pub wrap: bool,
+    // wrap at a space or punctuation rather than at the window's edge
+    pub linebreak: bool,
+    // drawn at the start of each row a wrapped line carries on onto
+    pub showbreak: String,
+}
+
+// Where `linebreak` may wrap a line: just after any of these.
+const BREAKAT: &str = " \t!@*-+;:,./?";
+
+// One screen row's worth of a line: the bytes in [start, end), the first
+// of which is at display column `col`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Row {
+    pub start: usize,
+    pub end: usize,
+    pub col: usize,
+}
+
+impl Layout {
+    pub fn new() -> Layout {
+        Layout {
+            tabstop: 8,
+            wrap: true,
+            linebreak: false,
+            showbreak: String::new(),
+        }
+    }
+
+    // Where a row's text starts on screen: rows after the first make room
+    // for `showbreak`.
+    pub fn indent(&self, row: usize) -> usize {
+        if row == 0 {
+            0
+        } else {
+            UnicodeWidthStr::width(&self.showbreak[..])
+        }
+    }
+
+    // Splits a line into the rows it takes up in a window `width` cells
+    // wide. Without `wrap` that's always the one row. A grapheme is never
+    // split over two rows; one that doesn't fit goes on to the next.
+    pub fn rows(&self, line: &str, width: usize) -> Vec<Row> {
+        // nothing but tabs and control characters takes up more cells
+        // than it has bytes, so most lines can skip looking at graphemes
+        // altogether. 0xc2 starts every character from U+80 to U+BF.
+        let plain = || line.bytes().all(|b| b >= b' ' && b != 0x7f && b != 0xc2);
+        if !self.wrap || (line.len() <= width && plain()) {
+            return vec![Row { start: 0, end: line.len(), col: 0 }];
+        }
+        let mut rows = Vec::new();
+        let mut start = 0;
+        let mut start_col = 0;
+        let mut at = 0;
+        let mut room = width;
+        // just past the last place in this row linebreak could wrap at
+        let mut brk: Option<(usize, usize)> = None;
+        for (i, g) in line.grapheme_indices(true) {
+            let w = self::width(g, at, self.tabstop);
+            while self.wrap && at + w - start_col > room && i > start {
+                let (end, col) = match brk {
+                    Some(b) if self.linebreak => b,
+                    _ => (i, at),
+                };
+                rows.push(Row { start, end, col: start_col });
+                start = end;
+                start_col = col;
+                room = cmp::max(width.saturating_sub(self.indent(1)), 1);
+                brk = None;
+            }
+            at += w;
+            if BREAKAT.contains(g) {
+                brk = Some((i + g.len(), at));
+            }
+        }
+        rows.push(Row { start, end: line.len(), col: start_col });
+        rows
+    }
+
+    // Which of `rows` the byte `col` is drawn in.
+    pub fn row_of(rows: &[Row], col: usize) -> usize {
+        rows.iter().rposition(|r| r.start <= col).unwrap_or(0)
+    }
+}
+
+#[test]
+fn step_by_grapheme() {
+    // e + combining acute, then a two byte é
+    let line = "ae\u{301}\u{e9}z";
+    assert_eq!(next(line, 0), 1);
+    assert_eq!(next(line, 1), 4);
+    assert_eq!(next(line, 4), 6);
+    assert_eq!(next(line, 7), 7);
+    assert_eq!(prev(line, 6), 4);
+    assert_eq!(prev(line, 4), 1);
+    assert_eq!(prev(line, 0), 0);
+    assert_eq!(last(line), 6);
+    assert_eq!(last(""), 0);
+    assert_eq!(snap(line, 2), 1);
+    assert_eq!(snap(line, 5), 4);
+    assert_eq!(grapheme_index(line, 6), 3);
+    assert_eq!(grapheme_col(line, 3), 6);
+    assert_eq!(grapheme_col(line, 9), 7);
+}
+
+#[test]
+fn display_columns() {
+    let line = "a\u{4e2d}\u{6587}e\u{301}\tx";
+    // each CJK character takes two cells, the combining mark none
+    assert_eq!(display_col(line, 1, 8), 1);
+    assert_eq!(display_col(line, 4, 8), 3);
+    assert_eq!(display_col(line, 7, 8), 5);
+    assert_eq!(display_col(line, 10, 8), 6);
+    // the tab fills out to the next tabstop
+    assert_eq!(display_col(line, 11, 8), 8);
+    assert_eq!(display_col(line, 11, 4), 8);
+    assert_eq!(display_col(line, 11, 3), 9);
+
+    assert_eq!(at_display(line, 0, 8), 0);
+    assert_eq!(at_display(line, 1, 8), 1);
+    assert_eq!(at_display(line, 2, 8), 1);
+    assert_eq!(at_display(line, 3, 8), 4);
+    assert_eq!(at_display(line, 7, 8), 10);
+    assert_eq!(at_display(line, 8, 8), 11);
+    assert_eq!(at_display(line, 20, 8), line.len());
+
+    // control characters are drawn as ^X or <xx>
+    assert_eq!(control("\u{0}"), Some(String::from("^@")));
+    assert_eq!(control("\u{1b}"), Some(String::from("^[")));
+    assert_eq!(control("\u{7f}"), Some(String::from("^?")));
+    assert_eq!(control("\u{85}"), Some(String::from("<85>")));
+    assert_eq!(control("\t"), None);
+    assert_eq!(control("a"), None);
+    assert_eq!(display_col("a\u{0}\u{9b}b", 4, 8), 7);
+    assert_eq!(Layout::new().rows("\u{0}\u{0}\u{0}", 4).len(), 2);
+}
+
+#[test]
+fn wrap_rows() {
+    let mut layout = Layout::new();
+    let line = "the quick brown fox";
+    let row = |start, end, col| Row { start, end, col };
+    assert_eq!(layout.rows(line, 8), vec![row(0, 8, 0), row(8, 16, 8), row(16, 19, 16)]);
+    assert_eq!(layout.rows("", 8), vec![row(0, 0, 0)]);
+
+    layout.linebreak = true;
+    assert_eq!(layout.rows(line, 8), vec![row(0, 4, 0), row(4, 10, 4), row(10, 16, 10), row(16, 19, 16)]);
+
+    layout.showbreak = String::from("> ");
+    layout.linebreak = false;
+    assert_eq!(layout.rows(line, 8), vec![row(0, 8, 0), row(8, 14, 8), row(14, 19, 14)]);
+
+    // a wide character that won't fit goes on to the next row
+    layout.showbreak.clear();
+    assert_eq!(layout.rows("abc\u{4e2d}", 4), vec![row(0, 3, 0), row(3, 6, 3)]);
+    assert_eq!(Layout::row_of(&layout.rows(line, 8), 9), 1);
+
+    layout.wrap = false;
+    assert_eq!(layout.rows(line, 8), vec![row(0, 19, 0)]);
+}
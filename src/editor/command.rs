@@ -1,4 +1,5 @@
 use editor::keyboard::{Key};
+use editor::search::Search;
 
 // A motion can vary a column in the following ways:
 // * not varying it
@@ -14,8 +15,20 @@ pub enum Column {
     Begin, End
 }
 
-enum SkipThing {
-	Word, Sentence, Whitespace,
+// What the word, sentence and paragraph motions skip over. A WORD is
+// anything between white space, where a word stops at punctuation too.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SkipThing {
+	Word, BigWord, Sentence, Paragraph,
+}
+
+// `f`, `F`, `t` and `T`: a character to find in the line, which way,
+// and whether to stop just before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Find {
+    pub c: char,
+    pub forward: bool,
+    pub till: bool,
 }
 
 #[derive(Debug)] 
@@ -26,7 +39,7 @@ pub enum Line {
     Last
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Span {
     Inclusive,
     Exclusive,
@@ -37,64 +50,146 @@ pub enum Span {
 pub enum Motion {
     Goto(Column,Line),
     Scroll(Line),
+    // whole and half screens, since only the buffer knows how big those are
+    Page(Line),
+    HalfPage(Line),
+    // screen rows rather than lines, which differ when lines wrap
+    Row(Line),
+    // Searches, which the state turns into a Goto once it's found where
+    // they end up: what was typed after `/` or `?`, `n` and `N` (true to
+    // turn the last search around), and `*` and `#` for the word at the
+    // point (true going forwards).
+    Search(Search),
+    SearchAgain(bool),
+    SearchWord(bool),
+    // `w`, `b`, `e` and `ge` and the like: to the start of the [count]th
+    // thing on or back, or to its end
+    Next(SkipThing, usize),
+    Prev(SkipThing, usize),
+    NextEnd(SkipThing, usize),
+    PrevEnd(SkipThing, usize),
+    // A character in the line, which the state turns into a Goto like a
+    // search, and `;` and `,` (true to turn the last one around).
+    Find(Find),
+    FindAgain(bool),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Operator {
 	Delete,
-	Insert,
+	Change,
+	Yank,
+	ShiftRight,
+	ShiftLeft,
+}
+
+// Commands that happen at the point rather than over a motion.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edit {
+	PutAfter,
+	PutBefore,
+	Undo,
+	Redo,
+	UndoLine,
+	Repeat,
+	// g- and g+, which move through undo states in time order
+	Earlier,
+	Later,
+	// ZZ and ZQ: write if changed then quit, and quit without writing
+	WriteQuit,
+	Quit,
+	// & and g&: the last :s again on this line, and on every line
+	Substitute,
+	SubstituteAll,
 }
 
 pub enum Action {
 	Operator(Operator),
-	Motion(Motion),
+	Motion(Span, Motion),
+	Edit(Edit),
+	// `/` or `?`, which need a pattern typed before they're a motion
+	Search(bool),
 }
 
 #[derive(Debug)]
 pub struct Command {
 	pub count: usize,
+    pub register: Option<char>,
+    pub operator: Option<Operator>,
     pub span: Span,
     pub motion: Motion,
 }
 
 impl Command {
     pub fn goto(s: Span, c: Column, l: Line) -> Command {
-	    Command{count: 1, span: s, motion: Motion::Goto(c, l) }
+	    Command{count: 1, register: None, operator: None, span: s, motion: Motion::Goto(c, l) }
 	}
 
 	pub fn scroll(l: Line) -> Command {
-	    Command{count: 1, span: Span::Linewise, motion: Motion::Scroll(l) }
+	    Command{count: 1, register: None, operator: None, span: Span::Linewise, motion: Motion::Scroll(l) }
 	}
 }
 
-enum TextObject {
-	Char, Word, Sentence, Line, Paragraph,
-}
 enum Partial {
-	Object(TextObject),
 	Action(Action),
 }
 
 #[derive(Debug)]
-enum BuilderResult {
+pub enum BuilderResult {
     Invalid,
     Pending,
     Command(Command),
+    Edit { count: usize, register: Option<char>, edit: Edit },
+    // the command so far waits on a pattern for `/` (true) or `?`
+    Search(bool),
 }
 
-struct Builder {
-	// TODO; what goes here
+// The builder consumes keys one at a time following vi's grammar:
+//
+//     [count]["register][operator][count]motion
+//
+// Counts on either side of the operator multiply, so `2d3j` is `d6j`.
+// Once a command is complete (or the keys can't form one) the builder
+// resets itself for the next command.
+pub struct Builder {
+	// the count currently being typed
 	count: Option<usize>,
+	// the count typed before the operator, e.g. the 2 in `2d3j`
+	op_count: Option<usize>,
+	register: Option<char>,
+	operator: Option<Operator>,
+	// we've seen a `"` and the next key names the register
+	want_register: bool,
+	// the first key of a two key command like `gg`
+	prefix: Option<char>,
 }
 
 impl Builder {
     pub fn new() -> Builder {
         Builder {
-        	count: None
+        	count: None,
+        	op_count: None,
+        	register: None,
+        	operator: None,
+        	want_register: false,
+        	prefix: None,
         }
     }
+
     pub fn handle_key(&mut self, key: Key) -> BuilderResult {
+        if self.want_register {
+            self.want_register = false;
+            return match key {
+                Key::Char(c) if is_register(c) => {
+                    self.register = Some(c);
+                    BuilderResult::Pending
+                }
+                _ => self.invalid()
+            };
+        }
+
         if let Key::Char(c) = key {
-        	if c.is_digit(10) {
+        	if self.is_count(key) {
         		let c = c.to_digit(10).unwrap() as usize;
         		match self.count {
         			None => {
@@ -106,123 +201,520 @@ impl Builder {
         		};
         		return BuilderResult::Pending;
         	}
+        	if c == '"' && self.operator.is_none() && self.register.is_none() && self.prefix.is_none() {
+        		self.want_register = true;
+        		return BuilderResult::Pending;
+        	}
+        }
+
+        if let (None, Key::Char(c)) = (self.prefix, key) {
+            if c == 'g' || c == 'Z' || c == 'f' || c == 'F' || c == 't' || c == 'T' {
+                self.prefix = Some(c);
+                return BuilderResult::Pending;
+            }
         }
 
-        match self.lookup_key(key) {
-            Some(partial) => {
-            	let cmd = self.build_cmd(partial);
-            	return BuilderResult::Command(cmd);
+        let count = self.total_count();
+        let partial = match self.prefix.take() {
+            Some(prefix) => self.lookup_prefixed(prefix, key, count),
+            None => self.lookup_key(key, count),
+        };
+        match partial {
+            Some(Partial::Action(Action::Operator(op))) => {
+                match self.operator {
+                    None => {
+                        self.operator = Some(op);
+                        self.op_count = self.count.take();
+                        BuilderResult::Pending
+                    }
+                    // doubled operators (`dd`, `>>`) work on [count] lines
+                    Some(pending) if pending == op => {
+                        let lines = count.unwrap_or(1) - 1;
+                        let motion = Motion::Goto(Column::Current, Line::Down(lines));
+                        self.finish(count, Span::Linewise, motion)
+                    }
+                    Some(_) => self.invalid()
+                }
             }
-            None => {
-            	return BuilderResult::Invalid;
+            Some(Partial::Action(Action::Motion(span, motion))) => {
+                // operators only make sense over text, not the window
+                match motion {
+                    Motion::Goto(..) | Motion::SearchAgain(_) | Motion::SearchWord(_) |
+                    Motion::Next(..) | Motion::Prev(..) | Motion::NextEnd(..) | Motion::PrevEnd(..) |
+                    Motion::Find(_) | Motion::FindAgain(_) => {
+                        self.finish(count, span, motion)
+                    }
+                    _ if self.operator.is_none() => self.finish(count, span, motion),
+                    _ => self.invalid()
+                }
             }
+            Some(Partial::Action(Action::Edit(edit))) if self.operator.is_none() => {
+                let result = BuilderResult::Edit {
+                    count: count.unwrap_or(1),
+                    register: self.register,
+                    edit,
+                };
+                self.reset();
+                result
+            }
+            Some(Partial::Action(Action::Search(forward))) => BuilderResult::Search(forward),
+            _ => self.invalid()
         }
-      	BuilderResult::Invalid
-    }
-
-    fn build_cmd(&mut self, partial: Partial) -> Command {
-        Command {
-        	count: self.count.unwrap_or(1),
-        	span: Span::Linewise,
-        	motion: match partial {
-        		Partial::Action(action) => match action {
-        		    Action::Motion(motion) => motion,
-        		    _ => panic!("{:?}", "oh no")
-        		},
-        		_ => {
-        			panic!("{:?}", "oh no");
-        		}
-        	}
+    }
+
+    // Finishes a command waiting on a search with the one typed.
+    pub fn search(&mut self, search: Search) -> BuilderResult {
+        let count = self.total_count();
+        self.finish(count, Span::Exclusive, Motion::Search(search))
+    }
+
+    // True if the key would be part of a count, which `.` leaves out of
+    // the keys it repeats.
+    pub fn is_count(&self, key: Key) -> bool {
+        match key {
+            // a leading 0 is the motion, not a count
+            Key::Char(c) => c.is_ascii_digit() && (c != '0' || self.count.is_some()) &&
+                self.prefix.is_none() && !self.want_register,
+            _ => false,
+        }
+    }
+
+    // The count typed so far, if any.
+    pub fn count(&self) -> Option<usize> {
+        self.total_count()
+    }
+
+    // Forgets the command so far, as when a search is given up on.
+    pub fn cancel(&mut self) {
+        self.reset();
+    }
+
+    // True if no keys of a command have been typed yet.
+    pub fn is_empty(&self) -> bool {
+        self.count.is_none() && self.register.is_none() &&
+            self.operator.is_none() && !self.want_register && self.prefix.is_none()
+    }
+
+    // The effective count, or None if no count was typed at all.
+    fn total_count(&self) -> Option<usize> {
+        match (self.op_count, self.count) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(1) * b.unwrap_or(1)),
         }
     }
 
-    fn lookup_key(&self, key: Key) -> Option<Partial> {
+    fn finish(&mut self, count: Option<usize>, span: Span, motion: Motion) -> BuilderResult {
+        let cmd = Command {
+            count: count.unwrap_or(1),
+            register: self.register,
+            operator: self.operator,
+            span,
+            motion,
+        };
+        self.reset();
+        BuilderResult::Command(cmd)
+    }
+
+    fn invalid(&mut self) -> BuilderResult {
+        self.reset();
+        BuilderResult::Invalid
+    }
+
+    fn reset(&mut self) {
+        *self = Builder::new();
+    }
+
+    fn lookup_prefixed(&self, prefix: char, key: Key, count: Option<usize>) -> Option<Partial> {
+    	match (prefix, key) {
+            // gg is G, but defaulting to the first line
+            ('g', Key::Char('g')) => {
+                let line = Line::Specific(count.unwrap_or(1) - 1);
+                Some(Partial::Action(Action::Motion(Span::Linewise, Motion::Goto(Column::Begin, line))))
+            }
+            ('g', Key::Char('j')) | ('g', Key::Down) => {
+                let n = count.unwrap_or(1);
+                Some(Partial::Action(Action::Motion(Span::Exclusive, Motion::Row(Line::Down(n)))))
+            }
+            ('g', Key::Char('k')) | ('g', Key::Up) => {
+                let n = count.unwrap_or(1);
+                Some(Partial::Action(Action::Motion(Span::Exclusive, Motion::Row(Line::Up(n)))))
+            }
+            ('g', Key::Char('e')) => {
+                let n = count.unwrap_or(1);
+                Some(Partial::Action(Action::Motion(Span::Inclusive, Motion::PrevEnd(SkipThing::Word, n))))
+            }
+            ('g', Key::Char('E')) => {
+                let n = count.unwrap_or(1);
+                Some(Partial::Action(Action::Motion(Span::Inclusive, Motion::PrevEnd(SkipThing::BigWord, n))))
+            }
+            // f and t are inclusive, so `dt)` takes everything up to the `)`
+            (_, _) if "fFtT".contains(prefix) => {
+                let c = match key {
+                    Key::Char(c) => c,
+                    Key::Tab => '\t',
+                    _ => return None,
+                };
+                let find = Find { c, forward: prefix == 'f' || prefix == 't', till: prefix == 't' || prefix == 'T' };
+                let span = if find.forward { Span::Inclusive } else { Span::Exclusive };
+                Some(Partial::Action(Action::Motion(span, Motion::Find(find))))
+            }
+            ('g', Key::Char('-')) => Some(Partial::Action(Action::Edit(Edit::Earlier))),
+            ('Z', Key::Char('Z')) => Some(Partial::Action(Action::Edit(Edit::WriteQuit))),
+            ('Z', Key::Char('Q')) => Some(Partial::Action(Action::Edit(Edit::Quit))),
+            ('g', Key::Char('+')) => Some(Partial::Action(Action::Edit(Edit::Later))),
+            ('g', Key::Char('&')) => Some(Partial::Action(Action::Edit(Edit::SubstituteAll))),
+            _ => None
+        }
+    }
+
+    fn lookup_key(&self, key: Key, count: Option<usize>) -> Option<Partial> {
+        let n = count.unwrap_or(1);
+        let goto = |span, col, line| Some(Partial::Action(Action::Motion(span, Motion::Goto(col, line))));
+        let skip = |span, motion| Some(Partial::Action(Action::Motion(span, motion)));
     	match key {
+            Key::Char('d') => Some(Partial::Action(Action::Operator(Operator::Delete))),
+            Key::Char('c') => Some(Partial::Action(Action::Operator(Operator::Change))),
+            Key::Char('y') => Some(Partial::Action(Action::Operator(Operator::Yank))),
+            Key::Char('>') => Some(Partial::Action(Action::Operator(Operator::ShiftRight))),
+            Key::Char('<') => Some(Partial::Action(Action::Operator(Operator::ShiftLeft))),
+
+            Key::Char('p') => Some(Partial::Action(Action::Edit(Edit::PutAfter))),
+            Key::Char('P') => Some(Partial::Action(Action::Edit(Edit::PutBefore))),
+            Key::Char('u') => Some(Partial::Action(Action::Edit(Edit::Undo))),
+            Key::Ctrl('r') => Some(Partial::Action(Action::Edit(Edit::Redo))),
+            Key::Char('U') => Some(Partial::Action(Action::Edit(Edit::UndoLine))),
+            Key::Char('.') => Some(Partial::Action(Action::Edit(Edit::Repeat))),
+            Key::Char('&') => Some(Partial::Action(Action::Edit(Edit::Substitute))),
+
             Key::Ctrl('h') | Key::Char('h') | Key::Left  => { 
-                Some(Partial::Action(Action::Motion(Motion::Goto(Column::Left(1), Line::Current))))
-            }
-            // Key::Ctrl('j') | Key::Char('j') | Key::Down  => { 
-            //     Some(Command::goto(Span::Linewise, Column::Current, Line::Down(1)))
-            // }
-            // Key::Ctrl('p') | Key::Char('k') | Key::Up  => { 
-            //     Some(Command::goto(Span::Linewise, Column::Current, Line::Up(1)))
-            // }
-            // Key::Char(' ') | Key::Char('l') | Key::Right  => { 
-            //     Some(Command::goto(Span::Exclusive, Column::Right(1), Line::Current))
-            // }
-            // Key::Char('0')  => {
-            //     Some(Command::goto(Span::Exclusive, Column::Specific(0), Line::Current))
-            // }
-            // Key::Char('^')  => {
-            //     Some(Command::goto(Span::Exclusive, Column::Begin, Line::Current))
-            // }
-            // Key::Char('$')  => { 
-            //     Some(Command::goto(Span::Exclusive, Column::End, Line::Current))
-            // }
-            // Key::Char('G')  => { 
-            //     Some(Command::goto(Span::Linewise, Column::Current, Line::Last))
-            // }
-            // Key::Char('+') | Key::Char('m') => { 
-            //     Some(Command::goto(Span::Exclusive, Column::Begin, Line::Down(1)))
-            // }
-            // Key::Char('-') => { 
-            //     Some(Command::goto(Span::Exclusive, Column::Begin, Line::Up(1)))
-            // }
-            // Key::Ctrl('b') => {
-            //     // what an awful way to do this.
-            //     let lines = self.active().unwrap().window().1;
-            //     let count = 1; // TODO: actually use [count]
-            //     let relative = (count * (lines - 2));
-            //     Some(Command::scroll(Line::Up(relative)))
-            // }
-            // Key::Ctrl('f') => {
-            //     // what an awful way to do this.
-            //     let lines = self.active().unwrap().window().1;
-            //     let count = 1; // TODO: actually use [count]
-            //     let relative = (count * (lines - 2));
-            //     Some(Command::scroll(Line::Down(relative)))
-            // }
-            // Key::Ctrl('d') => {
-            //     // what an awful way to do this.
-            //     let lines = self.active().unwrap().window().1;
-            //     let count = 1; // TODO: actually use [count]
-            //     let relative = (count * (lines / 2));
-            //     Some(Command::scroll(Line::Down(relative)))
-            // }
-            // Key::Ctrl('u') => {
-            //     // what an awful way to do this.
-            //     let lines = self.active().unwrap().window().1;
-            //     let count = 1; // TODO: actually use [count]
-            //     let relative = (count * (lines / 2));
-            //     Some(Command::scroll(Line::Up(relative)))
-            // }
-            // Key::Ctrl('e') => {
-            //     Some(Command::scroll(Line::Down(1)))
-            // }
-            // Key::Ctrl('y') => {
-            //     Some(Command::scroll(Line::Up(1)))
-            // }
+                goto(Span::Exclusive, Column::Left(n), Line::Current)
+            }
+            Key::Ctrl('j') | Key::Char('j') | Key::Down  => { 
+                goto(Span::Linewise, Column::Current, Line::Down(n))
+            }
+            Key::Ctrl('p') | Key::Char('k') | Key::Up  => { 
+                goto(Span::Linewise, Column::Current, Line::Up(n))
+            }
+            Key::Char(' ') | Key::Char('l') | Key::Right  => { 
+                goto(Span::Exclusive, Column::Right(n), Line::Current)
+            }
+            Key::Char('0')  => {
+                goto(Span::Exclusive, Column::Specific(0), Line::Current)
+            }
+            Key::Char('^')  => {
+                goto(Span::Exclusive, Column::Begin, Line::Current)
+            }
+            // [count]$ ends on the count-1'th line down
+            Key::Char('$')  => { 
+                goto(Span::Inclusive, Column::End, Line::Down(n - 1))
+            }
+            // G goes to line [count], or the last line without one
+            Key::Char('G')  => { 
+                let line = count.map_or(Line::Last, |n| Line::Specific(n - 1));
+                goto(Span::Linewise, Column::Begin, line)
+            }
+            // Ctrl-M arrives as Enter
+            Key::Char('+') | Key::Enter => { 
+                goto(Span::Linewise, Column::Begin, Line::Down(n))
+            }
+            Key::Char('-') => { 
+                goto(Span::Linewise, Column::Begin, Line::Up(n))
+            }
+            Key::Ctrl('b') | Key::PageUp => {
+                Some(Partial::Action(Action::Motion(Span::Linewise, Motion::Page(Line::Up(n)))))
+            }
+            Key::Ctrl('f') | Key::PageDown => {
+                Some(Partial::Action(Action::Motion(Span::Linewise, Motion::Page(Line::Down(n)))))
+            }
+            // with a count these scroll [count] lines instead of half a screen
+            Key::Ctrl('d') => {
+                let motion = count.map_or(Motion::HalfPage(Line::Down(1)), |n| Motion::Scroll(Line::Down(n)));
+                Some(Partial::Action(Action::Motion(Span::Linewise, motion)))
+            }
+            Key::Ctrl('u') => {
+                let motion = count.map_or(Motion::HalfPage(Line::Up(1)), |n| Motion::Scroll(Line::Up(n)));
+                Some(Partial::Action(Action::Motion(Span::Linewise, motion)))
+            }
+            Key::Ctrl('e') => {
+                Some(Partial::Action(Action::Motion(Span::Linewise, Motion::Scroll(Line::Down(n)))))
+            }
+            Key::Ctrl('y') => {
+                Some(Partial::Action(Action::Motion(Span::Linewise, Motion::Scroll(Line::Up(n)))))
+            }
+            Key::Char('w') => skip(Span::Exclusive, Motion::Next(SkipThing::Word, n)),
+            Key::Char('W') => skip(Span::Exclusive, Motion::Next(SkipThing::BigWord, n)),
+            Key::Char('b') => skip(Span::Exclusive, Motion::Prev(SkipThing::Word, n)),
+            Key::Char('B') => skip(Span::Exclusive, Motion::Prev(SkipThing::BigWord, n)),
+            Key::Char('e') => skip(Span::Inclusive, Motion::NextEnd(SkipThing::Word, n)),
+            Key::Char('E') => skip(Span::Inclusive, Motion::NextEnd(SkipThing::BigWord, n)),
+            Key::Char(')') => skip(Span::Exclusive, Motion::Next(SkipThing::Sentence, n)),
+            Key::Char('(') => skip(Span::Exclusive, Motion::Prev(SkipThing::Sentence, n)),
+            Key::Char('}') => skip(Span::Exclusive, Motion::Next(SkipThing::Paragraph, n)),
+            Key::Char('{') => skip(Span::Exclusive, Motion::Prev(SkipThing::Paragraph, n)),
+            // the state decides the span, since it depends which way the
+            // last find went
+            Key::Char(';') => Some(Partial::Action(Action::Motion(Span::Exclusive, Motion::FindAgain(false)))),
+            Key::Char(',') => Some(Partial::Action(Action::Motion(Span::Exclusive, Motion::FindAgain(true)))),
+            Key::Char('/') => Some(Partial::Action(Action::Search(true))),
+            Key::Char('?') => Some(Partial::Action(Action::Search(false))),
+            Key::Char('n') => Some(Partial::Action(Action::Motion(Span::Exclusive, Motion::SearchAgain(false)))),
+            Key::Char('N') => Some(Partial::Action(Action::Motion(Span::Exclusive, Motion::SearchAgain(true)))),
+            Key::Char('*') => Some(Partial::Action(Action::Motion(Span::Exclusive, Motion::SearchWord(true)))),
+            Key::Char('#') => Some(Partial::Action(Action::Motion(Span::Exclusive, Motion::SearchWord(false)))),
             _ => None
         }
     }
 }
 
+// Registers a-z (A-Z appends), the numbered 0-9, the unnamed `"`
+// and the small delete `-`.
+fn is_register(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '"' || c == '-'
+}
+
 
 #[test]
 fn builder_enter_count() {
     let mut builder = Builder::new();
     let cmd = builder.handle_key(Key::Char('1'));
 
-    match cmd {
-        BuilderResult::Pending => assert!(true),
-        _ => assert!(false, "should be pending"),
-    }
+    assert!(matches!(cmd, BuilderResult::Pending), "should be pending");
 
     let cmd = builder.handle_key(Key::Char('2'));
-    match cmd {
-        BuilderResult::Pending => assert!(true),
-        _ => assert!(false, "should be pending"),
-    }
+    assert!(matches!(cmd, BuilderResult::Pending), "should be pending");
     assert_eq!(builder.count, Some(12));
 }
 
+#[cfg(test)]
+fn build(keys: &str) -> Command {
+    let mut builder = Builder::new();
+    let mut chars = keys.chars().peekable();
+    while let Some(c) = chars.next() {
+        match builder.handle_key(Key::Char(c)) {
+            BuilderResult::Pending if chars.peek().is_some() => {}
+            BuilderResult::Command(cmd) => {
+                assert!(chars.peek().is_none(), "{} finished early", keys);
+                return cmd;
+            }
+            other => panic!("{}: unexpected {:?}", keys, other),
+        }
+    }
+    unreachable!()
+}
+
+#[test]
+fn builder_operators() {
+    let cmd = build("2dd");
+    assert_eq!(cmd.operator, Some(Operator::Delete));
+    assert_eq!(cmd.span, Span::Linewise);
+    match cmd.motion {
+        Motion::Goto(Column::Current, Line::Down(1)) => {}
+        ref m => panic!("{:?}", m),
+    }
+
+    let cmd = build("c$");
+    assert_eq!(cmd.operator, Some(Operator::Change));
+    assert_eq!(cmd.span, Span::Inclusive);
+
+    let cmd = build(">j");
+    assert_eq!(cmd.operator, Some(Operator::ShiftRight));
+    assert_eq!(cmd.span, Span::Linewise);
+
+    let cmd = build("0");
+    assert_eq!(cmd.operator, None);
+    match cmd.motion {
+        Motion::Goto(Column::Specific(0), Line::Current) => {}
+        ref m => panic!("{:?}", m),
+    }
+}
+
+#[test]
+fn builder_counts_multiply() {
+    let cmd = build("2d3j");
+    assert_eq!(cmd.count, 6);
+    match cmd.motion {
+        Motion::Goto(Column::Current, Line::Down(6)) => {}
+        ref m => panic!("{:?}", m),
+    }
+
+    let cmd = build("10G");
+    match cmd.motion {
+        Motion::Goto(_, Line::Specific(9)) => {}
+        ref m => panic!("{:?}", m),
+    }
+}
+
+#[test]
+fn builder_registers() {
+    let cmd = build("\"a3yy");
+    assert_eq!(cmd.register, Some('a'));
+    assert_eq!(cmd.operator, Some(Operator::Yank));
+    assert_eq!(cmd.count, 3);
+
+    let mut builder = Builder::new();
+    builder.handle_key(Key::Char('d'));
+    match builder.handle_key(Key::Char('y')) {
+        BuilderResult::Invalid => {}
+        other => panic!("mixed operators should be invalid: {:?}", other),
+    }
+    // and the builder starts over afterwards
+    assert!(builder.operator.is_none());
+}
+
+#[test]
+fn builder_counts_apply_to_motions() {
+    match build("5j").motion {
+        Motion::Goto(Column::Current, Line::Down(5)) => {}
+        ref m => panic!("{:?}", m),
+    }
+    match build("2$").motion {
+        Motion::Goto(Column::End, Line::Down(1)) => {}
+        ref m => panic!("{:?}", m),
+    }
+    let mut builder = Builder::new();
+    builder.handle_key(Key::Char('3'));
+    match builder.handle_key(Key::Ctrl('f')) {
+        BuilderResult::Command(Command { motion: Motion::Page(Line::Down(3)), .. }) => {}
+        other => panic!("{:?}", other),
+    }
+    // but you can't delete a screenful
+    builder.handle_key(Key::Char('d'));
+    match builder.handle_key(Key::Ctrl('f')) {
+        BuilderResult::Invalid => {}
+        other => panic!("{:?}", other),
+    }
+    assert!(builder.is_empty());
+}
+
+#[test]
+fn builder_edits() {
+    let mut builder = Builder::new();
+    for c in "\"b3".chars() {
+        builder.handle_key(Key::Char(c));
+    }
+    match builder.handle_key(Key::Char('P')) {
+        BuilderResult::Edit { count: 3, register: Some('b'), edit: Edit::PutBefore } => {}
+        other => panic!("{:?}", other),
+    }
+    builder.handle_key(Key::Char('d'));
+    match builder.handle_key(Key::Char('p')) {
+        BuilderResult::Invalid => {}
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn builder_prefixed() {
+    match build("3gg").motion {
+        Motion::Goto(Column::Begin, Line::Specific(2)) => {}
+        ref m => panic!("{:?}", m),
+    }
+    match build("2gj").motion {
+        Motion::Row(Line::Down(2)) => {}
+        ref m => panic!("{:?}", m),
+    }
+    let mut builder = Builder::new();
+    builder.handle_key(Key::Char('2'));
+    builder.handle_key(Key::Char('g'));
+    match builder.handle_key(Key::Char('-')) {
+        BuilderResult::Edit { count: 2, edit: Edit::Earlier, .. } => {}
+        other => panic!("{:?}", other),
+    }
+    builder.handle_key(Key::Char('Z'));
+    match builder.handle_key(Key::Char('Q')) {
+        BuilderResult::Edit { edit: Edit::Quit, .. } => {}
+        other => panic!("{:?}", other),
+    }
+    builder.handle_key(Key::Char('g'));
+    match builder.handle_key(Key::Char('&')) {
+        BuilderResult::Edit { edit: Edit::SubstituteAll, .. } => {}
+        other => panic!("{:?}", other),
+    }
+    builder.handle_key(Key::Char('g'));
+    match builder.handle_key(Key::Char('x')) {
+        BuilderResult::Invalid => {}
+        other => panic!("{:?}", other),
+    }
+    assert!(builder.is_empty());
+}
+
+#[test]
+fn builder_searches() {
+    let mut builder = Builder::new();
+    for c in "2d".chars() {
+        builder.handle_key(Key::Char(c));
+    }
+    match builder.handle_key(Key::Char('/')) {
+        BuilderResult::Search(true) => {}
+        other => panic!("{:?}", other),
+    }
+    // the count and operator wait for the pattern
+    let search = Search::parse("foo", true).unwrap();
+    match builder.search(search.clone()) {
+        BuilderResult::Command(Command { count: 2, operator: Some(Operator::Delete), motion: Motion::Search(s), .. }) => {
+            assert_eq!(s, search);
+        }
+        other => panic!("{:?}", other),
+    }
+    assert!(builder.is_empty());
+
+    match build("yN").motion {
+        Motion::SearchAgain(true) => {}
+        ref m => panic!("{:?}", m),
+    }
+    match build("#").motion {
+        Motion::SearchWord(false) => {}
+        ref m => panic!("{:?}", m),
+    }
+}
+
+#[test]
+fn builder_skips() {
+    let cmd = build("d2w");
+    assert_eq!(cmd.operator, Some(Operator::Delete));
+    assert_eq!(cmd.span, Span::Exclusive);
+    match cmd.motion {
+        Motion::Next(SkipThing::Word, 2) => {}
+        ref m => panic!("{:?}", m),
+    }
+    let cmd = build("3E");
+    assert_eq!(cmd.span, Span::Inclusive);
+    match cmd.motion {
+        Motion::NextEnd(SkipThing::BigWord, 3) => {}
+        ref m => panic!("{:?}", m),
+    }
+    match build("cgE").motion {
+        Motion::PrevEnd(SkipThing::BigWord, 1) => {}
+        ref m => panic!("{:?}", m),
+    }
+    match build("y(").motion {
+        Motion::Prev(SkipThing::Sentence, 1) => {}
+        ref m => panic!("{:?}", m),
+    }
+    match build("2}").motion {
+        Motion::Next(SkipThing::Paragraph, 2) => {}
+        ref m => panic!("{:?}", m),
+    }
+}
+
+#[test]
+fn builder_finds() {
+    let cmd = build("d2t)");
+    assert_eq!(cmd.operator, Some(Operator::Delete));
+    assert_eq!(cmd.count, 2);
+    assert_eq!(cmd.span, Span::Inclusive);
+    match cmd.motion {
+        Motion::Find(Find { c: ')', forward: true, till: true }) => {}
+        ref m => panic!("{:?}", m),
+    }
+    // the key after f is what to find, even a digit or a quote
+    match build("F3").motion {
+        Motion::Find(Find { c: '3', forward: false, till: false }) => {}
+        ref m => panic!("{:?}", m),
+    }
+    assert_eq!(build("T\"").span, Span::Exclusive);
+    match build("c,").motion {
+        Motion::FindAgain(true) => {}
+        ref m => panic!("{:?}", m),
+    }
+}
@@ -0,0 +1,53 @@
+extern crate libc;
+
+use std::panic;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Getting out alive when vex is killed or panics: the terminal has to be
+// put back the way it was and modified buffers saved somewhere, neither of
+// which is safe to do from a signal handler or the middle of a panic. So
+// both just leave a note here, and `main` does the rest once it's back in
+// control with the editor's state unborrowed.
+
+// the signal that's been caught, or 0
+static SIGNAL: AtomicUsize = AtomicUsize::new(0);
+// what a panic had to say, kept for after the terminal's back
+static PANIC: Mutex<Option<String>> = Mutex::new(None);
+
+extern "C" fn caught(signal: libc::c_int) {
+    SIGNAL.store(signal as usize, Ordering::SeqCst);
+}
+
+// Has SIGTERM and SIGHUP stop the editor rather than kill it outright.
+pub fn catch_signals() {
+    for &signal in &[libc::SIGTERM, libc::SIGHUP] {
+        unsafe {
+            libc::signal(signal, caught as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+    }
+}
+
+// The signal that's been caught, if any, by name.
+pub fn caught_signal() -> Option<&'static str> {
+    match SIGNAL.load(Ordering::SeqCst) as libc::c_int {
+        0 => None,
+        libc::SIGTERM => Some("TERM"),
+        libc::SIGHUP => Some("HUP"),
+        _ => Some("?"),
+    }
+}
+
+// Keeps panic messages back, since they'd only be lost in the raw terminal.
+pub fn catch_panics() {
+    panic::set_hook(Box::new(|info| {
+        if let Ok(mut message) = PANIC.lock() {
+            *message = Some(info.to_string());
+        }
+    }));
+}
+
+// The message of the last panic, if there's been one.
+pub fn panic_message() -> Option<String> {
+    PANIC.lock().ok().and_then(|mut message| message.take())
+}
@@ -0,0 +1,702 @@
+use std::fmt;
+use std::result;
+
+use editor::buffer::Buffer;
+use editor::options::Options;
+use editor::search;
+
+// Parses the command line typed after `:` into a typed Command, with its
+// line range, `!`, count and argument pulled apart, the way vi does it:
+//
+//     [range][name][!][count][argument]
+
+// What went wrong running a command line, shown on the status line.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    NotACommand(String),
+    NoRange,
+    NoBang,
+    InvalidRange,
+    MarkNotSet,
+    PatternNotFound(String),
+    TrailingCharacters(String),
+    InvalidArgument(String),
+    NoFileName,
+    FileExists,
+    PartialWrite,
+    // the file had bytes that didn't decode, which writing would lose
+    ConversionFailed,
+    // unsaved changes in the current buffer, or in the one named
+    NotSaved,
+    NotSavedIn(String),
+    ReadOnly,
+    InvalidPattern(String),
+    // already worded by whatever found it
+    Message(String),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotACommand(ref line) => write!(f, "E492: Not an editor command: {}", line),
+            Error::NoRange => write!(f, "E481: No range allowed"),
+            Error::NoBang => write!(f, "E477: No ! allowed"),
+            Error::InvalidRange => write!(f, "E16: Invalid range"),
+            Error::MarkNotSet => write!(f, "E20: Mark not set"),
+            Error::PatternNotFound(ref pat) => write!(f, "E486: Pattern not found: {}", pat),
+            Error::TrailingCharacters(ref arg) => write!(f, "E488: Trailing characters: {}", arg),
+            Error::InvalidArgument(ref arg) => write!(f, "E474: Invalid argument: {}", arg),
+            Error::NoFileName => write!(f, "E32: No file name"),
+            Error::FileExists => write!(f, "E13: File exists (add ! to override)"),
+            Error::PartialWrite => write!(f, "E140: Use ! to write partial buffer"),
+            Error::ConversionFailed => write!(f, "E513: Write error, conversion failed (add ! to override)"),
+            Error::NotSaved => write!(f, "E37: No write since last change (add ! to override)"),
+            Error::NotSavedIn(ref name) => write!(f, "E162: No write since last change for buffer \"{}\"", name),
+            Error::ReadOnly => write!(f, "E45: 'readonly' option is set (add ! to override)"),
+            Error::InvalidPattern(ref pat) => write!(f, "E383: Invalid search string: {}", pat),
+            Error::Message(ref msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Name {
+    // just a range, which goes to its last line
+    Goto,
+    Write,
+    // write then quit; Exit only writes if there are changes
+    WriteQuit,
+    Exit,
+    WriteAll,
+    Quit,
+    QuitAll,
+    WriteQuitAll,
+    Set,
+    Earlier,
+    Later,
+    UndoList,
+    Edit,
+    // toggle a hex dump of the buffer
+    Hex,
+    NoHlsearch,
+    Substitute,
+    // `:&`, the last substitute again
+    SubstituteRepeat,
+    // run a command on each line that matches, or with Vglobal each one
+    // that doesn't
+    Global,
+    Vglobal,
+    Normal,
+    Delete,
+    Yank,
+    Move,
+    Copy,
+    Join,
+    ShiftRight,
+    ShiftLeft,
+    Put,
+    Mark,
+}
+
+// What a command accepts.
+struct Def {
+    name: &'static str,
+    // how short it can be abbreviated to
+    abbrev: usize,
+    cmd: Name,
+    range: bool,
+    bang: bool,
+    count: bool,
+}
+
+const COMMANDS: &[Def] = &[
+    Def { name: "write", abbrev: 1, cmd: Name::Write, range: true, bang: true, count: false },
+    Def { name: "wq", abbrev: 2, cmd: Name::WriteQuit, range: true, bang: true, count: false },
+    Def { name: "wall", abbrev: 2, cmd: Name::WriteAll, range: false, bang: true, count: false },
+    Def { name: "wqall", abbrev: 3, cmd: Name::WriteQuitAll, range: false, bang: true, count: false },
+    Def { name: "xit", abbrev: 1, cmd: Name::Exit, range: true, bang: true, count: false },
+    Def { name: "xall", abbrev: 2, cmd: Name::WriteQuitAll, range: false, bang: true, count: false },
+    Def { name: "exit", abbrev: 3, cmd: Name::Exit, range: true, bang: true, count: false },
+    Def { name: "quit", abbrev: 1, cmd: Name::Quit, range: false, bang: true, count: false },
+    Def { name: "qall", abbrev: 2, cmd: Name::QuitAll, range: false, bang: true, count: false },
+    Def { name: "quitall", abbrev: 5, cmd: Name::QuitAll, range: false, bang: true, count: false },
+    Def { name: "set", abbrev: 2, cmd: Name::Set, range: false, bang: false, count: false },
+    Def { name: "earlier", abbrev: 2, cmd: Name::Earlier, range: false, bang: false, count: false },
+    Def { name: "later", abbrev: 3, cmd: Name::Later, range: false, bang: false, count: false },
+    Def { name: "undolist", abbrev: 5, cmd: Name::UndoList, range: false, bang: false, count: false },
+    Def { name: "edit", abbrev: 1, cmd: Name::Edit, range: false, bang: true, count: false },
+    Def { name: "hex", abbrev: 3, cmd: Name::Hex, range: false, bang: false, count: false },
+    Def { name: "nohlsearch", abbrev: 3, cmd: Name::NoHlsearch, range: false, bang: false, count: false },
+    Def { name: "substitute", abbrev: 1, cmd: Name::Substitute, range: true, bang: false, count: false },
+    Def { name: "&", abbrev: 1, cmd: Name::SubstituteRepeat, range: true, bang: false, count: false },
+    Def { name: "global", abbrev: 1, cmd: Name::Global, range: true, bang: true, count: false },
+    Def { name: "vglobal", abbrev: 1, cmd: Name::Vglobal, range: true, bang: false, count: false },
+    Def { name: "normal", abbrev: 4, cmd: Name::Normal, range: true, bang: true, count: false },
+    Def { name: "delete", abbrev: 1, cmd: Name::Delete, range: true, bang: false, count: false },
+    Def { name: "yank", abbrev: 1, cmd: Name::Yank, range: true, bang: false, count: false },
+    Def { name: "move", abbrev: 1, cmd: Name::Move, range: true, bang: false, count: false },
+    Def { name: "copy", abbrev: 2, cmd: Name::Copy, range: true, bang: false, count: false },
+    Def { name: "t", abbrev: 1, cmd: Name::Copy, range: true, bang: false, count: false },
+    Def { name: "join", abbrev: 1, cmd: Name::Join, range: true, bang: true, count: true },
+    Def { name: ">", abbrev: 1, cmd: Name::ShiftRight, range: true, bang: false, count: false },
+    Def { name: "<", abbrev: 1, cmd: Name::ShiftLeft, range: true, bang: false, count: false },
+    Def { name: "put", abbrev: 2, cmd: Name::Put, range: true, bang: true, count: false },
+    Def { name: "k", abbrev: 1, cmd: Name::Mark, range: true, bang: false, count: false },
+    Def { name: "mark", abbrev: 2, cmd: Name::Mark, range: true, bang: false, count: false },
+];
+
+fn lookup(name: &str) -> Option<&'static Def> {
+    COMMANDS.iter().find(|def| name.len() >= def.abbrev && def.name.starts_with(name))
+}
+
+// Where an address starts counting from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Base {
+    Current,
+    Last,
+    Number(usize),
+    Mark(char),
+    // the next line with the pattern in it, or the previous one
+    Search(String),
+    SearchBack(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Address {
+    pub base: Base,
+    pub offset: isize,
+}
+
+impl Address {
+    // The line number (counting from 1, with 0 meaning before the first
+    // line) the address is at, searching from line `from` with the case
+    // options in `options`.
+    pub fn resolve(&self, buf: &Buffer, from: usize, options: &Options) -> Result<usize> {
+        let line = match self.base {
+            Base::Current => from,
+            Base::Last => buf.lines.len(),
+            Base::Number(n) => n,
+            Base::Mark(c) => buf.mark(c).ok_or(Error::MarkNotSet)?.1 + 1,
+            Base::Search(ref pat) => find(buf, pat, from, true, options)?,
+            Base::SearchBack(ref pat) => find(buf, pat, from, false, options)?,
+        };
+        let line = if line > isize::MAX as usize { None } else { (line as isize).checked_add(self.offset) };
+        match line {
+            Some(line) if line >= 0 && line <= buf.lines.len() as isize => Ok(line as usize),
+            _ => Err(Error::InvalidRange),
+        }
+    }
+}
+
+fn find(buf: &Buffer, pat: &str, from: usize, forward: bool, options: &Options) -> Result<usize> {
+    let re = search::compile(pat, options.ignorecase, options.smartcase)
+        .map_err(|_| Error::InvalidPattern(pat.to_owned()))?;
+    match buf.find_line(&re, from.saturating_sub(1), forward) {
+        Some(l) => Ok(l + 1),
+        None => Err(Error::PatternNotFound(pat.to_owned())),
+    }
+}
+
+// Up to two addresses. `%` is short for `1,$`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Range {
+    pub start: Option<Address>,
+    pub end: Option<Address>,
+    // separated by `;`, so the second is found from the first
+    pub from_start: bool,
+}
+
+impl Range {
+    pub fn is_empty(&self) -> bool {
+        self.start.is_none()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Command {
+    pub range: Range,
+    pub name: Name,
+    pub bang: bool,
+    pub count: Option<usize>,
+    pub arg: String,
+}
+
+// The `++name=value` options that can come before a file name, as in
+// `:e ++enc=latin1 notes.txt`.
+#[derive(Debug, Default, PartialEq)]
+pub struct FileOpts {
+    pub enc: Option<String>,
+    // `++bin` or `++nobin`
+    pub bin: Option<bool>,
+}
+
+impl Command {
+    // Splits any `++` options off the front of the argument, leaving the
+    // file name.
+    pub fn file_arg(&self) -> Result<(FileOpts, &str)> {
+        let mut opts = FileOpts::default();
+        let mut rest = &self.arg[..];
+        while rest.starts_with("++") {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let opt = &rest[2..end];
+            match opt.find('=').map(|i| (&opt[..i], &opt[i + 1..])) {
+                Some(("enc", value)) | Some(("encoding", value)) => opts.enc = Some(value.to_owned()),
+                None if opt == "bin" || opt == "binary" => opts.bin = Some(true),
+                None if opt == "nobin" || opt == "nobinary" => opts.bin = Some(false),
+                _ => return Err(Error::InvalidArgument(rest[..end].to_owned())),
+            }
+            rest = rest[end..].trim_start();
+        }
+        Ok((opts, rest))
+    }
+
+    // Splits `:g`'s `/pattern/command` in two. Any character that isn't a
+    // letter, digit, `\`, `"` or `|` can stand in for the `/`s.
+    pub fn pattern_arg(&self) -> Result<(String, &str)> {
+        match self.arg.chars().next() {
+            Some(c) if !c.is_alphanumeric() && !"\\\"|".contains(c) => {
+                let (pattern, rest) = search::until(&self.arg[c.len_utf8()..], c);
+                Ok((pattern, rest.unwrap_or("").trim_start()))
+            }
+            Some(_) => Err(Error::Message(String::from("E146: Regular expressions can't be delimited by letters"))),
+            None => Err(Error::Message(String::from("Regular expression missing from :global"))),
+        }
+    }
+
+    // The register and count after :d or :y, as in `:d a 3`.
+    pub fn register_arg(&self) -> Result<(Option<char>, Option<usize>)> {
+        let mut rest = self.arg.trim();
+        let register = match rest.chars().next() {
+            Some(c) if !c.is_ascii_digit() => {
+                rest = &rest[c.len_utf8()..];
+                Some(c)
+            }
+            _ => None,
+        };
+        Ok((register, count_arg(rest)?))
+    }
+
+    // How far :> or :< shifts, one more for each `>` or `<` after the
+    // first, and the count after them.
+    pub fn shift_arg(&self) -> Result<(usize, Option<usize>)> {
+        let c = if self.name == Name::ShiftRight { '>' } else { '<' };
+        let more = self.arg.chars().take_while(|&d| d == c).count();
+        Ok((more + 1, count_arg(&self.arg[more..])?))
+    }
+
+    // The line (counting from 1, with 0 meaning before the first line) the
+    // range ends on, the current line without one, for commands like :pu
+    // that can go above the first line.
+    pub fn line(&self, buf: &Buffer, options: &Options) -> Result<usize> {
+        let current = buf.point().1 + 1;
+        let start = match self.range.start {
+            Some(ref a) => a.resolve(buf, current, options)?,
+            None => return Ok(current),
+        };
+        match self.range.end {
+            Some(ref b) => b.resolve(buf, if self.range.from_start { start } else { current }, options),
+            None => Ok(start),
+        }
+    }
+
+    // The first and last lines (counting from 0) the command applies to,
+    // the current line if it wasn't given a range. A count starts from
+    // the last line of the range, so `:d 3` deletes this line and the
+    // two below it.
+    pub fn lines(&self, buf: &Buffer, options: &Options) -> Result<(usize, usize)> {
+        let current = buf.point().1 + 1;
+        let (start, end) = match (&self.range.start, &self.range.end) {
+            (&None, _) => (current, current),
+            (Some(a), &None) => {
+                let a = a.resolve(buf, current, options)?;
+                (a, a)
+            }
+            (Some(a), Some(b)) => {
+                let a = a.resolve(buf, current, options)?;
+                let b = b.resolve(buf, if self.range.from_start { a } else { current }, options)?;
+                (a, b)
+            }
+        };
+        // a backwards range is taken the right way round
+        let (start, end) = if start > end { (end, start) } else { (start, end) };
+        let (start, end) = match self.count {
+            Some(0) => return Err(Error::InvalidArgument(String::from("0"))),
+            Some(n) => (end, end.saturating_add(n - 1)),
+            None => (start, end),
+        };
+        let last = buf.lines.len();
+        Ok((start.max(1) - 1, end.max(1).min(last) - 1))
+    }
+}
+
+fn count_arg(s: &str) -> Result<Option<usize>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None);
+    }
+    match s.parse() {
+        Ok(0) => Err(Error::Message(String::from("E939: Positive count required"))),
+        Ok(n) => Ok(Some(n)),
+        // too big to hold, which runs to the last line all the same
+        Err(_) if s.chars().all(|c| c.is_ascii_digit()) => Ok(Some(usize::MAX)),
+        Err(_) => Err(Error::TrailingCharacters(s.to_owned())),
+    }
+}
+
+// The address after :m or :t, as a line counting from 1, with 0 meaning
+// before the first line.
+pub fn address(s: &str, buf: &Buffer, options: &Options) -> Result<usize> {
+    let mut p = Parser { s, pos: 0 };
+    let address = p.address()?.ok_or_else(|| Error::Message(String::from("E14: Invalid address")))?;
+    p.skip(char::is_whitespace);
+    if !p.rest().is_empty() {
+        return Err(Error::TrailingCharacters(p.rest().to_owned()));
+    }
+    address.resolve(buf, buf.point().1 + 1, options)
+}
+
+pub fn parse(line: &str) -> Result<Command> {
+    let mut p = Parser { s: line, pos: 0 };
+    p.skip(|c| c == ':' || c.is_whitespace());
+    let range = p.range()?;
+    p.skip(char::is_whitespace);
+
+    // `&`, `>` and `<` are names all of their own, and `:ka` is `:k a`
+    let name = match p.take(char::is_alphabetic) {
+        "" if p.eat('&') => "&",
+        "" if p.eat('>') => ">",
+        "" if p.eat('<') => "<",
+        name if name.len() == 2 && name.starts_with('k') && lookup(name).is_none() => {
+            p.pos -= 1;
+            "k"
+        }
+        name => name,
+    };
+    let def = if name.is_empty() {
+        if !p.rest().trim().is_empty() {
+            return Err(Error::TrailingCharacters(p.rest().to_owned()));
+        }
+        None
+    } else {
+        Some(lookup(name).ok_or_else(|| Error::NotACommand(line.trim().to_owned()))?)
+    };
+    let bang = p.eat('!');
+    p.skip(char::is_whitespace);
+    let mut count = None;
+    let cmd = match def {
+        Some(def) => {
+            if !def.range && !range.is_empty() {
+                return Err(Error::NoRange);
+            }
+            if !def.bang && bang {
+                return Err(Error::NoBang);
+            }
+            if def.count {
+                let digits = p.take(|c| c.is_ascii_digit());
+                count = digits.parse().ok();
+                p.skip(char::is_whitespace);
+            }
+            def.cmd
+        }
+        None => Name::Goto,
+    };
+    Ok(Command {
+        range,
+        name: cmd,
+        bang,
+        count,
+        arg: p.rest().trim_end().to_owned(),
+    })
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.s[self.pos..]
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn take<F: Fn(char) -> bool>(&mut self, f: F) -> &'a str {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if !f(c) {
+                break;
+            }
+            self.pos += c.len_utf8();
+        }
+        &self.s[start..self.pos]
+    }
+
+    fn skip<F: Fn(char) -> bool>(&mut self, f: F) {
+        self.take(f);
+    }
+
+    fn range(&mut self) -> Result<Range> {
+        let mut range = Range { start: None, end: None, from_start: false };
+        if self.eat('%') {
+            range.start = Some(Address { base: Base::Number(1), offset: 0 });
+            range.end = Some(Address { base: Base::Last, offset: 0 });
+            return Ok(range);
+        }
+        range.start = self.address()?;
+        // vi lets you give any number of addresses and uses the last two
+        loop {
+            self.skip(char::is_whitespace);
+            let from_start = if self.eat(',') {
+                false
+            } else if self.eat(';') {
+                true
+            } else {
+                break;
+            };
+            // a missing address either side of the comma is the current line
+            let current = Address { base: Base::Current, offset: 0 };
+            let next = self.address()?.unwrap_or_else(|| current.clone());
+            if range.start.is_none() {
+                range.start = Some(current);
+            } else if range.end.is_some() {
+                range.start = range.end.take();
+            }
+            range.end = Some(next);
+            range.from_start = from_start;
+        }
+        Ok(range)
+    }
+
+    fn address(&mut self) -> Result<Option<Address>> {
+        self.skip(char::is_whitespace);
+        let base = match self.peek() {
+            Some('.') => {
+                self.pos += 1;
+                Some(Base::Current)
+            }
+            Some('$') => {
+                self.pos += 1;
+                Some(Base::Last)
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let digits = self.take(|c| c.is_ascii_digit());
+                Some(Base::Number(digits.parse().map_err(|_| Error::InvalidRange)?))
+            }
+            Some('\'') => {
+                self.pos += 1;
+                match self.peek() {
+                    Some(c) => {
+                        self.pos += c.len_utf8();
+                        Some(Base::Mark(c))
+                    }
+                    None => return Err(Error::MarkNotSet),
+                }
+            }
+            Some(delim) if delim == '/' || delim == '?' => {
+                self.pos += 1;
+                let pat = self.pattern(delim);
+                Some(if delim == '/' { Base::Search(pat) } else { Base::SearchBack(pat) })
+            }
+            _ => None,
+        };
+        // `+3`, `-`, or just a number straight after an address
+        let mut offset: isize = 0;
+        let mut any = false;
+        loop {
+            let sign = match self.peek() {
+                Some('+') => 1,
+                Some('-') => -1,
+                Some(c) if c.is_ascii_digit() && base.is_some() => {
+                    let n = self.number()?;
+                    offset = offset.checked_add(n).ok_or(Error::InvalidRange)?;
+                    continue;
+                }
+                _ => break,
+            };
+            self.pos += 1;
+            any = true;
+            let n = if self.peek().is_some_and(|c| c.is_ascii_digit()) { self.number()? } else { 1 };
+            offset = offset.checked_add(sign * n).ok_or(Error::InvalidRange)?;
+        }
+        Ok(match base {
+            Some(base) => Some(Address { base, offset }),
+            None if any => Some(Address { base: Base::Current, offset }),
+            None => None,
+        })
+    }
+
+    fn number(&mut self) -> Result<isize> {
+        self.take(|c| c.is_ascii_digit()).parse().map_err(|_| Error::InvalidRange)
+    }
+
+    // Reads up to an unescaped `delim`, or the end of the line.
+    fn pattern(&mut self, delim: char) -> String {
+        let mut pat = String::new();
+        while let Some(c) = self.peek() {
+            self.pos += c.len_utf8();
+            if c == delim {
+                break;
+            }
+            if c == '\\' && self.peek() == Some(delim) {
+                self.pos += delim.len_utf8();
+                pat.push(delim);
+                continue;
+            }
+            pat.push(c);
+        }
+        pat
+    }
+}
+
+#[cfg(test)]
+fn addr(base: Base, offset: isize) -> Option<Address> {
+    Some(Address { base, offset })
+}
+
+#[test]
+fn parse_names() {
+    let cmd = parse("q").unwrap();
+    assert_eq!(cmd.name, Name::Quit);
+    assert!(!cmd.bang);
+    assert!(parse(":quit!").unwrap().bang);
+    assert_eq!(parse("  ea 10s").unwrap().arg, "10s");
+    assert_eq!(parse("undol").unwrap().name, Name::UndoList);
+    assert_eq!(parse("w").unwrap().name, Name::Write);
+    assert_eq!(parse("wq").unwrap().name, Name::WriteQuit);
+    assert_eq!(parse("wa").unwrap().name, Name::WriteAll);
+    assert_eq!(parse("x").unwrap().name, Name::Exit);
+    assert_eq!(parse("xa").unwrap().name, Name::WriteQuitAll);
+    assert_eq!(parse("qa!").unwrap().name, Name::QuitAll);
+    assert_eq!(parse("1,2w >> out").unwrap().arg, ">> out");
+    assert_eq!(parse("set ts=4 ").unwrap().arg, "ts=4");
+    assert_eq!(parse("e!").unwrap().name, Name::Edit);
+    assert_eq!(parse("%s/a/b/g").unwrap().arg, "/a/b/g");
+    assert_eq!(parse("se").unwrap().name, Name::Set);
+    let cmd = parse("'a,'b&&").unwrap();
+    assert_eq!((cmd.name, &cmd.arg[..]), (Name::SubstituteRepeat, "&"));
+
+    let cmd = parse("e ++enc=latin1 my file").unwrap();
+    assert_eq!(cmd.file_arg(), Ok((FileOpts { enc: Some(String::from("latin1")), bin: None }, "my file")));
+    let cmd = parse("e ++bin ++enc=utf-8").unwrap();
+    assert_eq!(cmd.file_arg(), Ok((FileOpts { enc: Some(String::from("utf-8")), bin: Some(true) }, "")));
+    let pattern_arg = |line| parse(line).unwrap().pattern_arg().map(|(pattern, rest)| (pattern, rest.to_owned()));
+    let split = |pattern: &str, rest: &str| Ok((pattern.to_owned(), rest.to_owned()));
+    assert_eq!(pattern_arg("g/a\\/b/ s/x/y/"), split("a/b", "s/x/y/"));
+    assert_eq!(pattern_arg("v#x#d"), split("x", "d"));
+    assert_eq!(pattern_arg("g!/x"), split("x", ""));
+    assert!(pattern_arg("g xd").is_err() && pattern_arg("g").is_err());
+    assert_eq!(parse("g!/x/").unwrap().name, Name::Global);
+    assert_eq!(parse("norm! dd").unwrap().arg, "dd");
+
+    assert_eq!(parse("d a 3").unwrap().register_arg(), Ok((Some('a'), Some(3))));
+    assert_eq!(parse("y3").unwrap().register_arg(), Ok((None, Some(3))));
+    assert!(parse("d a b").unwrap().register_arg().is_err());
+    let cmd = parse("'a,'b>>> 2").unwrap();
+    assert_eq!((cmd.name, cmd.shift_arg()), (Name::ShiftRight, Ok((3, Some(2)))));
+    assert_eq!(parse("<").unwrap().shift_arg(), Ok((1, None)));
+    assert_eq!(parse("t.").unwrap().name, Name::Copy);
+    assert_eq!(parse("co$").unwrap().arg, "$");
+    let cmd = parse("ka").unwrap();
+    assert_eq!((cmd.name, &cmd.arg[..]), (Name::Mark, "a"));
+    assert_eq!(parse("j! 3").unwrap().count, Some(3));
+    assert_eq!(parse("pu! x").unwrap().name, Name::Put);
+    assert_eq!(parse("e ++bogus x").unwrap().file_arg(), Err(Error::InvalidArgument(String::from("++bogus"))));
+
+    assert_eq!(parse("zz ts=4"), Err(Error::NotACommand(String::from("zz ts=4"))));
+    assert_eq!(parse("quiz"), Err(Error::NotACommand(String::from("quiz"))));
+    assert_eq!(parse("1,2q"), Err(Error::NoRange));
+    assert_eq!(parse("set!"), Err(Error::NoBang));
+    assert_eq!(parse("3 frob"), Err(Error::NotACommand(String::from("3 frob"))));
+    assert_eq!(parse("3!"), Err(Error::TrailingCharacters(String::from("!"))));
+}
+
+#[test]
+fn parse_ranges() {
+    let range = |line| parse(line).unwrap().range;
+    assert_eq!(range("").start, None);
+    assert_eq!(range("5"), Range { start: addr(Base::Number(5), 0), end: None, from_start: false });
+    assert_eq!(range("%"), Range {
+        start: addr(Base::Number(1), 0),
+        end: addr(Base::Last, 0),
+        from_start: false,
+    });
+    assert_eq!(range(".,$-1"), Range {
+        start: addr(Base::Current, 0),
+        end: addr(Base::Last, -1),
+        from_start: false,
+    });
+    assert_eq!(range("+3;'a"), Range {
+        start: addr(Base::Current, 3),
+        end: addr(Base::Mark('a'), 0),
+        from_start: true,
+    });
+    assert_eq!(range("/a\\/b/+,?c"), Range {
+        start: addr(Base::Search(String::from("a/b")), 1),
+        end: addr(Base::SearchBack(String::from("c")), 0),
+        from_start: false,
+    });
+    assert_eq!(range(",5").start, addr(Base::Current, 0));
+    assert_eq!(range("1,2,3"), Range {
+        start: addr(Base::Number(2), 0),
+        end: addr(Base::Number(3), 0),
+        from_start: false,
+    });
+    assert_eq!(range(".5--").start, addr(Base::Current, 3));
+}
+
+#[test]
+fn resolve_ranges() {
+    let mut buf = Buffer::new_empty(80, 24);
+    buf.lines = ["one", "two", "three", "four", "five"].iter().map(|l| l.to_string()).collect();
+    buf.set_mark('a', (0, 3));
+    let mut options = Options::new();
+    let lines = |line: &str, buf: &Buffer| parse(line).unwrap().lines(buf, &Options::new());
+
+    assert_eq!(lines("", &buf), Ok((0, 0)));
+    assert_eq!(lines("%", &buf), Ok((0, 4)));
+    assert_eq!(lines("2,'a", &buf), Ok((1, 3)));
+    assert_eq!(lines("$,2", &buf), Ok((1, 4)));
+    assert_eq!(lines("/f/", &buf), Ok((3, 3)));
+    assert_eq!(lines("/f/;/f/", &buf), Ok((3, 4)));
+    assert_eq!(lines("?o?", &buf), Ok((3, 3)));
+    assert_eq!(lines("0", &buf), Ok((0, 0)));
+
+    assert_eq!(lines("6", &buf), Err(Error::InvalidRange));
+    assert_eq!(lines("$+1", &buf), Err(Error::InvalidRange));
+    assert_eq!(parse(".+9223372036854775807+9223372036854775807").err(), Some(Error::InvalidRange));
+    assert_eq!(lines("9223372036854775807+1", &buf), Err(Error::InvalidRange));
+    assert_eq!(lines("18446744073709551615+2", &buf), Err(Error::InvalidRange));
+    // a count runs to the end of the buffer, however big it is
+    let cmd = Command { count: Some(usize::MAX), ..parse("2d").unwrap() };
+    assert_eq!(cmd.lines(&buf, &options), Ok((1, 4)));
+    assert_eq!(lines("'b", &buf), Err(Error::MarkNotSet));
+    assert_eq!(lines("/six/", &buf), Err(Error::PatternNotFound(String::from("six"))));
+
+    // patterns are regular expressions, and follow ignorecase
+    assert_eq!(lines("/^t.*e$/", &buf), Ok((2, 2)));
+    assert_eq!(lines("/(/", &buf), Err(Error::InvalidPattern(String::from("("))));
+    assert_eq!(lines("/F/", &buf), Err(Error::PatternNotFound(String::from("F"))));
+    options.ignorecase = true;
+    assert_eq!(parse("/F/").unwrap().lines(&buf, &options), Ok((3, 3)));
+
+    // :pu and :m can go above the first line
+    assert_eq!(parse("0pu").unwrap().line(&buf, &options), Ok(0));
+    assert_eq!(parse("pu").unwrap().line(&buf, &options), Ok(1));
+    assert_eq!(address("$-1", &buf, &options), Ok(4));
+    assert_eq!(address("0", &buf, &options), Ok(0));
+    assert!(address("", &buf, &options).is_err() && address("2x", &buf, &options).is_err());
+}
@@ -0,0 +1,694 @@
+extern crate encoding_rs;
+
+pub use self::encoding_rs::Encoding;
+use self::encoding_rs::{UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+use std::env;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str;
+
+// How lines end in a file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileFormat {
+    Unix,
+    Dos,
+    Mac,
+}
+
+impl FileFormat {
+    pub fn parse(name: &str) -> Option<FileFormat> {
+        match name {
+            "unix" => Some(FileFormat::Unix),
+            "dos" => Some(FileFormat::Dos),
+            "mac" => Some(FileFormat::Mac),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FileFormat::Unix => "unix",
+            FileFormat::Dos => "dos",
+            FileFormat::Mac => "mac",
+        }
+    }
+
+    pub fn ending(self) -> &'static str {
+        match self {
+            FileFormat::Unix => "\n",
+            FileFormat::Dos => "\r\n",
+            FileFormat::Mac => "\r",
+        }
+    }
+}
+
+// What a file looked like apart from its lines, so it can be written back
+// the same way.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Format {
+    pub fileformat: FileFormat,
+    // whether the last line ended with a line break
+    pub eol: bool,
+    // whether the file started with a byte order mark
+    pub bomb: bool,
+    // what the file's bytes are in; lines are always UTF-8 once read
+    pub encoding: &'static Encoding,
+    // lines end at a line feed and that's all, with no byte order mark
+    pub binary: bool,
+    // the file was read in byte for byte, each byte as the character with
+    // that code, so it's written back that way whatever `binary` says now
+    pub raw: bool,
+    // the lines are a hex dump of the file rather than its text
+    pub hex: bool,
+}
+
+// Looks up an encoding by any of its usual names, like `latin1`.
+pub fn encoding(name: &str) -> Option<&'static Encoding> {
+    if name.is_empty() {
+        return Some(UTF_8);
+    }
+    Encoding::for_label(name.as_bytes())
+}
+
+// The name `fileencoding` shows for an encoding.
+pub fn encoding_name(encoding: &'static Encoding) -> String {
+    encoding.name().to_lowercase()
+}
+
+fn bom(encoding: &'static Encoding) -> &'static [u8] {
+    if encoding == UTF_8 {
+        b"\xef\xbb\xbf"
+    } else if encoding == UTF_16LE {
+        b"\xff\xfe"
+    } else if encoding == UTF_16BE {
+        b"\xfe\xff"
+    } else {
+        b""
+    }
+}
+
+impl Format {
+    pub fn new() -> Format {
+        Format {
+            fileformat: FileFormat::Unix,
+            eol: true,
+            bomb: false,
+            encoding: UTF_8,
+            binary: false,
+            raw: false,
+            hex: false,
+        }
+    }
+
+    // Splits a file into lines, working out what it's encoded in and how
+    // its lines end. Unless `encoding` says otherwise, a byte order mark
+    // decides the encoding, then UTF-8 if the file is valid UTF-8 and
+    // Latin-1 if it isn't. A file is dos if every line feed in it comes
+    // after a carriage return, and mac if it has carriage returns but no
+    // line feeds at all. Also returns whether any bytes couldn't be
+    // decoded and were replaced.
+    pub fn read<R: Read>(mut r: R, encoding: Option<&'static Encoding>, binary: bool)
+        -> io::Result<(Vec<String>, Format, bool)>
+    {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        Ok(Format::decode(&bytes, encoding, binary))
+    }
+
+    fn decode(bytes: &[u8], encoding: Option<&'static Encoding>, binary: bool) -> (Vec<String>, Format, bool) {
+        let mut format = Format::new();
+        if binary {
+            format.binary = true;
+            format.raw = true;
+            let text: String = bytes.iter().map(|&b| b as char).collect();
+            let (lines, eol) = split(&text, "\n");
+            format.eol = eol;
+            return (lines, format, false);
+        }
+        let mut raw = bytes;
+        match (Encoding::for_bom(raw), encoding) {
+            (Some((found, len)), None) => {
+                format.bomb = true;
+                format.encoding = found;
+                raw = &raw[len..];
+            }
+            (Some((found, len)), Some(forced)) if found == forced => {
+                format.bomb = true;
+                format.encoding = found;
+                raw = &raw[len..];
+            }
+            (_, Some(forced)) => format.encoding = forced,
+            (None, None) => {
+                format.encoding = if str::from_utf8(raw).is_ok() { UTF_8 } else { WINDOWS_1252 };
+            }
+        }
+        let (text, invalid) = format.encoding.decode_without_bom_handling(raw);
+
+        let feeds = text.matches('\n').count();
+        let crlfs = text.matches("\r\n").count();
+        format.fileformat = if feeds > 0 && feeds == crlfs {
+            FileFormat::Dos
+        } else if feeds == 0 && text.contains('\r') {
+            FileFormat::Mac
+        } else {
+            FileFormat::Unix
+        };
+        let (lines, eol) = split(&text, format.fileformat.ending());
+        format.eol = eol;
+        (lines, format, invalid)
+    }
+
+    // What `:w` and opening a file mention about it, like ` [dos] [noeol]`.
+    pub fn describe(&self) -> String {
+        let mut tags = String::new();
+        if self.binary {
+            tags.push_str(" [binary]");
+        } else if self.encoding != UTF_8 {
+            tags.push_str(&format!(" [{}]", encoding_name(self.encoding)));
+        }
+        if self.fileformat != FileFormat::Unix {
+            tags.push_str(&format!(" [{}]", self.fileformat.name()));
+        }
+        if !self.eol {
+            tags.push_str(" [noeol]");
+        }
+        if self.bomb {
+            tags.push_str(" [BOM]");
+        }
+        tags
+    }
+
+    // Writes `lines` as they'd be in the file, where `first` and `last` say
+    // whether they start and end it. Returns the number of bytes written,
+    // or an error if a character has no place in the file's encoding.
+    pub fn write<'a, W, I>(&self, w: &mut W, lines: I, first: bool, last: bool) -> io::Result<usize>
+        where W: Write, I: Iterator<Item=&'a String>
+    {
+        if self.hex {
+            let bytes = from_hex(lines)?;
+            w.write_all(&bytes)?;
+            return Ok(bytes.len());
+        }
+        let mut bytes = 0;
+        if first && self.bomb && !self.binary {
+            let bom = bom(self.encoding);
+            w.write_all(bom)?;
+            bytes += bom.len();
+        }
+        let ending = if self.binary { "\n" } else { self.fileformat.ending() };
+        let mut lines = lines.peekable();
+        let mut text = String::new();
+        while let Some(line) = lines.next() {
+            text.clear();
+            text.push_str(line);
+            if self.eol || !last || lines.peek().is_some() {
+                text.push_str(ending);
+            }
+            bytes += self.encode(w, &text)?;
+        }
+        Ok(bytes)
+    }
+
+    fn encode<W: Write>(&self, w: &mut W, text: &str) -> io::Result<usize> {
+        if self.raw {
+            let mut bytes = Vec::with_capacity(text.len());
+            for c in text.chars() {
+                if c > '\u{ff}' {
+                    let msg = format!("{:?} doesn't fit in a byte", c);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+                }
+                bytes.push(c as u8);
+            }
+            w.write_all(&bytes)?;
+            return Ok(bytes.len());
+        }
+        if self.encoding == UTF_8 {
+            w.write_all(text.as_bytes())?;
+            return Ok(text.len());
+        }
+        // encoding_rs only decodes UTF-16, so that's done here
+        if self.encoding == UTF_16LE || self.encoding == UTF_16BE {
+            let mut bytes = 0;
+            for unit in text.encode_utf16() {
+                let pair = if self.encoding == UTF_16LE { unit.to_le_bytes() } else { unit.to_be_bytes() };
+                w.write_all(&pair)?;
+                bytes += 2;
+            }
+            return Ok(bytes);
+        }
+        let (encoded, _, unmappable) = self.encoding.encode(text);
+        if unmappable {
+            let msg = format!("conversion to {} failed", encoding_name(self.encoding));
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        w.write_all(&encoded)?;
+        Ok(encoded.len())
+    }
+
+    // Turns the lines into a hex dump of the file they'd make, or a hex
+    // dump back into lines, returning the new lines.
+    pub fn toggle_hex<'a, I>(&mut self, lines: I) -> io::Result<Vec<String>>
+        where I: Iterator<Item=&'a String>
+    {
+        if self.hex {
+            let bytes = from_hex(lines)?;
+            let encoding = if self.bomb { None } else { Some(self.encoding) };
+            let (lines, mut format, _) = Format::decode(&bytes, encoding, self.raw);
+            format.binary = self.binary;
+            *self = format;
+            Ok(lines)
+        } else {
+            let mut bytes = Vec::new();
+            self.write(&mut bytes, lines, true, true)?;
+            self.hex = true;
+            Ok(to_hex(&bytes))
+        }
+    }
+}
+
+// Splits text into lines on `ending`, returning whether the last line had
+// one: if so it leaves an empty piece after it.
+fn split(text: &str, ending: &str) -> (Vec<String>, bool) {
+    let mut lines: Vec<String> = text.split(ending).map(String::from).collect();
+    match lines.last().map(|l| l.is_empty()) {
+        Some(true) => {
+            lines.pop();
+            (lines, true)
+        }
+        _ => (lines, false),
+    }
+}
+
+// How many bytes each line of a hex dump shows.
+const HEX_WIDTH: usize = 16;
+
+// Dumps bytes the way `xxd` does: the offset, then sixteen bytes in pairs,
+// then those bytes as text with anything unprintable as a dot.
+//
+//     00000000: 6865 6c6c 6f0a                           hello.
+pub fn to_hex(bytes: &[u8]) -> Vec<String> {
+    bytes.chunks(HEX_WIDTH).enumerate().map(|(i, chunk)| {
+        let mut line = format!("{:08x}:", i * HEX_WIDTH);
+        for (j, b) in chunk.iter().enumerate() {
+            if j % 2 == 0 {
+                line.push(' ');
+            }
+            line.push_str(&format!("{:02x}", b));
+        }
+        // line the text up with that of a full row
+        let full = 10 + HEX_WIDTH * 2 + HEX_WIDTH / 2 - 1;
+        while line.len() < full + 2 {
+            line.push(' ');
+        }
+        line.extend(chunk.iter().map(|&b| if (b' '..0x7f).contains(&b) { b as char } else { '.' }));
+        line
+    }).collect()
+}
+
+// Reads a hex dump back into bytes. Only the hex digits between the colon
+// and the text at the end count, and the offsets aren't checked, so bytes
+// can be added or taken out of a line without fixing up the rest. A line
+// with no offset at all isn't part of a dump though.
+pub fn from_hex<'a, I>(lines: I) -> io::Result<Vec<u8>>
+    where I: Iterator<Item=&'a String>
+{
+    let mut bytes = Vec::new();
+    for line in lines {
+        if line.is_empty() {
+            continue;
+        }
+        let bad = || io::Error::new(io::ErrorKind::InvalidData, format!("not a hex dump: {}", line));
+        let mut parts = line.splitn(2, ':');
+        let offset = parts.next().unwrap_or("");
+        if offset.is_empty() || !offset.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(bad());
+        }
+        let hex = parts.next().ok_or_else(bad)?;
+        let hex = hex.trim_start();
+        let hex = &hex[..hex.find("  ").unwrap_or(hex.len())];
+        let digits: Vec<u8> = hex.bytes().filter(|&b| b != b' ').collect();
+        if !digits.len().is_multiple_of(2) {
+            return Err(bad());
+        }
+        for pair in digits.chunks(2) {
+            let pair = str::from_utf8(pair).map_err(|_| bad())?;
+            bytes.push(u8::from_str_radix(pair, 16).map_err(|_| bad())?);
+        }
+    }
+    Ok(bytes)
+}
+
+// Saves `path` by having `write` fill in a new file beside it, then renaming
+// that over the old one, so a full disk or a crash part way through leaves
+// the old file as it was rather than cut short. The new file gets the old
+// one's permissions and owner, and a symlink is followed so it's the file
+// it points at that gets replaced, not the link. Returns what `write` did.
+//
+// When renaming can't be done, because the directory won't take a new file
+// or the old one has other links or an owner we can't give the new one,
+// it's an error unless `force` says to write over the old file in place.
+pub fn save<F>(path: &Path, force: bool, write: F) -> io::Result<usize>
+    where F: FnOnce(&mut BufWriter<File>) -> io::Result<usize>
+{
+    let target = resolve(path);
+    let meta = fs::metadata(&target).ok();
+    let mut temp = temp_path(&target);
+    let mut beside = true;
+    // the new text is no more readable than the old while it's written
+    let mode = meta.as_ref().map_or(0o666, mode);
+    let file = match create(&temp, mode) {
+        Ok(file) => file,
+        // a directory we can't create files in may still hold a file we
+        // can write to, so write the new text somewhere else first
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied && meta.is_some() => {
+            if !force {
+                return Err(unsafe_save("can't create a file beside it"));
+            }
+            temp = temp_path(&env::temp_dir().join(target.file_name().unwrap_or_default()));
+            beside = false;
+            create(&temp, mode)?
+        }
+        Err(e) => return Err(e),
+    };
+    let bytes = match fill(file, write) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+    };
+    let replaced = match meta {
+        Some(ref meta) if beside && links(meta) == 1 && keep_owner(&temp, meta) => {
+            fs::set_permissions(&temp, meta.permissions()).and_then(|_| fs::rename(&temp, &target))
+        }
+        None => fs::rename(&temp, &target),
+        // renaming would split a hard link in two or hand the file over
+        // to us
+        Some(_) if !force => Err(unsafe_save("it has other links or an owner we can't keep")),
+        Some(_) => {
+            // if this goes wrong the new text is still safe in `temp`
+            return match overwrite(&temp, &target) {
+                Ok(()) => {
+                    let _ = fs::remove_file(&temp);
+                    Ok(bytes)
+                }
+                Err(e) => Err(io::Error::new(e.kind(), format!("{}, new text kept in {}", e, temp.display()))),
+            };
+        }
+    };
+    match replaced {
+        Ok(()) => {
+            sync_dir(&target);
+            Ok(bytes)
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+fn unsafe_save(why: &str) -> io::Error {
+    io::Error::other(format!("can't be replaced safely, {} (add ! to override)", why))
+}
+
+// Copies `temp` over `target` in place, which keeps its links and owner.
+// It's only cut down to size once everything's been written, so whatever
+// happens it doesn't end up shorter than both the old text and the new.
+fn overwrite(temp: &Path, target: &Path) -> io::Result<()> {
+    let mut from = File::open(temp)?;
+    let mut to = OpenOptions::new().write(true).open(target)?;
+    let len = io::copy(&mut from, &mut to)?;
+    to.set_len(len)?;
+    to.sync_all()
+}
+
+// Runs `write` and makes sure what it wrote has reached the disk.
+fn fill<F>(file: File, write: F) -> io::Result<usize>
+    where F: FnOnce(&mut BufWriter<File>) -> io::Result<usize>
+{
+    let mut writer = BufWriter::new(file);
+    let bytes = write(&mut writer)?;
+    writer.flush()?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    Ok(bytes)
+}
+
+// Follows a symlink to the file it points at.
+fn resolve(path: &Path) -> PathBuf {
+    match fs::symlink_metadata(path) {
+        Ok(ref meta) if meta.file_type().is_symlink() => {
+            fs::canonicalize(path).or_else(|_| {
+                // a dangling link: create what it points at
+                fs::read_link(path).map(|link| path.with_file_name(link))
+            }).unwrap_or_else(|_| path.to_path_buf())
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+// A name beside `path` that nothing else should be using.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+    path.with_file_name(format!(".{}.{}.tmp", name, process::id()))
+}
+
+// Makes sure the rename itself survives a crash.
+fn sync_dir(path: &Path) {
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+}
+
+// Creates `path` to write to, with permissions `mode` from the start. It
+// mustn't be there already, not even as a symlink.
+#[cfg(unix)]
+pub fn create(path: &Path, mode: u32) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().write(true).create_new(true).mode(mode).open(path)
+}
+
+#[cfg(not(unix))]
+pub fn create(path: &Path, _: u32) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+// The permission bits of a file, for `create`.
+#[cfg(unix)]
+pub fn mode(meta: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+pub fn mode(_: &Metadata) -> u32 {
+    0o666
+}
+
+#[cfg(unix)]
+fn links(meta: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink()
+}
+
+#[cfg(not(unix))]
+fn links(_: &Metadata) -> u64 {
+    1
+}
+
+// Gives `path` the owner in `meta`, returning whether it has it now.
+#[cfg(unix)]
+fn keep_owner(path: &Path, meta: &Metadata) -> bool {
+    use std::os::unix::fs::{chown, MetadataExt};
+    match fs::metadata(path) {
+        Ok(ref now) if now.uid() == meta.uid() && now.gid() == meta.gid() => true,
+        _ => chown(path, Some(meta.uid()), Some(meta.gid())).is_ok(),
+    }
+}
+
+#[cfg(not(unix))]
+fn keep_owner(_: &Path, _: &Metadata) -> bool {
+    true
+}
+
+#[test]
+fn round_trip_formats() {
+    let files: &[&[u8]] = &[
+        b"one\ntwo\n",
+        b"one\r\ntwo\r\n",
+        b"one\rtwo\r",
+        b"one\ntwo",
+        b"\xef\xbb\xbfone\r\ntwo",
+        b"",
+        b"\n",
+        // a stray carriage return in a unix file is part of its line
+        b"one\r\ntwo\n",
+    ];
+    for &file in files {
+        let (lines, format, _) = Format::read(file, None, false).unwrap();
+        let mut out = Vec::new();
+        let bytes = format.write(&mut out, lines.iter(), true, true).unwrap();
+        assert_eq!(out, file);
+        assert_eq!(bytes, file.len());
+    }
+
+    let (lines, format, _) = Format::read(&b"\xef\xbb\xbfa\r\nb"[..], None, false).unwrap();
+    assert_eq!(lines, vec!["a", "b"]);
+    assert_eq!(format, Format { fileformat: FileFormat::Dos, eol: false, bomb: true, ..Format::new() });
+    assert_eq!(format.describe(), " [dos] [noeol] [BOM]");
+    let (lines, format, _) = Format::read(&b"a\r\nb\n"[..], None, false).unwrap();
+    assert_eq!(lines, vec!["a\r", "b"]);
+    assert_eq!(format.fileformat, FileFormat::Unix);
+}
+
+#[test]
+fn encodings() {
+    let files: &[&[u8]] = &[
+        // caf\u{e9} in Latin-1, UTF-16 both ways round and UTF-8
+        b"caf\xe9\n",
+        b"\xff\xfec\0a\0f\0\xe9\0\r\0\n\0",
+        b"\xfe\xff\0c\0a\0f\0\xe9\0\n",
+        b"\xef\xbb\xbfcaf\xc3\xa9\n",
+    ];
+    for &file in files {
+        let (lines, format, invalid) = Format::read(file, None, false).unwrap();
+        assert_eq!(lines, vec!["caf\u{e9}"]);
+        assert!(!invalid);
+        let mut out = Vec::new();
+        format.write(&mut out, lines.iter(), true, true).unwrap();
+        assert_eq!(out, file);
+    }
+
+    let (_, format, _) = Format::read(&b"caf\xe9"[..], None, false).unwrap();
+    assert_eq!(encoding_name(format.encoding), "windows-1252");
+    assert_eq!(format.describe(), " [windows-1252] [noeol]");
+
+    // forcing an encoding the bytes aren't in
+    let (lines, _, invalid) = Format::read(&b"caf\xe9"[..], encoding("utf-8"), false).unwrap();
+    assert_eq!(lines, vec!["caf\u{fffd}"]);
+    assert!(invalid);
+    let (lines, _, _) = Format::read(&b"caf\xc3\xa9"[..], encoding("latin1"), false).unwrap();
+    assert_eq!(lines, vec!["caf\u{c3}\u{a9}"]);
+
+    // characters Latin-1 has no room for
+    let format = Format { encoding: encoding("latin1").unwrap(), ..Format::new() };
+    let line = String::from("\u{4e2d}");
+    assert!(format.write(&mut Vec::new(), Some(&line).into_iter(), true, true).is_err());
+    assert_eq!(encoding("bogus"), None);
+}
+
+#[test]
+fn binary_and_hex() {
+    let file: &[u8] = b"\xef\xbb\xbf\0\xff\r\n\xc3\x28\nend";
+    let (lines, mut format, invalid) = Format::read(file, None, true).unwrap();
+    assert!(!invalid);
+    assert_eq!(lines, vec!["\u{ef}\u{bb}\u{bf}\u{0}\u{ff}\r", "\u{c3}(", "end"]);
+    assert_eq!(format.describe(), " [binary] [noeol]");
+    let mut out = Vec::new();
+    format.write(&mut out, lines.iter(), true, true).unwrap();
+    assert_eq!(out, file);
+
+    let dump = format.toggle_hex(lines.iter()).unwrap();
+    assert_eq!(dump, vec![
+        "00000000: efbb bf00 ff0d 0ac3 280a 656e 64         ........(.end",
+    ]);
+    let mut out = Vec::new();
+    format.write(&mut out, dump.iter(), true, true).unwrap();
+    assert_eq!(out, file);
+
+    // edits to the hex go through to the bytes
+    let edited = [String::from("00000000: 6869 0a"), String::from("00000010: 21  junk")];
+    assert_eq!(from_hex(edited.iter()).unwrap(), b"hi\n!");
+    let back = format.toggle_hex(edited.iter()).unwrap();
+    assert_eq!(back, vec!["hi", "!"]);
+    assert!(!format.hex && format.binary && format.raw);
+
+    // `:set bin` on text read as UTF-8 keeps its characters
+    let mut format = Format { binary: true, ..Format::new() };
+    let text = vec![String::from("caf\u{e9} \u{4e2d}")];
+    let mut out = Vec::new();
+    format.write(&mut out, text.iter(), true, true).unwrap();
+    assert_eq!(out, "caf\u{e9} \u{4e2d}\n".as_bytes());
+    let dump = format.toggle_hex(text.iter()).unwrap();
+    assert!(dump[0].starts_with("00000000: 6361 66c3 a920 e4b8 ad0a "));
+    assert_eq!(format.toggle_hex(dump.iter()).unwrap(), text);
+    assert!(format.binary && !format.raw);
+
+    assert!(from_hex([String::from("00000000: 6")].iter()).is_err());
+    assert!(from_hex([String::from("hello")].iter()).is_err());
+    assert!(from_hex([String::from("key: cafe babe")].iter()).is_err());
+
+    assert_eq!(to_hex(&[0; 17]), vec![
+        "00000000: 0000 0000 0000 0000 0000 0000 0000 0000  ................",
+        "00000010: 00                                       .",
+    ]);
+}
+
+#[cfg(test)]
+fn scratch(name: &str) -> PathBuf {
+    let dir = ::std::env::temp_dir().join(format!("vex-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn save_replaces_file() {
+    let dir = scratch("save");
+    let path = dir.join("a.txt");
+    fs::write(&path, "old\n").unwrap();
+    let bytes = save(&path, false, |w| w.write_all(b"new\n").map(|_| 4)).unwrap();
+    assert_eq!(bytes, 4);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+    // nothing left lying around
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+    // a write that fails leaves the old file alone
+    let failed = save(&path, false, |w| {
+        w.write_all(b"half")?;
+        Err(io::Error::other("disk full"))
+    });
+    assert_eq!(failed.unwrap_err().to_string(), "disk full");
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn save_keeps_mode_and_links() {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    let dir = scratch("links");
+    let path = dir.join("script.sh");
+    fs::write(&path, "echo hi\n").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).unwrap();
+    let link = dir.join("link");
+    symlink(&path, &link).unwrap();
+
+    save(&link, false, |w| w.write_all(b"echo bye\n").map(|_| 9)).unwrap();
+    assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "echo bye\n");
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o750);
+
+    let hard = dir.join("hard");
+    fs::hard_link(&path, &hard).unwrap();
+    // replacing a file with other links would break them
+    let failed = save(&hard, false, |w| w.write_all(b"echo again\n").map(|_| 11));
+    assert!(failed.unwrap_err().to_string().contains("add ! to override"));
+    assert_eq!(fs::read_to_string(&path).unwrap(), "echo bye\n");
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+    // but it can be forced, which writes over it in place
+    save(&hard, true, |w| w.write_all(b"echo\n").map(|_| 5)).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "echo\n");
+    assert_eq!(fs::read_to_string(&hard).unwrap(), "echo\n");
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+    fs::remove_dir_all(&dir).unwrap();
+}
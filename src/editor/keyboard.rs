@@ -1,3 +1,4 @@
+extern crate rustbox;
 
 // lifted verbatim from
 // https://github.com/gchp/rustbox/blob/master/src/keyboard.rs
@@ -25,3 +26,28 @@ pub enum Key {
     F(u32),
     Unknown(u16),
 }
+
+impl From<rustbox::Key> for Key {
+    fn from(key: rustbox::Key) -> Key {
+        match key {
+            rustbox::Key::Tab => Key::Tab,
+            rustbox::Key::Enter => Key::Enter,
+            rustbox::Key::Esc => Key::Esc,
+            rustbox::Key::Backspace => Key::Backspace,
+            rustbox::Key::Right => Key::Right,
+            rustbox::Key::Left => Key::Left,
+            rustbox::Key::Up => Key::Up,
+            rustbox::Key::Down => Key::Down,
+            rustbox::Key::Delete => Key::Delete,
+            rustbox::Key::Insert => Key::Insert,
+            rustbox::Key::Home => Key::Home,
+            rustbox::Key::End => Key::End,
+            rustbox::Key::PageUp => Key::PageUp,
+            rustbox::Key::PageDown => Key::PageDown,
+            rustbox::Key::Char(c) => Key::Char(c),
+            rustbox::Key::Ctrl(c) => Key::Ctrl(c),
+            rustbox::Key::F(n) => Key::F(n),
+            rustbox::Key::Unknown(n) => Key::Unknown(n),
+        }
+    }
+}
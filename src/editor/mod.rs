@@ -1,5 +1,8 @@
 pub use editor::state::State;
+pub use editor::options::Options;
 pub use editor::command::{Command, Span, Motion, Line, Column};
+pub use editor::swap::list as list_swaps;
+pub use editor::crash::{catch_panics, catch_signals, caught_signal, panic_message};
 
 // column, line
 type Coord = (usize, usize);
@@ -34,5 +37,17 @@ enum Direction {
 
 mod keyboard;
 mod command;
+mod ex;
 mod state;
 mod buffer;
+mod columns;
+mod motions;
+mod text;
+mod registers;
+mod undo;
+mod options;
+mod file;
+mod swap;
+mod crash;
+mod search;
+mod substitute;
@@ -0,0 +1,444 @@
+use super::Coord;
+
+use editor::columns;
+use editor::command::Find;
+use editor::text::Text;
+
+// The word, sentence and paragraph motions, done the way vi does them: by
+// walking the text a character at a time. The walk stops at the end of
+// each line too, past its last character, which counts as white space, so
+// an empty line is a word of its own.
+
+// How a step went.
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    Moved,
+    // onto the end of the line
+    EndOfLine,
+    // to the start of the next line, or the end of the one before
+    NewLine,
+    // the buffer ended
+    Stuck,
+}
+
+impl Step {
+    fn left_line(self) -> bool {
+        self == Step::EndOfLine || self == Step::NewLine
+    }
+}
+
+fn inc(lines: &Text, at: &mut Coord) -> Step {
+    let len = lines[at.1].len();
+    if at.0 < len {
+        at.0 = columns::next(&lines[at.1], at.0);
+        if at.0 < len { Step::Moved } else { Step::EndOfLine }
+    } else if at.1 + 1 < lines.len() {
+        *at = (0, at.1 + 1);
+        Step::NewLine
+    } else {
+        Step::Stuck
+    }
+}
+
+fn dec(lines: &Text, at: &mut Coord) -> Step {
+    if at.0 > 0 {
+        at.0 = columns::prev(&lines[at.1], at.0);
+        Step::Moved
+    } else if at.1 > 0 {
+        at.1 -= 1;
+        at.0 = lines[at.1].len();
+        Step::NewLine
+    } else {
+        Step::Stuck
+    }
+}
+
+// Steps that only stop at the end of a line if it's empty.
+fn incl(lines: &Text, at: &mut Coord) -> Step {
+    match inc(lines, at) {
+        Step::EndOfLine => inc(lines, at),
+        step => step,
+    }
+}
+
+fn decl(lines: &Text, at: &mut Coord) -> Step {
+    match dec(lines, at) {
+        Step::NewLine if at.0 > 0 => dec(lines, at),
+        step => step,
+    }
+}
+
+fn char_at(lines: &Text, at: Coord) -> Option<char> {
+    lines[at.1][at.0..].chars().next()
+}
+
+fn empty_line(lines: &Text, at: Coord) -> bool {
+    at.0 == 0 && lines[at.1].is_empty()
+}
+
+// 0 for white space, 1 for punctuation and 2 for letters, digits and
+// underscores. A WORD is everything that isn't white space.
+fn class(lines: &Text, at: Coord, big: bool) -> u8 {
+    match char_at(lines, at) {
+        None => 0,
+        Some(c) if c.is_whitespace() => 0,
+        Some(_) if big => 1,
+        Some(c) if c.is_alphanumeric() || c == '_' => 2,
+        Some(_) => 1,
+    }
+}
+
+// Steps over characters of `class`, returning true if the buffer ran out.
+fn skip(lines: &Text, at: &mut Coord, class_of: u8, big: bool, forward: bool) -> bool {
+    while class(lines, *at, big) == class_of {
+        let step = if forward { inc(lines, at) } else { dec(lines, at) };
+        if step == Step::Stuck {
+            return true;
+        }
+    }
+    false
+}
+
+// `w`: the start of the [count]th word on. With `eol`, as when there's an
+// operator, the last word stops at the end of its line instead, so `dw`
+// doesn't join lines.
+pub fn next_word(lines: &Text, mut at: Coord, big: bool, count: usize, eol: bool) -> Coord {
+    for n in (0..count).rev() {
+        let stop = |step: Step| step == Step::Stuck || (step.left_line() && eol && n == 0);
+        let from = class(lines, at, big);
+        let last_line = at.1 + 1 == lines.len();
+        let step = inc(lines, &mut at);
+        if step == Step::Stuck || (step.left_line() && last_line) || stop(step) {
+            return at;
+        }
+        // past the rest of this word, then the white space after it, but
+        // not past an empty line
+        if from != 0 {
+            while class(lines, at, big) == from {
+                if stop(inc(lines, &mut at)) {
+                    return at;
+                }
+            }
+        }
+        while class(lines, at, big) == 0 && !empty_line(lines, at) {
+            if stop(inc(lines, &mut at)) {
+                return at;
+            }
+        }
+    }
+    at
+}
+
+// `b`: the start of the [count]th word back.
+pub fn prev_word(lines: &Text, mut at: Coord, big: bool, count: usize) -> Coord {
+    'words: for _ in 0..count {
+        if dec(lines, &mut at) == Step::Stuck {
+            return at;
+        }
+        while class(lines, at, big) == 0 {
+            if empty_line(lines, at) {
+                continue 'words;
+            }
+            if dec(lines, &mut at) == Step::Stuck {
+                return at;
+            }
+        }
+        let word = class(lines, at, big);
+        if skip(lines, &mut at, word, big, false) {
+            return at;
+        }
+        // one too far
+        inc(lines, &mut at);
+    }
+    at
+}
+
+// `e`: the end of the [count]th word on. With `stay`, as for `cw`, a
+// first word the point's at the end of already counts.
+pub fn word_end(lines: &Text, mut at: Coord, big: bool, count: usize, stay: bool) -> Coord {
+    let mut stay = stay;
+    for _ in 0..count {
+        let from = class(lines, at, big);
+        if inc(lines, &mut at) == Step::Stuck {
+            return at;
+        }
+        if from != 0 && class(lines, at, big) == from {
+            // in the middle of a word
+            if skip(lines, &mut at, from, big, true) {
+                return at;
+            }
+        } else if !stay || from == 0 {
+            // at the end of one, so on to the end of the next
+            while class(lines, at, big) == 0 {
+                if inc(lines, &mut at) == Step::Stuck {
+                    return at;
+                }
+            }
+            let word = class(lines, at, big);
+            if skip(lines, &mut at, word, big, true) {
+                return at;
+            }
+        }
+        // one too far
+        dec(lines, &mut at);
+        stay = false;
+    }
+    at
+}
+
+// `ge`: the end of the [count]th word back.
+pub fn prev_word_end(lines: &Text, mut at: Coord, big: bool, count: usize) -> Coord {
+    for _ in 0..count {
+        let from = class(lines, at, big);
+        if dec(lines, &mut at) == Step::Stuck {
+            return at;
+        }
+        if from != 0 && skip(lines, &mut at, from, big, false) {
+            return at;
+        }
+        while class(lines, at, big) == 0 && !empty_line(lines, at) {
+            if dec(lines, &mut at) == Step::Stuck {
+                return at;
+            }
+        }
+    }
+    at
+}
+
+// `}` and `{`: the [count]th empty line on or back after some text. With
+// no more of them `}` goes past the end of the last line, or if there
+// aren't enough the point stays where it is.
+pub fn paragraph(lines: &Text, at: Coord, forward: bool, count: usize) -> Coord {
+    let last = lines.len() - 1;
+    let mut l = at.1;
+    for n in (0..count).rev() {
+        let mut text = false;
+        let mut first = true;
+        loop {
+            text |= !lines[l].is_empty();
+            if !first && text && lines[l].is_empty() {
+                break;
+            }
+            first = false;
+            if (forward && l == last) || (!forward && l == 0) {
+                if n > 0 {
+                    return at;
+                }
+                break;
+            }
+            l = if forward { l + 1 } else { l - 1 };
+        }
+    }
+    if forward && l == last {
+        (lines[l].len(), l)
+    } else {
+        (0, l)
+    }
+}
+
+// `)` and `(`: the start of the [count]th sentence on or back. A sentence
+// ends with `.`, `!` or `?`, then any closing `)`, `]`, `"` or `'`, then
+// the end of the line or white space; empty lines are sentences of their
+// own.
+pub fn sentence(lines: &Text, at: Coord, forward: bool, count: usize) -> Coord {
+    let step = |pos: &mut Coord| if forward { incl(lines, pos) } else { decl(lines, pos) };
+    let closing = |c: Option<char>| c.is_some_and(|c| ")]\"'".contains(c));
+    let mut pos = at;
+    for n in (0..count).rev() {
+        let mut skip_white = true;
+        let found = if char_at(lines, pos).is_none() {
+            // on an empty line: on to one that isn't
+            while step(&mut pos) != Step::Stuck && char_at(lines, pos).is_none() {}
+            forward
+        } else {
+            if !forward {
+                decl(lines, &mut pos);
+            }
+            false
+        };
+        if !found {
+            // back over white space and the end of a sentence before it
+            let mut dot = false;
+            loop {
+                let c = match char_at(lines, pos) {
+                    Some(c) if c == ' ' || c == '\t' || ".!?)]\"'".contains(c) => c,
+                    _ => break,
+                };
+                let mut before = pos;
+                if decl(lines, &mut before) == Step::Stuck || (forward && lines[before.1].is_empty()) || dot {
+                    break;
+                }
+                dot = ".!?".contains(c);
+                if closing(Some(c)) && !char_at(lines, before).is_some_and(|b| ".!?)]\"'".contains(b)) {
+                    break;
+                }
+                decl(lines, &mut pos);
+            }
+            let start_line = pos.1;
+            // then on to the end of this sentence
+            loop {
+                let c = match char_at(lines, pos) {
+                    Some(c) => c,
+                    None => {
+                        if !forward && pos.1 != start_line {
+                            pos = (0, pos.1 + 1);
+                        }
+                        break;
+                    }
+                };
+                if c == '.' || c == '!' || c == '?' {
+                    let mut after = pos;
+                    let mut stuck = false;
+                    loop {
+                        if inc(lines, &mut after) == Step::Stuck {
+                            stuck = true;
+                            break;
+                        }
+                        if !closing(char_at(lines, after)) {
+                            break;
+                        }
+                    }
+                    let next = char_at(lines, after);
+                    if stuck || next.is_none() || next == Some(' ') || next == Some('\t') {
+                        pos = after;
+                        if char_at(lines, pos).is_none() {
+                            inc(lines, &mut pos);
+                        }
+                        break;
+                    }
+                }
+                if step(&mut pos) == Step::Stuck {
+                    if n > 0 {
+                        return at;
+                    }
+                    skip_white = false;
+                    break;
+                }
+            }
+        }
+        while skip_white && (char_at(lines, pos) == Some(' ') || char_at(lines, pos) == Some('\t')) {
+            if incl(lines, &mut pos) == Step::Stuck {
+                break;
+            }
+        }
+    }
+    pos
+}
+
+// `f`, `F`, `t` and `T`: the column of the [count]th `c` along the line,
+// or just before it. Repeated with `;`, `t` doesn't stop before the `c`
+// it's already stopped before.
+pub fn find_char(line: &str, col: usize, find: Find, count: usize, again: bool) -> Option<usize> {
+    let mut at = col;
+    let mut stop = !(again && find.till && count == 1);
+    for _ in 0..count {
+        loop {
+            if find.forward {
+                let next = columns::next(line, at);
+                if next >= line.len() {
+                    return None;
+                }
+                at = next;
+            } else {
+                if at == 0 {
+                    return None;
+                }
+                at = columns::prev(line, at);
+            }
+            if stop && line[at..].starts_with(find.c) {
+                break;
+            }
+            stop = true;
+        }
+    }
+    if find.till {
+        at = if find.forward { columns::prev(line, at) } else { columns::next(line, at) };
+    }
+    Some(at)
+}
+
+#[cfg(test)]
+fn text(lines: &[&str]) -> Text {
+    lines.iter().map(|l| l.to_string()).collect()
+}
+
+#[test]
+fn words() {
+    let t = text(&["foo.bar  baz", "", "  qux"]);
+    assert_eq!(next_word(&t, (0, 0), false, 1, false), (3, 0));
+    assert_eq!(next_word(&t, (3, 0), false, 2, false), (9, 0));
+    assert_eq!(next_word(&t, (0, 0), true, 1, false), (9, 0));
+    // an empty line is a word, and the last one ends past the last character
+    assert_eq!(next_word(&t, (9, 0), false, 1, false), (0, 1));
+    assert_eq!(next_word(&t, (0, 1), false, 1, false), (2, 2));
+    assert_eq!(next_word(&t, (2, 2), false, 1, false), (5, 2));
+    // with an operator the last word stops at the end of its line
+    assert_eq!(next_word(&t, (9, 0), false, 1, true), (12, 0));
+    assert_eq!(next_word(&t, (0, 0), false, 2, true), (4, 0));
+    assert_eq!(next_word(&t, (4, 0), false, 2, true), (12, 0));
+
+    assert_eq!(prev_word(&t, (2, 2), false, 1), (0, 1));
+    assert_eq!(prev_word(&t, (0, 1), false, 1), (9, 0));
+    assert_eq!(prev_word(&t, (5, 0), false, 2), (3, 0));
+    assert_eq!(prev_word(&t, (5, 0), true, 1), (0, 0));
+    assert_eq!(prev_word(&t, (0, 0), false, 1), (0, 0));
+
+    assert_eq!(word_end(&t, (0, 0), false, 1, false), (2, 0));
+    assert_eq!(word_end(&t, (2, 0), false, 1, false), (3, 0));
+    assert_eq!(word_end(&t, (2, 0), false, 1, true), (2, 0));
+    assert_eq!(word_end(&t, (0, 0), true, 1, false), (6, 0));
+    // empty lines are skipped, and the last word ends where it ends
+    assert_eq!(word_end(&t, (11, 0), false, 1, false), (4, 2));
+    assert_eq!(word_end(&t, (4, 2), false, 1, false), (5, 2));
+
+    assert_eq!(prev_word_end(&t, (10, 0), false, 1), (6, 0));
+    assert_eq!(prev_word_end(&t, (6, 0), false, 2), (2, 0));
+    assert_eq!(prev_word_end(&t, (3, 2), false, 1), (0, 1));
+    assert_eq!(prev_word_end(&t, (3, 2), true, 2), (11, 0));
+}
+
+#[test]
+fn paragraphs() {
+    let t = text(&["a", "b", "", "c", "", "", "d"]);
+    assert_eq!(paragraph(&t, (0, 0), true, 1), (0, 2));
+    assert_eq!(paragraph(&t, (0, 2), true, 1), (0, 4));
+    assert_eq!(paragraph(&t, (0, 0), true, 3), (1, 6));
+    assert_eq!(paragraph(&t, (0, 0), true, 4), (0, 0));
+    assert_eq!(paragraph(&t, (0, 6), false, 1), (0, 5));
+    assert_eq!(paragraph(&t, (0, 5), false, 1), (0, 2));
+    assert_eq!(paragraph(&t, (0, 3), false, 2), (0, 0));
+}
+
+#[test]
+fn sentences() {
+    let t = text(&["Hello there.  How are (you?) Fine", "", "Next one. And", "the last."]);
+    assert_eq!(sentence(&t, (0, 0), true, 1), (14, 0));
+    assert_eq!(sentence(&t, (14, 0), true, 1), (29, 0));
+    assert_eq!(sentence(&t, (29, 0), true, 1), (0, 1));
+    assert_eq!(sentence(&t, (0, 1), true, 1), (0, 2));
+    assert_eq!(sentence(&t, (0, 2), true, 1), (10, 2));
+    assert_eq!(sentence(&t, (0, 0), true, 2), (29, 0));
+
+    assert_eq!(sentence(&t, (29, 0), false, 1), (14, 0));
+    assert_eq!(sentence(&t, (16, 0), false, 1), (14, 0));
+    assert_eq!(sentence(&t, (14, 0), false, 1), (0, 0));
+    assert_eq!(sentence(&t, (0, 2), false, 1), (0, 1));
+    assert_eq!(sentence(&t, (4, 3), false, 1), (10, 2));
+}
+
+#[test]
+fn finds() {
+    let find = |c, forward, till| Find { c, forward, till };
+    let line = "f(a, b), (c)";
+    assert_eq!(find_char(line, 0, find(',', true, false), 1, false), Some(3));
+    assert_eq!(find_char(line, 0, find(',', true, false), 2, false), Some(7));
+    assert_eq!(find_char(line, 0, find(',', true, false), 3, false), None);
+    assert_eq!(find_char(line, 0, find(')', true, true), 1, false), Some(5));
+    assert_eq!(find_char(line, 11, find('(', false, false), 2, false), Some(1));
+    assert_eq!(find_char(line, 11, find('(', false, true), 1, false), Some(10));
+    assert_eq!(find_char(line, 0, find('f', false, false), 1, false), None);
+    // `;` after `t` goes on to the next one
+    assert_eq!(find_char(line, 2, find(',', true, true), 1, false), Some(2));
+    assert_eq!(find_char(line, 2, find(',', true, true), 1, true), Some(6));
+    assert_eq!(find_char("", 0, find('x', true, false), 1, false), None);
+}
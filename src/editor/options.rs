@@ -0,0 +1,422 @@
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use editor::columns::Layout;
+use editor::file::{self, FileFormat, Format};
+
+// Settings that apply to the whole editor.
+pub struct Options {
+    // keep undo history in a file so it survives restarts
+    pub undofile: bool,
+    // where undo files go; next to the file they're for if unset
+    pub undodir: Option<PathBuf>,
+    // how many columns a tab takes up
+    pub tabstop: usize,
+    // show tabs, trailing spaces and line ends using `listchars`
+    pub list: bool,
+    pub listchars: ListChars,
+    // soft wrap long lines, at a word boundary with linebreak, starting
+    // each continued row with showbreak
+    pub wrap: bool,
+    pub linebreak: bool,
+    pub showbreak: String,
+    // keep a copy of each buffer in a swap file to recover from if vex
+    // dies; the swap files go in `directory`, or next to their files
+    pub swapfile: bool,
+    pub directory: Option<PathBuf>,
+    // bring swap files up to date after this many keys, or when nothing's
+    // been typed for this many milliseconds
+    pub updatecount: usize,
+    pub updatetime: usize,
+    // searches ignore case, unless smartcase is on and the pattern has a
+    // capital in it, and go round the end of the buffer with wrapscan
+    pub ignorecase: bool,
+    pub smartcase: bool,
+    pub wrapscan: bool,
+    // show the match for a search while it's being typed, and highlight
+    // every match of the last one
+    pub incsearch: bool,
+    pub hlsearch: bool,
+}
+
+// What `list` draws in place of otherwise invisible characters. Anything
+// left as None is drawn as usual.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListChars {
+    // the first cell of a tab, then the rest of it
+    pub tab: Option<(char, char)>,
+    // spaces at the end of a line
+    pub trail: Option<char>,
+    // non-breaking spaces
+    pub nbsp: Option<char>,
+    // just past the end of each line
+    pub eol: Option<char>,
+}
+
+impl ListChars {
+    // Parses a `listchars` value like `tab:> ,trail:-,eol:$`.
+    pub fn parse(value: &str) -> Option<ListChars> {
+        let mut chars = ListChars { tab: None, trail: None, nbsp: None, eol: None };
+        if value.is_empty() {
+            return Some(chars);
+        }
+        for item in value.split(',') {
+            let mut parts = item.splitn(2, ':');
+            let name = parts.next().unwrap_or("");
+            let shown: Vec<char> = parts.next().unwrap_or("").chars().collect();
+            match (name, shown.len()) {
+                ("tab", 2) => chars.tab = Some((shown[0], shown[1])),
+                ("trail", 1) => chars.trail = Some(shown[0]),
+                ("nbsp", 1) => chars.nbsp = Some(shown[0]),
+                ("eol", 1) => chars.eol = Some(shown[0]),
+                _ => return None,
+            }
+        }
+        Some(chars)
+    }
+}
+
+impl fmt::Display for ListChars {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut items = Vec::new();
+        if let Some((first, rest)) = self.tab {
+            items.push(format!("tab:{}{}", first, rest));
+        }
+        if let Some(c) = self.trail {
+            items.push(format!("trail:{}", c));
+        }
+        if let Some(c) = self.nbsp {
+            items.push(format!("nbsp:{}", c));
+        }
+        if let Some(c) = self.eol {
+            items.push(format!("eol:{}", c));
+        }
+        write!(f, "{}", items.join(","))
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            undofile: false,
+            undodir: None,
+            tabstop: 8,
+            list: false,
+            listchars: ListChars::parse("tab:> ,trail:-,nbsp:+").unwrap(),
+            wrap: true,
+            linebreak: false,
+            showbreak: String::new(),
+            swapfile: true,
+            directory: None,
+            updatecount: 200,
+            updatetime: 4000,
+            ignorecase: false,
+            smartcase: false,
+            wrapscan: true,
+            incsearch: false,
+            hlsearch: false,
+        }
+    }
+
+    // How buffers lay out their lines.
+    pub fn layout(&self) -> Layout {
+        Layout {
+            tabstop: self.tabstop,
+            wrap: self.wrap,
+            linebreak: self.linebreak,
+            showbreak: self.showbreak.clone(),
+        }
+    }
+
+    // Applies the arguments to `:set`, each of which is one of `name`,
+    // `noname`, `invname`, `name!`, `name?` or `name=value`. Spaces inside
+    // a value are escaped with a backslash. Options about how the file is
+    // written go to the current buffer's `format`. Returns what `name?`
+    // asked to see, or why an argument was wrong.
+    pub fn set(&mut self, args: &str, format: &mut Format) -> Result<Option<String>, String> {
+        let mut shown = Vec::new();
+        for arg in split_args(args) {
+            if let Some(value) = self.set_one(&arg, format)? {
+                shown.push(value);
+            }
+        }
+        if shown.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(shown.join(" ")))
+        }
+    }
+
+    fn set_one(&mut self, arg: &str, format: &mut Format) -> Result<Option<String>, String> {
+        let (name, value) = match arg.find('=') {
+            Some(i) => (&arg[..i], Some(&arg[i + 1..])),
+            None => (arg, None),
+        };
+        if let Some(name) = name.strip_suffix('?') {
+            return self.show(name, format).map(Some);
+        }
+        match (name, value) {
+            ("tabstop", Some(v)) | ("ts", Some(v)) => {
+                match v.parse() {
+                    Ok(0) => return Err(format!("Argument must be positive: {}", arg)),
+                    Ok(n) => self.tabstop = n,
+                    Err(_) => return Err(format!("Number required after =: {}", arg)),
+                }
+            }
+            ("listchars", Some(v)) | ("lcs", Some(v)) => {
+                match ListChars::parse(v) {
+                    Some(chars) => self.listchars = chars,
+                    None => return Err(format!("Invalid argument: {}", arg)),
+                }
+            }
+            ("showbreak", Some(v)) | ("sbr", Some(v)) => {
+                self.showbreak = v.to_owned();
+            }
+            ("undodir", Some(v)) | ("udir", Some(v)) => {
+                self.undodir = if v.is_empty() { None } else { Some(PathBuf::from(v)) };
+            }
+            ("directory", Some(v)) | ("dir", Some(v)) => {
+                self.directory = if v.is_empty() { None } else { Some(PathBuf::from(v)) };
+            }
+            ("updatecount", Some(v)) | ("uc", Some(v)) => {
+                match v.parse() {
+                    Ok(n) => self.updatecount = n,
+                    Err(_) => return Err(format!("Number required after =: {}", arg)),
+                }
+            }
+            ("updatetime", Some(v)) | ("ut", Some(v)) => {
+                match v.parse() {
+                    Ok(0) => return Err(format!("Argument must be positive: {}", arg)),
+                    Ok(n) => self.updatetime = n,
+                    Err(_) => return Err(format!("Number required after =: {}", arg)),
+                }
+            }
+            ("fileformat", Some(v)) | ("ff", Some(v)) => {
+                match FileFormat::parse(v) {
+                    Some(ff) => format.fileformat = ff,
+                    None => return Err(format!("Invalid argument: {}", arg)),
+                }
+            }
+            ("fileencoding", Some(v)) | ("fenc", Some(v)) => {
+                match file::encoding(v) {
+                    Some(encoding) => format.encoding = encoding,
+                    None => return Err(format!("Invalid argument: {}", arg)),
+                }
+            }
+            (_, Some(_)) => {
+                match self.flag(name, format) {
+                    Some(_) => return Err(format!("Invalid argument: {}", arg)),
+                    None => return Err(format!("Unknown option: {}", name)),
+                }
+            }
+            (_, None) => {
+                if let Some(flag) = self.flag(name, format) {
+                    *flag = true;
+                    return Ok(None);
+                }
+                let invert = name.starts_with("inv") || name.ends_with('!');
+                match self.flag(strip_flag(name), format) {
+                    Some(flag) => *flag = invert && !*flag,
+                    // a string or number option on its own shows its value
+                    None => return self.show(name, format).map(Some),
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // The on/off options, by name and short name.
+    fn flag<'a>(&'a mut self, name: &str, format: &'a mut Format) -> Option<&'a mut bool> {
+        match name {
+            "undofile" | "udf" => Some(&mut self.undofile),
+            "list" => Some(&mut self.list),
+            "wrap" => Some(&mut self.wrap),
+            "linebreak" | "lbr" => Some(&mut self.linebreak),
+            "swapfile" | "swf" => Some(&mut self.swapfile),
+            "ignorecase" | "ic" => Some(&mut self.ignorecase),
+            "smartcase" | "scs" => Some(&mut self.smartcase),
+            "wrapscan" | "ws" => Some(&mut self.wrapscan),
+            "incsearch" | "is" => Some(&mut self.incsearch),
+            "hlsearch" | "hls" => Some(&mut self.hlsearch),
+            "endofline" | "eol" => Some(&mut format.eol),
+            "bomb" => Some(&mut format.bomb),
+            "binary" | "bin" => Some(&mut format.binary),
+            _ => None,
+        }
+    }
+
+    fn show(&mut self, name: &str, format: &mut Format) -> Result<String, String> {
+        let value = match name {
+            "tabstop" | "ts" => format!("tabstop={}", self.tabstop),
+            "listchars" | "lcs" => format!("listchars={}", self.listchars),
+            "showbreak" | "sbr" => format!("showbreak={}", self.showbreak),
+            "undodir" | "udir" => {
+                let dir = self.undodir.as_ref().map_or(String::new(), |d| d.to_string_lossy().into_owned());
+                format!("undodir={}", dir)
+            }
+            "directory" | "dir" => {
+                let dir = self.directory.as_ref().map_or(String::new(), |d| d.to_string_lossy().into_owned());
+                format!("directory={}", dir)
+            }
+            "updatecount" | "uc" => format!("updatecount={}", self.updatecount),
+            "updatetime" | "ut" => format!("updatetime={}", self.updatetime),
+            "fileformat" | "ff" => format!("fileformat={}", format.fileformat.name()),
+            "fileencoding" | "fenc" => format!("fileencoding={}", file::encoding_name(format.encoding)),
+            _ => {
+                match self.flag(name, format) {
+                    Some(&mut true) => String::from(name),
+                    Some(&mut false) => format!("no{}", name),
+                    None => return Err(format!("Unknown option: {}", name)),
+                }
+            }
+        };
+        Ok(value)
+    }
+
+    // The undo file for `path`: `.name.un~` beside it, or in the undodir
+    // named after its full path with the slashes turned into `%`s.
+    pub fn undo_path(&self, path: &Path) -> PathBuf {
+        keep_path(path, self.undodir.as_ref(), ".un~", "")
+    }
+
+    // The swap file for `path`: `.name.swp`, or kept the same way as undo
+    // files in `directory` but ending in `.swp`.
+    pub fn swap_path(&self, path: &Path) -> PathBuf {
+        keep_path(path, self.directory.as_ref(), ".swp", ".swp")
+    }
+}
+
+// Where to keep a file that goes along with `path`, ending in `ext` next to
+// it or `dir_ext` in `dir`.
+fn keep_path(path: &Path, dir: Option<&PathBuf>, ext: &str, dir_ext: &str) -> PathBuf {
+    match dir {
+        Some(dir) => {
+            let full = match env::current_dir() {
+                Ok(cwd) => cwd.join(path),
+                Err(_) => path.to_path_buf(),
+            };
+            dir.join(full.to_string_lossy().replace("/", "%") + dir_ext)
+        }
+        None => {
+            let name = path.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+            path.with_file_name(format!(".{}{}", name, ext))
+        }
+    }
+}
+
+// `nolist`, `invlist` and `list!` all name `list`.
+fn strip_flag(name: &str) -> &str {
+    name.strip_suffix('!')
+        .or_else(|| name.strip_prefix("no"))
+        .or_else(|| name.strip_prefix("inv"))
+        .unwrap_or(name)
+}
+
+// Splits `:set` arguments on whitespace that isn't escaped by a backslash.
+fn split_args(args: &str) -> Vec<String> {
+    let mut split = Vec::new();
+    let mut arg = String::new();
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    arg.push(next);
+                }
+            }
+            c if c.is_whitespace() => {
+                if !arg.is_empty() {
+                    split.push(arg);
+                    arg = String::new();
+                }
+            }
+            c => arg.push(c),
+        }
+    }
+    if !arg.is_empty() {
+        split.push(arg);
+    }
+    split
+}
+
+#[test]
+fn undo_paths() {
+    let mut options = Options::new();
+    assert_eq!(options.undo_path(Path::new("src/main.rs")), PathBuf::from("src/.main.rs.un~"));
+
+    options.undodir = Some(PathBuf::from("/tmp/undo"));
+    let path = options.undo_path(Path::new("/home/me/notes.txt"));
+    assert_eq!(path, PathBuf::from("/tmp/undo/%home%me%notes.txt"));
+
+    assert_eq!(options.swap_path(Path::new("src/main.rs")), PathBuf::from("src/.main.rs.swp"));
+    options.directory = options.undodir.clone();
+    let path = options.swap_path(Path::new("/home/me/notes.txt"));
+    assert_eq!(path, PathBuf::from("/tmp/undo/%home%me%notes.txt.swp"));
+}
+
+#[test]
+fn set_options() {
+    let mut options = Options::new();
+    let mut format = Format::new();
+    assert_eq!(options.set("ts=4 list", &mut format), Ok(None));
+    assert_eq!(options.tabstop, 4);
+    assert!(options.list);
+    assert_eq!(options.set("nolist", &mut format), Ok(None));
+    assert!(!options.list);
+    assert_eq!(options.set("list!", &mut format), Ok(None));
+    assert!(options.list);
+    assert_eq!(options.set("invlist", &mut format), Ok(None));
+    assert!(!options.list);
+
+    assert_eq!(options.set("tabstop list?", &mut format), Ok(Some(String::from("tabstop=4 nolist"))));
+    assert_eq!(options.set("undofile?", &mut format), Ok(Some(String::from("noundofile"))));
+
+    assert!(options.set("ts=0", &mut format).is_err());
+    assert!(options.set("ts=x", &mut format).is_err());
+    assert!(options.set("list=1", &mut format).is_err());
+
+    assert_eq!(options.set("nowrap lbr sbr=>>\\ ", &mut format), Ok(None));
+    assert!(!options.layout().wrap);
+    assert!(options.layout().linebreak);
+    assert_eq!(options.layout().showbreak, ">> ");
+    assert_eq!(options.set("bogus", &mut format), Err(String::from("Unknown option: bogus")));
+}
+
+#[test]
+fn set_listchars() {
+    let mut options = Options::new();
+    let mut format = Format::new();
+    assert_eq!(options.set("lcs=tab:>\\ ,eol:$", &mut format), Ok(None));
+    assert_eq!(options.listchars.tab, Some(('>', ' ')));
+    assert_eq!(options.listchars.trail, None);
+    assert_eq!(options.listchars.eol, Some('$'));
+    assert_eq!(options.set("listchars?", &mut format), Ok(Some(String::from("listchars=tab:> ,eol:$"))));
+
+    assert!(options.set("lcs=tab:>", &mut format).is_err());
+    assert!(options.set("lcs=bogus:x", &mut format).is_err());
+}
+
+#[test]
+fn set_file_format() {
+    let mut options = Options::new();
+    let mut format = Format::new();
+    assert_eq!(options.set("ff=dos noeol", &mut format), Ok(None));
+    assert_eq!(format.fileformat, FileFormat::Dos);
+    assert!(!format.eol);
+    assert_eq!(options.set("ff? eol? bomb?", &mut format), Ok(Some(String::from("fileformat=dos noeol nobomb"))));
+    assert_eq!(options.set("ff=amiga", &mut format), Err(String::from("Invalid argument: ff=amiga")));
+
+    assert_eq!(options.set("fenc=latin1", &mut format), Ok(None));
+    assert_eq!(options.set("fenc?", &mut format), Ok(Some(String::from("fileencoding=windows-1252"))));
+    assert!(options.set("fenc=klingon", &mut format).is_err());
+
+    assert_eq!(options.set("bin", &mut format), Ok(None));
+    assert!(format.binary);
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+// What a register holds. Characterwise text may still span lines, in
+// which case it contains '\n's.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Register {
+    Chars(String),
+    Lines(Vec<String>),
+}
+
+impl Register {
+    fn append(self, other: Register) -> Register {
+        match (self, other) {
+            (Register::Chars(mut a), Register::Chars(b)) => {
+                a.push_str(&b);
+                Register::Chars(a)
+            }
+            // once either side is linewise the whole thing is
+            (a, b) => {
+                let mut lines = a.into_lines();
+                lines.extend(b.into_lines());
+                Register::Lines(lines)
+            }
+        }
+    }
+
+    fn into_lines(self) -> Vec<String> {
+        match self {
+            Register::Chars(text) => text.split('\n').map(|l| l.to_owned()).collect(),
+            Register::Lines(lines) => lines,
+        }
+    }
+
+    fn is_small(&self) -> bool {
+        match *self {
+            Register::Chars(ref text) => !text.contains('\n'),
+            Register::Lines(_) => false,
+        }
+    }
+}
+
+// The registers shared by every buffer:
+//
+// * `"` the unnamed register, which is whichever register was last written
+// * `a`-`z` named registers; writing to `A`-`Z` appends to them instead
+// * `0` the last yank
+// * `1`-`9` the last nine deletes of a line or more, most recent first
+// * `-` the last delete within a line
+pub struct Registers {
+    registers: HashMap<char, Register>,
+    unnamed: Option<char>,
+}
+
+impl Registers {
+    pub fn new() -> Registers {
+        Registers {
+            registers: HashMap::new(),
+            unnamed: None,
+        }
+    }
+
+    pub fn get(&self, name: char) -> Option<&Register> {
+        let name = match name {
+            '"' => self.unnamed?,
+            c => c.to_ascii_lowercase(),
+        };
+        self.registers.get(&name)
+    }
+
+    pub fn yank(&mut self, name: Option<char>, text: Register) {
+        match name {
+            None | Some('"') => self.set('0', text),
+            Some(name) => self.set(name, text),
+        }
+    }
+
+    pub fn delete(&mut self, name: Option<char>, text: Register) {
+        match name {
+            None | Some('"') => {
+                if text.is_small() {
+                    self.set('-', text);
+                } else {
+                    self.shift_numbered();
+                    self.set('1', text);
+                }
+            }
+            Some(name) => self.set(name, text),
+        }
+    }
+
+    fn set(&mut self, name: char, text: Register) {
+        let text = if name.is_ascii_uppercase() {
+            let name = name.to_ascii_lowercase();
+            match self.registers.remove(&name) {
+                Some(prev) => prev.append(text),
+                None => text,
+            }
+        } else {
+            text
+        };
+        let name = name.to_ascii_lowercase();
+        self.registers.insert(name, text);
+        self.unnamed = Some(name);
+    }
+
+    // "1 becomes "2 and so on, with "9 falling off the end.
+    fn shift_numbered(&mut self) {
+        for n in (1..9).rev() {
+            let from = ::std::char::from_digit(n, 10).unwrap();
+            let to = ::std::char::from_digit(n + 1, 10).unwrap();
+            if let Some(text) = self.registers.remove(&from) {
+                self.registers.insert(to, text);
+            }
+        }
+    }
+}
+
+#[test]
+fn unnamed_follows_last_write() {
+    let mut regs = Registers::new();
+    assert_eq!(regs.get('"'), None);
+
+    regs.yank(None, Register::Chars("hello".to_owned()));
+    assert_eq!(regs.get('"'), Some(&Register::Chars("hello".to_owned())));
+    assert_eq!(regs.get('0'), Some(&Register::Chars("hello".to_owned())));
+
+    regs.delete(None, Register::Chars("x".to_owned()));
+    assert_eq!(regs.get('"'), Some(&Register::Chars("x".to_owned())));
+    assert_eq!(regs.get('-'), Some(&Register::Chars("x".to_owned())));
+    // yanks aren't clobbered by deletes
+    assert_eq!(regs.get('0'), Some(&Register::Chars("hello".to_owned())));
+}
+
+#[test]
+fn numbered_deletes_shift() {
+    let mut regs = Registers::new();
+    for i in 0..10 {
+        regs.delete(None, Register::Lines(vec![i.to_string()]));
+    }
+    assert_eq!(regs.get('1'), Some(&Register::Lines(vec!["9".to_owned()])));
+    assert_eq!(regs.get('9'), Some(&Register::Lines(vec!["1".to_owned()])));
+}
+
+#[test]
+fn uppercase_appends() {
+    let mut regs = Registers::new();
+    regs.yank(Some('a'), Register::Chars("foo".to_owned()));
+    regs.yank(Some('A'), Register::Chars("bar".to_owned()));
+    assert_eq!(regs.get('a'), Some(&Register::Chars("foobar".to_owned())));
+
+    regs.yank(Some('A'), Register::Lines(vec!["baz".to_owned()]));
+    let lines = vec!["foobar".to_owned(), "baz".to_owned()];
+    assert_eq!(regs.get('a'), Some(&Register::Lines(lines)));
+    assert_eq!(regs.get('A'), regs.get('a'));
+}
@@ -0,0 +1,146 @@
+extern crate regex;
+
+pub use self::regex::{Captures, Regex};
+use self::regex::RegexBuilder;
+
+// Patterns are Rust regular expressions rather than vim's dialect, so
+// `\bword\b` rather than `\<word\>`, and `(a|b)` without backslashes.
+
+// Where a search leaves the point, from what's typed after the pattern:
+// `/pat/+2` two lines below the match, `/pat/e-1` one character before its
+// end, `/pat/s+1` (or `b+1`) one after its start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Offset {
+    Lines(isize),
+    Start(isize),
+    End(isize),
+}
+
+// A search as typed after `/` or `?`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Search {
+    // empty for the last pattern searched for
+    pub pattern: String,
+    pub forward: bool,
+    pub offset: Offset,
+    // whether smartcase applies, which it doesn't for the patterns `*`
+    // and `#` make, even when `n` repeats them
+    pub smartcase: bool,
+}
+
+impl Search {
+    // Splits the pattern from the offset at the first `/` (or `?` going
+    // backwards) that isn't escaped with a backslash.
+    pub fn parse(typed: &str, forward: bool) -> Result<Search, String> {
+        let delim = if forward { '/' } else { '?' };
+        let (pattern, offset) = until(typed, delim);
+        let offset = offset.unwrap_or("");
+        let offset = parse_offset(offset).ok_or_else(|| format!("E488: Trailing characters: {}", offset))?;
+        Ok(Search { pattern, forward, offset, smartcase: true })
+    }
+
+    // Compiles the pattern the way the case options say to match it.
+    pub fn compile(&self, ignorecase: bool, smartcase: bool) -> Result<Regex, String> {
+        compile(&self.pattern, ignorecase, smartcase && self.smartcase)
+    }
+}
+
+fn parse_offset(s: &str) -> Option<Offset> {
+    let (kind, n) = match s.chars().next() {
+        Some('e') => (Offset::End(0), &s[1..]),
+        Some('s') | Some('b') => (Offset::Start(0), &s[1..]),
+        Some(_) => (Offset::Lines(0), s),
+        None => return Some(Offset::Start(0)),
+    };
+    // a bare sign is one either way, and a bare number for lines is down
+    let n = match n {
+        "" if kind == Offset::Lines(0) => return None,
+        "" => 0,
+        "+" => 1,
+        "-" => -1,
+        n if n.starts_with('+') => n[1..].parse().ok()?,
+        n if n.starts_with('-') => n.parse().ok()?,
+        n if kind == Offset::Lines(0) => n.parse().ok()?,
+        _ => return None,
+    };
+    Some(match kind {
+        Offset::Lines(_) => Offset::Lines(n),
+        Offset::Start(_) => Offset::Start(n),
+        Offset::End(_) => Offset::End(n),
+    })
+}
+
+// Compiles a pattern, ignoring case with `ignorecase` unless `smartcase`
+// is on too and the pattern has a capital letter in it.
+pub fn compile(pattern: &str, ignorecase: bool, smartcase: bool) -> Result<Regex, String> {
+    let ignore = ignorecase && !(smartcase && pattern.chars().any(char::is_uppercase));
+    RegexBuilder::new(pattern)
+        .case_insensitive(ignore)
+        .build()
+        .map_err(|_| format!("E383: Invalid search string: {}", pattern))
+}
+
+// Takes `s` up to the first `delim` that isn't escaped, returning it and
+// what comes after the `delim`, if there was one. Escaped `delim`s lose
+// their backslash; other escapes are kept for the pattern or replacement
+// to make sense of.
+pub fn until(s: &str, delim: char) -> (String, Option<&str>) {
+    let mut taken = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == delim {
+            return (taken, Some(&s[i + c.len_utf8()..]));
+        }
+        if c == '\\' {
+            match chars.next() {
+                Some((_, c)) if c == delim => taken.push(c),
+                Some((_, c)) => {
+                    taken.push('\\');
+                    taken.push(c);
+                }
+                None => taken.push('\\'),
+            }
+        } else {
+            taken.push(c);
+        }
+    }
+    (taken, None)
+}
+
+// A pattern matching `word` on its own, as `*` searches for.
+pub fn whole_word(word: &str) -> String {
+    format!(r"\b{}\b", regex::escape(word))
+}
+
+#[test]
+fn parse_searches() {
+    let search = |typed| Search::parse(typed, true).unwrap();
+    assert_eq!(search("foo"), Search { pattern: String::from("foo"), forward: true, offset: Offset::Start(0), smartcase: true });
+    assert_eq!(search("a\\/b/e").pattern, "a/b");
+    assert_eq!(search("a\\.b").pattern, "a\\.b");
+    assert_eq!(search("foo/e").offset, Offset::End(0));
+    assert_eq!(search("foo/e+1").offset, Offset::End(1));
+    assert_eq!(search("foo/s-2").offset, Offset::Start(-2));
+    assert_eq!(search("foo/b").offset, Offset::Start(0));
+    assert_eq!(search("foo/+").offset, Offset::Lines(1));
+    assert_eq!(search("foo/3").offset, Offset::Lines(3));
+    assert_eq!(search("foo/-2").offset, Offset::Lines(-2));
+    assert_eq!(search("/e").pattern, "");
+    assert!(Search::parse("foo/x", true).is_err());
+
+    let back = Search::parse("a/b?e", false).unwrap();
+    assert_eq!((&back.pattern[..], back.offset), ("a/b", Offset::End(0)));
+}
+
+#[test]
+fn case_and_words() {
+    assert!(compile("foo", true, false).unwrap().is_match("FOO"));
+    assert!(!compile("Foo", true, true).unwrap().is_match("FOO"));
+    assert!(compile("foo", true, true).unwrap().is_match("FOO"));
+    assert!(!compile("foo", false, true).unwrap().is_match("FOO"));
+    assert!(compile("(", false, false).is_err());
+
+    let re = compile(&whole_word("a.b"), false, false).unwrap();
+    assert!(re.is_match("x a.b y"));
+    assert!(!re.is_match("a.bc") && !re.is_match("axb"));
+}
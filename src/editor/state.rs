@@ -1,23 +1,41 @@
 extern crate rustbox;
+extern crate unicode_segmentation;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::io::prelude::*;
-use std::io::{BufReader, LineWriter};
-use std::fs::File;
-use std::error::Error;
+use std::io::{self, BufReader, BufWriter};
+use std::fs::{self, File, OpenOptions};
 use std::cmp;
+use std::mem;
+use std::time::Duration;
+
+use super::Coord;
 
 use editor::buffer::Buffer;
-use super::{Direction};
-use super::{Command, Span, Motion, Line, Column};
+use editor::columns::{self, Row};
+use editor::command::{Builder, BuilderResult, Column, Command, Edit, Find, Line, Motion, Operator, Span};
+use editor::crash;
+use editor::ex::{self, Name};
+use editor::file::{self, Encoding};
+use editor::options::Options;
+use editor::registers::{Register, Registers};
+use editor::search::{self, Offset, Regex, Search};
+use editor::substitute::{self, Flags, Substitute, Substitution};
+use editor::swap::Swap;
+use editor::undo::{self, Travel};
+use editor::keyboard::Key;
+use editor::motions;
 
-use self::rustbox::{Color, RustBox, Key, Event};
+use self::rustbox::{Color, RustBox, Event};
+use self::unicode_segmentation::UnicodeSegmentation;
 
 enum Mode {
     // we treat `:` as different from normal
     Colon,
     Normal,
     Insert,
+    // typing a pattern after `/` (true) or `?`
+    Search(bool),
 }
 
 pub struct State {
@@ -26,9 +44,64 @@ pub struct State {
     width: usize,
     buf_idx: usize,
     buffers: Vec<Buffer>,
-    count: String,
+    builder: Builder,
+    registers: Registers,
+    options: Options,
+    // the keys of the change being made, and of the last one made, for `.`,
+    // each without its count, which a count given to `.` replaces
+    change: Vec<Key>,
+    change_count: Option<usize>,
+    last_change: Vec<Key>,
+    last_count: Option<usize>,
     status: String,
+    // what's being typed after `:`, `/` or `?`
     colon: String,
+    // the last search, to repeat with `n`, and those typed before it,
+    // newest last, with how far back Up has gone through them
+    last_search: Option<Search>,
+    // the last search compiled, for hlsearch to draw its matches with
+    search_re: Option<Regex>,
+    search_history: Vec<String>,
+    history_pos: usize,
+    // the last `f`, `F`, `t` or `T`, to repeat with `;` and `,`
+    last_find: Option<Find>,
+    // whether hlsearch shows the last search's matches, which it stops
+    // doing after :nohlsearch until the next search
+    highlight: bool,
+    // with incsearch, where the point and window were when the search
+    // started, and the match for what's been typed so far
+    search_start: Option<(Coord, usize)>,
+    incmatch: Option<(Coord, Coord)>,
+    // the last :s's pattern, replacement and flags, for `&` and for `~` in
+    // the next one, and a :s with `c` waiting to hear about each match
+    last_substitute: Option<(String, String, Flags)>,
+    confirm: Option<Substitution>,
+    // running a :g, which can't run another
+    global: bool,
+    // swap files found when opening files, waiting to be asked about
+    prompts: Vec<SwapPrompt>,
+    // keys typed since the swap files were brought up to date
+    typed: usize,
+}
+
+// A swap file left by another vex editing the same file as a buffer.
+struct SwapPrompt {
+    buffer: usize,
+    path: PathBuf,
+    swap: Swap,
+}
+
+impl SwapPrompt {
+    fn question(&self) -> String {
+        let running = if self.swap.is_running() {
+            format!(" (process {} still running)", self.swap.pid)
+        } else {
+            String::new()
+        };
+        let delete = if self.swap.is_running() { "" } else { " (D)elete it," };
+        format!("Swap file \"{}\" already exists{}! [O]pen Read-Only, (E)dit anyway, (R)ecover,{} (Q)uit:",
+                self.path.display(), running, delete)
+    }
 }
 
 impl State {
@@ -39,41 +112,321 @@ impl State {
             height: h,
             buf_idx: 0,
             buffers: Vec::new(),
-            count: String::new(),
+            builder: Builder::new(),
+            registers: Registers::new(),
+            options: Options::new(),
+            change: Vec::new(),
+            change_count: None,
+            last_change: Vec::new(),
+            last_count: None,
             status: String::new(),
             colon: String::new(),
+            last_search: None,
+            search_re: None,
+            search_history: Vec::new(),
+            history_pos: 0,
+            last_find: None,
+            highlight: false,
+            search_start: None,
+            incmatch: None,
+            last_substitute: None,
+            confirm: None,
+            global: false,
+            prompts: Vec::new(),
+            typed: 0,
         }
     }
     
-    pub fn open(&mut self, filename: &Path) {
+    pub fn options_mut(&mut self) -> &mut Options {
+        &mut self.options
+    }
+
+    // Opens a file in a new buffer, keeping every byte as it is if it's
+    // `binary`.
+    pub fn open(&mut self, filename: &Path, binary: bool) {
+        let buffer = self.load(filename, None, binary);
+        self.buffers.push(buffer);
+        let idx = self.buffers.len() - 1;
+        self.check_swap(idx);
+    }
+
+    // Opens a file and gets back what was in its swap file, as `vex -r`.
+    pub fn recover(&mut self, filename: &Path, binary: bool) {
+        self.open(filename, binary);
+        let idx = self.buffers.len() - 1;
+        match self.prompts.iter().position(|p| p.buffer == idx) {
+            Some(i) => {
+                let prompt = self.prompts.remove(i);
+                self.prompts.insert(0, prompt);
+                self.answer_swap(Key::Char('r'));
+            }
+            None => {
+                self.status = format!("E305: No swap file found for {}", filename.display());
+            }
+        }
+    }
+
+    // Looks for a swap file left by some other vex editing the same file,
+    // and asks what to do about it if there is one. Otherwise the buffer
+    // gets a swap file of its own.
+    fn check_swap(&mut self, idx: usize) {
+        if !self.options.swapfile || !self.buffers[idx].has_name() {
+            return;
+        }
+        let path = self.options.swap_path(&self.buffers[idx].name);
+        match Swap::read(&path) {
+            Ok(swap) => {
+                let prompt = SwapPrompt { buffer: idx, path, swap };
+                if self.prompts.is_empty() {
+                    self.status = prompt.question();
+                }
+                self.prompts.push(prompt);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => self.set_swap(idx, path),
+            Err(e) => {
+                self.status = format!("Cannot read swap file \"{}\": {}", path.display(), e);
+            }
+        }
+    }
+
+    fn answer_swap(&mut self, key: Key) {
+        let running = self.prompts[0].swap.is_running();
+        match key {
+            Key::Char('o') | Key::Char('O') | Key::Enter => {
+                self.buffers[self.prompts[0].buffer].readonly = true;
+                self.status.clear();
+            }
+            Key::Char('e') | Key::Char('E') => {
+                // leave the other swap file be and use one beside it
+                let other = self.prompts[0].path.with_extension("swo");
+                if other.exists() {
+                    self.status = format!("No swap file for this buffer: \"{}\" exists too", other.display());
+                } else {
+                    self.status.clear();
+                    let idx = self.prompts[0].buffer;
+                    self.set_swap(idx, other);
+                }
+            }
+            Key::Char('r') | Key::Char('R') => {
+                let (idx, path) = (self.prompts[0].buffer, self.prompts[0].path.clone());
+                let lines = mem::take(&mut self.prompts[0].swap.lines);
+                self.buffers[idx].recover(lines);
+                self.status = format!("Recovered from \"{}\"; write the buffer to keep what's been recovered",
+                                      path.display());
+                self.set_swap(idx, path);
+            }
+            Key::Char('d') | Key::Char('D') if !running => {
+                let path = self.prompts[0].path.clone();
+                match fs::remove_file(&path) {
+                    Ok(()) => {
+                        self.status.clear();
+                        let idx = self.prompts[0].buffer;
+                        self.set_swap(idx, path);
+                    }
+                    Err(e) => self.status = format!("Cannot delete \"{}\": {}", path.display(), e),
+                }
+            }
+            Key::Char('q') | Key::Char('Q') | Key::Esc => {
+                let idx = self.prompts[0].buffer;
+                self.close(idx);
+                for prompt in self.prompts.iter_mut() {
+                    if prompt.buffer > idx {
+                        prompt.buffer -= 1;
+                    }
+                }
+                self.status.clear();
+            }
+            _ => return,
+        }
+        self.prompts.remove(0);
+        if let Some(next) = self.prompts.first() {
+            self.status = next.question();
+        }
+    }
+
+    // Gives buffer `idx` the swap file at `path`, or says why it can't.
+    fn set_swap(&mut self, idx: usize, path: PathBuf) {
+        if let Err(e) = self.buffers[idx].set_swap(path) {
+            self.status = format!("Cannot write swap file for \"{}\": {}", self.buffers[idx].name(), e);
+        }
+    }
+
+    // Brings every buffer's swap file up to date.
+    fn sync_swaps(&mut self) {
+        self.typed = 0;
+        for buffer in self.buffers.iter_mut() {
+            if let Err(e) = buffer.write_swap() {
+                self.status = format!("Cannot write swap file for \"{}\": {}", buffer.name(), e);
+            }
+        }
+    }
+
+    // Saves every modified buffer for vex to be stopped without asking,
+    // saying where each one went. The rest have nothing to recover, so
+    // their swap files go.
+    pub fn preserve(&mut self) -> Vec<String> {
+        let mut saved = Vec::new();
+        for buffer in self.buffers.iter_mut() {
+            if !buffer.is_modified() {
+                buffer.remove_swap();
+                continue;
+            }
+            let swapped = buffer.has_swap();
+            saved.push(match buffer.preserve() {
+                Ok(ref path) if swapped => {
+                    format!("\"{}\" saved in \"{}\"; recover it with vex -r {}",
+                            buffer.name.display(), path.display(), buffer.name.display())
+                }
+                Ok(path) => format!("\"{}\" saved in \"{}\"", buffer.name(), path.display()),
+                Err(e) => format!("\"{}\" could not be saved: {}", buffer.name(), e),
+            });
+        }
+        saved
+    }
+
+    // Closes buffer `idx` and its swap file.
+    fn close(&mut self, idx: usize) {
+        self.buffers[idx].remove_swap();
+        self.buffers.remove(idx);
+        if self.buf_idx >= self.buffers.len() {
+            self.buf_idx = 0;
+        }
+    }
+
+    // Reads a file into a new buffer, decoding it from `encoding` if that's
+    // given, and says how it went on the status line.
+    fn load(&mut self, filename: &Path, encoding: Option<&'static Encoding>, binary: bool) -> Buffer {
         // assume buffers are 100% width and miss 1 line on top and bottom
         let mut buffer = Buffer::new(filename, self.width, self.height-2);
+        buffer.format.binary = binary;
         match File::open(filename) {
             Ok(f) => {
-                let reader = BufReader::new(f);
-                buffer.load_reader(reader);
+                let bytes = f.metadata().map(|m| m.len()).unwrap_or(0);
+                self.status = match buffer.load_reader(BufReader::new(f), encoding, binary) {
+                    Ok(invalid) => {
+                        format!("\"{}\"{}{} {}L, {}B", filename.display(), buffer.format.describe(),
+                                if invalid { " [CONVERSION ERROR]" } else { "" }, buffer.lines.len(), bytes)
+                    }
+                    Err(e) => format!("\"{}\" {}", filename.display(), e),
+                };
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.status = format!("\"{}\" [New]", filename.display());
             }
             Err(e) => {
-                println!("{:?}", e);
+                self.status = format!("\"{}\" {}", filename.display(), e);
             }
         }
-        self.buffers.push(buffer);
+        if self.options.undofile {
+            if let Ok(f) = File::open(self.options.undo_path(filename)) {
+                if let Err(e) = buffer.read_undo(BufReader::new(f)) {
+                    self.status = format!("Cannot read undo file: {}", e);
+                }
+            }
+        }
+        buffer.set_layout(self.options.layout());
+        buffer
     }
 
-    fn save_buffer(&self, buffer: &Buffer) {
-        match File::create(buffer.name.as_path()) {
-            Ok(file) => {
-                let mut writer = LineWriter::new(file);
-                for line in buffer.lines.iter() {
-                    writer.write(line.as_ref()).unwrap();
-                    writer.write("\n".as_ref()).unwrap();
+    // Writes lines `range` of buffer `idx`, or all of it, to the file
+    // named in `arg`, or the buffer's own file if that's empty. `>> file`
+    // appends instead. Without `force` this won't clobber some other file
+    // or write part of a buffer over its own file.
+    fn write_buffer(&mut self, idx: usize, range: Option<(usize, usize)>, arg: &str, force: bool) -> ex::Result<()> {
+        let (append, arg) = match arg.strip_prefix(">>") {
+            Some(rest) => (true, rest.trim_start()),
+            None => (false, arg),
+        };
+        let buffer = &mut self.buffers[idx];
+        // writing a buffer with no name yet gives it one
+        if !buffer.has_name() && !arg.is_empty() {
+            buffer.name = PathBuf::from(arg);
+        }
+        let path = if arg.is_empty() { buffer.name.clone() } else { PathBuf::from(arg) };
+        if path.as_os_str().is_empty() {
+            return Err(ex::Error::NoFileName);
+        }
+        let own = path == buffer.name;
+        if own && buffer.readonly && !force {
+            return Err(ex::Error::ReadOnly);
+        }
+        if buffer.undecodable && !force {
+            return Err(ex::Error::ConversionFailed);
+        }
+        let last = buffer.lines.len() - 1;
+        let (first, end) = range.unwrap_or((0, last));
+        let whole = first == 0 && end == last;
+        if !force && !append {
+            if own && !whole {
+                return Err(ex::Error::PartialWrite);
+            }
+            if !own && path.exists() {
+                return Err(ex::Error::FileExists);
+            }
+        }
+
+        let write = |w: &mut BufWriter<File>| buffer.write_lines(w, first, end);
+        let written = if append {
+            OpenOptions::new().append(true).create(true).open(&path)
+                .and_then(|f| {
+                    let mut writer = BufWriter::new(f);
+                    let bytes = write(&mut writer)?;
+                    writer.flush()?;
+                    Ok(bytes)
+                })
+        } else {
+            file::save(&path, force, write)
+        };
+        let bytes = written.map_err(|e| ex::Error::Message(format!("\"{}\" {}", path.display(), e)))?;
+        self.status = format!("\"{}\"{} {}L, {}B {}", path.display(), buffer.format.describe(),
+                              end + 1 - first, bytes, if append { "appended" } else { "written" });
+
+        if own && whole && !append {
+            buffer.set_saved();
+            // what didn't decode is gone from the file now too
+            buffer.undecodable = false;
+            if self.options.undofile {
+                let saved = file::save(&self.options.undo_path(&path), false, |w| buffer.write_undo(w).map(|_| 0));
+                if let Err(e) = saved {
+                    self.status = format!("Cannot write undo file: {}", e);
                 }
-                // status.push_str(&"file written");
             }
-            Err(_) => {
-                // status.push_str(e.description());
+        }
+        Ok(())
+    }
+
+    // Closes the active buffer, unless it has changes that would be lost.
+    fn quit(&mut self, force: bool) -> ex::Result<()> {
+        if !force && self.active().unwrap().is_modified() {
+            return Err(ex::Error::NotSaved);
+        }
+        let idx = self.buf_idx;
+        self.close(idx);
+        Ok(())
+    }
+
+    fn quit_all(&mut self, force: bool) -> ex::Result<()> {
+        if !force {
+            if let Some(buffer) = self.buffers.iter().find(|b| b.is_modified()) {
+                return Err(ex::Error::NotSavedIn(buffer.name().to_owned()));
             }
         }
+        for buffer in self.buffers.iter_mut() {
+            buffer.remove_swap();
+        }
+        self.buffers.clear();
+        self.buf_idx = 0;
+        Ok(())
+    }
+
+    // Writes every buffer with changes in it.
+    fn write_all(&mut self, force: bool) -> ex::Result<()> {
+        for i in 0..self.buffers.len() {
+            if self.buffers[i].is_modified() {
+                self.write_buffer(i, None, "", force)?;
+            }
+        }
+        Ok(())
     }
 
     fn active(&self) -> Option<&Buffer> {
@@ -92,105 +445,396 @@ impl State {
         }
     }
 
-    pub fn get_motion(&self, key: Key) -> Option<Command> {
+    fn do_cmd_key(&mut self, key: Key) {
+        // keys that switch modes only mean that when they start a command
+        if !self.builder.is_empty() {
+            return self.build_cmd(key);
+        }
+        if let Key::Char('i') | Key::Char('a') | Key::Char('o') | Key::Char('O') = key {
+            self.change = vec![key];
+            self.change_count = None;
+        }
         match key {
-            Key::Ctrl('h') | Key::Char('h') | Key::Left  => { 
-                Some(Command::goto(Span::Exclusive, Column::Left(1), Line::Current))
+            Key::Char(':') => {
+                self.mode = Mode::Colon;
+                // for incsearch on the patterns of :s, :g and :v
+                self.search_start = Some(self.active().unwrap().view());
             }
-            Key::Ctrl('j') | Key::Char('j') | Key::Down  => { 
-                Some(Command::goto(Span::Linewise, Column::Current, Line::Down(1)))
+            Key::Char('i') => {
+                self.mode = Mode::Insert;
             }
-            Key::Ctrl('p') | Key::Char('k') | Key::Up  => { 
-                Some(Command::goto(Span::Linewise, Column::Current, Line::Up(1)))
+            Key::Char('a') => {
+                self.active_mut().unwrap().right(1);
+                self.mode = Mode::Insert;
             }
-            Key::Char(' ') | Key::Char('l') | Key::Right  => { 
-                Some(Command::goto(Span::Exclusive, Column::Right(1), Line::Current))
+            Key::Char('O') => {
+                self.mode = Mode::Insert;
+                let active = self.active_mut().unwrap();
+                active.end();
+                active.newline();
             }
-            Key::Char('0')  => {
-                Some(Command::goto(Span::Exclusive, Column::Specific(0), Line::Current))
+            Key::Char('o') => {
+                self.mode = Mode::Insert;
+                let active = self.active_mut().unwrap();
+                active.end();
+                active.newline();
             }
-            Key::Char('^')  => {
-                Some(Command::goto(Span::Exclusive, Column::Begin, Line::Current))
+            _ => self.build_cmd(key)
+        }
+    }
+
+    fn build_cmd(&mut self, key: Key) {
+        if !self.builder.is_count(key) {
+            self.change.push(key);
+            self.change_count = self.builder.count();
+        }
+        let result = self.builder.handle_key(key);
+        self.do_result(result);
+    }
+
+    fn do_result(&mut self, result: BuilderResult) {
+        match result {
+            BuilderResult::Command(cmd) => {
+                let cmd = match self.find(cmd) {
+                    Ok(cmd) => cmd,
+                    Err(e) => {
+                        self.status = e;
+                        self.change.clear();
+                        return;
+                    }
+                };
+                let yanked = self.active_mut().unwrap().do_cmd(cmd.count, &cmd);
+                if let Some(text) = yanked {
+                    match cmd.operator {
+                        Some(Operator::Yank) => self.registers.yank(cmd.register, text),
+                        _ => self.registers.delete(cmd.register, text),
+                    }
+                }
+                match cmd.operator {
+                    // the change goes on until insert mode ends
+                    Some(Operator::Change) => {
+                        self.mode = Mode::Insert;
+                    }
+                    Some(Operator::Yank) | None => {
+                        self.change.clear();
+                        self.active_mut().unwrap().checkpoint();
+                    }
+                    Some(_) => {
+                        self.finish_change();
+                    }
+                }
             }
-            Key::Char('$')  => { 
-                Some(Command::goto(Span::Exclusive, Column::End, Line::Current))
+            BuilderResult::Edit { count, register, edit } => {
+                match edit {
+                    Edit::PutAfter | Edit::PutBefore => {
+                        self.do_edit(count, register, edit);
+                        self.finish_change();
+                    }
+                    _ => {
+                        self.change.clear();
+                        self.do_edit(count, register, edit);
+                    }
+                }
             }
-            Key::Char('G')  => { 
-                Some(Command::goto(Span::Linewise, Column::Current, Line::Last))
+            BuilderResult::Search(forward) => {
+                self.mode = Mode::Search(forward);
+                self.history_pos = 0;
+                self.search_start = Some(self.active().unwrap().view());
             }
-            Key::Char('+') | Key::Char('m') => { 
-                Some(Command::goto(Span::Exclusive, Column::Begin, Line::Down(1)))
+            BuilderResult::Pending => {}
+            BuilderResult::Invalid => {
+                self.change.clear();
             }
-            Key::Char('-') => { 
-                Some(Command::goto(Span::Exclusive, Column::Begin, Line::Up(1)))
+        }
+    }
+
+    // Turns a search or find into the Goto for wherever it ends up, or says
+    // why it can't. Other commands are left alone.
+    fn find(&mut self, cmd: Command) -> Result<Command, String> {
+        let mut at = self.active().unwrap().point();
+        let search = match cmd.motion {
+            Motion::Find(find) => {
+                self.last_find = Some(find);
+                return self.find_char(find, false, cmd);
             }
-            Key::Ctrl('b') => {
-                // what an awful way to do this.
-                let lines = self.active().unwrap().window().1;
-                let count = 1; // TODO: actually use [count]
-                let relative = (count * (lines - 2));
-                Some(Command::scroll(Line::Up(relative)))
+            Motion::FindAgain(reverse) => {
+                // like vi, a failed find just doesn't move
+                let mut find = self.last_find.ok_or_else(String::new)?;
+                find.forward ^= reverse;
+                return self.find_char(find, true, cmd);
             }
-            Key::Ctrl('f') => {
-                // what an awful way to do this.
-                let lines = self.active().unwrap().window().1;
-                let count = 1; // TODO: actually use [count]
-                let relative = (count * (lines - 2));
-                Some(Command::scroll(Line::Down(relative)))
+            Motion::Search(ref search) => {
+                // `//e` searches for the last pattern with a new offset
+                let mut search = search.clone();
+                if search.pattern.is_empty() {
+                    let last = self.last_search.as_ref().ok_or(NO_PREVIOUS)?;
+                    search.pattern = last.pattern.clone();
+                    search.smartcase = last.smartcase;
+                }
+                self.set_last_search(search.clone());
+                search
             }
-            Key::Ctrl('d') => {
-                // what an awful way to do this.
-                let lines = self.active().unwrap().window().1;
-                let count = 1; // TODO: actually use [count]
-                let relative = (count * (lines / 2));
-                Some(Command::scroll(Line::Down(relative)))
+            Motion::SearchAgain(reverse) => {
+                let mut search = self.last_search.clone().ok_or(NO_PREVIOUS)?;
+                search.forward ^= reverse;
+                search
             }
-            Key::Ctrl('u') => {
-                // what an awful way to do this.
-                let lines = self.active().unwrap().window().1;
-                let count = 1; // TODO: actually use [count]
-                let relative = (count * (lines / 2));
-                Some(Command::scroll(Line::Up(relative)))
+            Motion::SearchWord(forward) => {
+                // from the start of the word, so `#` doesn't find it again
+                let word = {
+                    let (word, start) = self.active().unwrap().word_at_point().ok_or("E348: No string under cursor")?;
+                    at = start;
+                    word.to_owned()
+                };
+                // smartcase is for patterns that were typed
+                let search = Search { pattern: search::whole_word(&word), forward,
+                                      offset: Offset::Start(0), smartcase: false };
+                self.set_last_search(search.clone());
+                self.search_history.push(search.pattern.clone());
+                search
             }
-            Key::Ctrl('e') => {
-                Some(Command::scroll(Line::Down(1)))
+            _ => return Ok(cmd),
+        };
+        let re = search.compile(self.options.ignorecase, self.options.smartcase)?;
+        let buffer = self.active().unwrap();
+        let mut found = None;
+        let mut wrapped = false;
+        for _ in 0..cmd.count {
+            match buffer.find(&re, at, search.forward, self.options.wrapscan) {
+                Some((start, end, wrap)) => {
+                    found = Some((start, end));
+                    wrapped |= wrap;
+                    at = start;
+                }
+                None if self.options.wrapscan => {
+                    return Err(ex::Error::PatternNotFound(search.pattern).to_string());
+                }
+                None => {
+                    let end = if search.forward { "BOTTOM" } else { "TOP" };
+                    let code = if search.forward { "E385" } else { "E384" };
+                    return Err(format!("{}: search hit {} without match for: {}", code, end, search.pattern));
+                }
             }
-            Key::Ctrl('y') => {
-                Some(Command::scroll(Line::Up(1)))
+        }
+        let (start, end) = found.unwrap();
+        let (col, l) = buffer.offset_match(start, end, search.offset);
+        let col = columns::grapheme_index(&buffer.lines[l], col);
+        let (which, total) = buffer.count_matches(&re, start, MAX_COUNT);
+        let message = match (wrapped, search.forward) {
+            (true, true) => String::from("search hit BOTTOM, continuing at TOP"),
+            (true, false) => String::from("search hit TOP, continuing at BOTTOM"),
+            (false, _) => format!("{}{}", if search.forward { '/' } else { '?' }, search.pattern),
+        };
+        self.status = format!("{} [{}/{}]", message, count(which), count(total));
+        self.highlight = true;
+        // a search is exclusive, unless it ends on the end of the match or
+        // on another line
+        let span = match search.offset {
+            Offset::Lines(_) => Span::Linewise,
+            Offset::End(_) => Span::Inclusive,
+            Offset::Start(_) => Span::Exclusive,
+        };
+        Ok(Command { span, motion: Motion::Goto(Column::Specific(col), Line::Specific(l)), ..cmd })
+    }
+
+    fn find_char(&self, find: Find, again: bool, cmd: Command) -> Result<Command, String> {
+        let buffer = self.active().unwrap();
+        let (col, l) = buffer.point();
+        let line = &buffer.lines[l];
+        let col = motions::find_char(line, col, find, cmd.count, again).ok_or_else(String::new)?;
+        let col = columns::grapheme_index(line, col);
+        // forwards the character found is part of it, backwards it isn't
+        let span = if find.forward { Span::Inclusive } else { Span::Exclusive };
+        Ok(Command { span, motion: Motion::Goto(Column::Specific(col), Line::Current), ..cmd })
+    }
+
+    // With incsearch, shows where what's been typed of a search would go.
+    fn incsearch(&mut self, forward: bool) {
+        let pattern = Search::parse(&self.colon, forward).ok().map(|search| search.pattern);
+        self.show_match(pattern, forward);
+    }
+
+    // The same for the pattern of a :s, :g or :v being typed.
+    fn colon_incsearch(&mut self) {
+        let pattern = ex::parse(&self.colon).ok().and_then(|cmd| match cmd.name {
+            Name::Substitute => Substitute::parse(&cmd.arg).ok().and_then(|sub| sub.with).map(|(pattern, _)| pattern),
+            Name::Global | Name::Vglobal => cmd.pattern_arg().ok().map(|(pattern, _)| pattern),
+            _ => None,
+        });
+        self.show_match(pattern, true);
+    }
+
+    fn show_match(&mut self, pattern: Option<String>, forward: bool) {
+        let view = match self.search_start {
+            Some(view) if self.options.incsearch => view,
+            _ => return,
+        };
+        self.incmatch = None;
+        let buffer = &mut self.buffers[self.buf_idx];
+        buffer.set_view(view);
+        let pattern = match pattern {
+            Some(ref pattern) if !pattern.is_empty() => pattern,
+            _ => return,
+        };
+        // half typed patterns often don't compile yet
+        if let Ok(re) = search::compile(pattern, self.options.ignorecase, self.options.smartcase) {
+            if let Some((start, end, _)) = buffer.find(&re, view.0, forward, self.options.wrapscan) {
+                buffer.show(start);
+                self.incmatch = Some((start, end));
             }
-            _ => None
         }
     }
 
-    fn do_cmd_key(&mut self, key: Key) {
+    // Puts the window back where it was before incsearch moved it.
+    fn end_search(&mut self) {
+        if let Some(view) = self.search_start.take() {
+            self.buffers[self.buf_idx].set_view(view);
+        }
+        self.incmatch = None;
+    }
+
+    // What hlsearch highlights, if anything.
+    fn highlight_regex(&self) -> Option<&Regex> {
+        if !self.options.hlsearch || !self.highlight {
+            return None;
+        }
+        self.search_re.as_ref()
+    }
+
+    fn set_last_search(&mut self, search: Search) {
+        self.last_search = Some(search);
+        self.compile_search();
+    }
+
+    // Compiles the last search ready for drawing, again whenever the case
+    // options change.
+    fn compile_search(&mut self) {
+        self.search_re = self.last_search.as_ref().and_then(|search| {
+            search.compile(self.options.ignorecase, self.options.smartcase).ok()
+        });
+    }
+
+    // Keys typed after `/` or `?`. Up and Down go back through the
+    // searches made before, and backing up past the start gives up.
+    fn do_search_key(&mut self, forward: bool, key: Key) {
+        self.change.push(key);
         match key {
-            Key::Char(':') => {
-                self.mode = Mode::Colon;
+            Key::Char(c) => {
+                self.colon.push(c);
+                self.incsearch(forward);
             }
-            Key::Char('i') => {
-                self.mode = Mode::Insert;
+            Key::Backspace if !self.colon.is_empty() => {
+                self.colon.pop();
+                self.incsearch(forward);
             }
-            Key::Char('a') => {
-                self.active_mut().unwrap().right(1);
-                self.mode = Mode::Insert;
+            Key::Esc | Key::Backspace => {
+                self.end_search();
+                self.colon.clear();
+                self.builder.cancel();
+                self.change.clear();
+                self.mode = Mode::Normal;
             }
-            Key::Char('O') => {
-                self.mode = Mode::Insert;
-                let mut active = self.active_mut().unwrap();
-                active.end();
-                active.newline();
+            Key::Up | Key::Down => {
+                let pos = match key {
+                    Key::Up => cmp::min(self.history_pos + 1, self.search_history.len()),
+                    _ => self.history_pos.saturating_sub(1),
+                };
+                self.history_pos = pos;
+                self.colon = match pos {
+                    0 => String::new(),
+                    n => self.search_history[self.search_history.len() - n].clone(),
+                };
+                self.incsearch(forward);
             }
-            Key::Char('o') => {
-                self.mode = Mode::Insert;
-                let mut active = self.active_mut().unwrap();
-                active.end();
-                active.newline();
+            Key::Enter => {
+                self.end_search();
+                let typed = mem::take(&mut self.colon);
+                self.mode = Mode::Normal;
+                match Search::parse(&typed, forward) {
+                    Ok(search) => {
+                        if !typed.is_empty() {
+                            self.search_history.retain(|s| *s != typed);
+                            self.search_history.push(typed);
+                        }
+                        let result = self.builder.search(search);
+                        self.do_result(result);
+                    }
+                    Err(e) => {
+                        self.builder.cancel();
+                        self.change.clear();
+                        self.status = e;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Remembers the keys of the change just made for `.` and ends its undo
+    // step.
+    fn finish_change(&mut self) {
+        self.last_change = self.change.drain(..).collect();
+        self.last_count = self.change_count;
+        self.active_mut().unwrap().checkpoint();
+    }
+
+    fn do_edit(&mut self, count: usize, register: Option<char>, edit: Edit) {
+        match edit {
+            Edit::PutAfter | Edit::PutBefore => {
+                let name = register.unwrap_or('"');
+                match self.registers.get(name).cloned() {
+                    Some(text) => {
+                        let before = edit == Edit::PutBefore;
+                        self.active_mut().unwrap().put(&text, before, count);
+                    }
+                    None => {
+                        self.status = format!("Nothing in register {}", name);
+                    }
+                }
+            }
+            Edit::Undo => {
+                if !self.active_mut().unwrap().undo(count) {
+                    self.status = String::from("Already at oldest change");
+                }
+            }
+            Edit::Redo => {
+                if !self.active_mut().unwrap().redo(count) {
+                    self.status = String::from("Already at newest change");
+                }
+            }
+            Edit::UndoLine => {
+                self.active_mut().unwrap().undo_line();
             }
-            _ => match self.get_motion(key) {
-                Some(cmd) => {
-                    self.active_mut().unwrap().do_cmd(1, &cmd);
+            Edit::Earlier => {
+                self.active_mut().unwrap().earlier(Travel::Changes(count));
+            }
+            Edit::Later => {
+                self.active_mut().unwrap().later(Travel::Changes(count));
+            }
+            Edit::WriteQuit => {
+                self.do_colon("x");
+            }
+            Edit::Quit => {
+                self.do_colon("q!");
+            }
+            Edit::Repeat => {
+                // counts don't go with changes that start insert mode
+                let counted = match self.last_change.first() {
+                    Some(&Key::Char(c)) => !"iaoO".contains(c),
+                    _ => true,
+                };
+                let count = if counted { self.change_count.or(self.last_count) } else { None };
+                let mut keys: Vec<Key> = count.map_or(String::new(), |n| n.to_string()).chars().map(Key::Char).collect();
+                keys.extend(self.last_change.iter().cloned());
+                for key in keys {
+                    self.handle_key(key);
                 }
-                None => {}
+            }
+            Edit::Substitute => {
+                self.do_colon("s");
+            }
+            Edit::SubstituteAll => {
+                self.do_colon("%s//~/&");
             }
         }
     }
@@ -199,30 +843,446 @@ impl State {
         match key {
             Key::Char(c) => {
                 self.colon.push(c);
+                self.colon_incsearch();
             }
             Key::Esc => {
+                self.end_search();
                 self.colon.clear();
                 self.mode = Mode::Normal;
             }
             Key::Enter => {
-                // TODO: actually parse the line
-                if self.colon == "q" {
-                    self.buffers.remove(self.buf_idx);
-                    if self.buf_idx >= self.buffers.len() {
-                        self.buf_idx = 0;
-                    }
-                }
+                self.end_search();
+                let line = self.colon.clone();
                 self.colon.clear();
                 self.mode = Mode::Normal;
+                self.do_colon(&line);
             }
             _ => {}
         }
     }
 
+    fn do_colon(&mut self, line: &str) {
+        if let Err(e) = ex::parse(line).and_then(|cmd| self.do_ex(cmd)) {
+            self.status = e.to_string();
+        }
+        // whatever the line changed comes undone in one go
+        if let Some(buffer) = self.active_mut() {
+            buffer.checkpoint();
+        }
+    }
+
+    fn do_ex(&mut self, cmd: ex::Command) -> ex::Result<()> {
+        match cmd.name {
+            Name::Goto => {
+                if !cmd.range.is_empty() {
+                    let active = &mut self.buffers[self.buf_idx];
+                    let (_, last) = cmd.lines(active, &self.options)?;
+                    active.goto_line(last);
+                }
+            }
+            Name::Write | Name::WriteQuit | Name::Exit => {
+                let range = if cmd.range.is_empty() {
+                    None
+                } else {
+                    Some(cmd.lines(self.active().unwrap(), &self.options)?)
+                };
+                // :x leaves a file alone that has nothing new to write
+                let idx = self.buf_idx;
+                if cmd.name != Name::Exit || self.buffers[idx].is_modified() {
+                    self.write_buffer(idx, range, &cmd.arg, cmd.bang)?;
+                }
+                if cmd.name != Name::Write {
+                    self.quit(cmd.bang)?;
+                }
+            }
+            Name::WriteAll => {
+                self.write_all(cmd.bang)?;
+            }
+            Name::Quit => {
+                self.quit(cmd.bang)?;
+            }
+            Name::QuitAll => {
+                self.quit_all(cmd.bang)?;
+            }
+            Name::WriteQuitAll => {
+                self.write_all(cmd.bang)?;
+                self.quit_all(cmd.bang)?;
+            }
+            Name::Earlier | Name::Later => {
+                let travel = Travel::parse(&cmd.arg).ok_or_else(|| ex::Error::InvalidArgument(cmd.arg.clone()))?;
+                let active = self.active_mut().unwrap();
+                if cmd.name == Name::Earlier {
+                    active.earlier(travel);
+                } else {
+                    active.later(travel);
+                }
+            }
+            Name::Set => {
+                let format = &mut self.buffers[self.buf_idx].format;
+                let hlsearch = self.options.hlsearch;
+                let shown = self.options.set(&cmd.arg, format).map_err(ex::Error::Message)?;
+                self.status = shown.unwrap_or_default();
+                // turning hlsearch on shows the matches again
+                if self.options.hlsearch && !hlsearch {
+                    self.highlight = true;
+                }
+                self.compile_search();
+                let layout = self.options.layout();
+                for buffer in self.buffers.iter_mut() {
+                    buffer.set_layout(layout.clone());
+                }
+            }
+            Name::Edit => {
+                let (opts, name) = cmd.file_arg()?;
+                let encoding = match opts.enc {
+                    Some(ref enc) => Some(file::encoding(enc).ok_or_else(|| ex::Error::InvalidArgument(enc.clone()))?),
+                    None => None,
+                };
+                let idx = self.buf_idx;
+                if !cmd.bang && self.buffers[idx].is_modified() {
+                    return Err(ex::Error::NotSaved);
+                }
+                // on its own it reads the file in again, as binary if it
+                // was before
+                let path = if name.is_empty() { self.buffers[idx].name.clone() } else { PathBuf::from(name) };
+                if path.as_os_str().is_empty() {
+                    return Err(ex::Error::NoFileName);
+                }
+                let binary = opts.bin.unwrap_or(name.is_empty() && self.buffers[idx].format.binary);
+                self.buffers[idx].remove_swap();
+                self.buffers[idx] = self.load(&path, encoding, binary);
+                self.check_swap(idx);
+            }
+            Name::NoHlsearch => {
+                self.highlight = false;
+            }
+            Name::Substitute | Name::SubstituteRepeat => {
+                let sub = Substitute::parse(&cmd.arg).map_err(ex::Error::Message)?;
+                if cmd.name == Name::SubstituteRepeat && sub.with.is_some() {
+                    return Err(ex::Error::TrailingCharacters(cmd.arg.clone()));
+                }
+                let (first, last) = ex::Command { count: sub.count, ..cmd }.lines(self.active().unwrap(), &self.options)?;
+                self.substitute(sub, first, last)?;
+            }
+            Name::Global | Name::Vglobal => {
+                if self.global {
+                    return Err(ex::Error::Message(String::from("E147: Cannot do :global recursive")));
+                }
+                let (pattern, command) = cmd.pattern_arg()?;
+                let pattern = if pattern.is_empty() {
+                    let last = self.last_search.as_ref().ok_or_else(|| ex::Error::Message(String::from(NO_PREVIOUS)))?;
+                    last.pattern.clone()
+                } else {
+                    pattern
+                };
+                let re = search::compile(&pattern, self.options.ignorecase, self.options.smartcase)
+                    .map_err(|_| ex::Error::InvalidPattern(pattern.clone()))?;
+                // the whole buffer unless it's given a range
+                let buffer = self.active().unwrap();
+                let (first, last) = if cmd.range.is_empty() {
+                    (0, buffer.lines.len() - 1)
+                } else {
+                    cmd.lines(buffer, &self.options)?
+                };
+                let matching = cmd.name == Name::Global && !cmd.bang;
+                let lines: Vec<usize> = (first..last + 1).filter(|&l| re.is_match(&buffer.lines[l]) == matching).collect();
+                if lines.is_empty() {
+                    return Err(if matching {
+                        ex::Error::PatternNotFound(pattern)
+                    } else {
+                        ex::Error::Message(format!("Pattern found in every line: {}", pattern))
+                    });
+                }
+                let forward = self.last_search.as_ref().is_none_or(|last| last.forward);
+                self.set_last_search(Search { pattern, forward, offset: Offset::Start(0), smartcase: true });
+                self.highlight = true;
+                self.global(lines, command)?;
+            }
+            Name::Delete | Name::Yank => {
+                let (register, count) = cmd.register_arg()?;
+                let (first, last) = ex::Command { count, ..cmd }.lines(self.active().unwrap(), &self.options)?;
+                if cmd.name == Name::Delete {
+                    let text = self.buffers[self.buf_idx].delete_range(first, last);
+                    self.registers.delete(register, text);
+                } else {
+                    let text = self.active().unwrap().yank_lines(first, last);
+                    self.registers.yank(register, text);
+                }
+            }
+            Name::Move | Name::Copy => {
+                let buffer = &mut self.buffers[self.buf_idx];
+                let (first, last) = cmd.lines(buffer, &self.options)?;
+                let to = ex::address(&cmd.arg, buffer, &self.options)?;
+                if cmd.name == Name::Copy {
+                    buffer.copy_lines(first, last, to);
+                } else if to > first && to <= last {
+                    return Err(ex::Error::Message(String::from("E134: Cannot move a range of lines into itself")));
+                } else {
+                    buffer.move_lines(first, last, to);
+                }
+            }
+            Name::Join => {
+                let buffer = &mut self.buffers[self.buf_idx];
+                let (first, last) = cmd.lines(buffer, &self.options)?;
+                // a single line is joined to the one after it
+                let last = if first == last { last + 1 } else { last };
+                if last < buffer.lines.len() {
+                    buffer.join_lines(first, last, cmd.bang);
+                }
+            }
+            Name::ShiftRight | Name::ShiftLeft => {
+                let (times, count) = cmd.shift_arg()?;
+                let buffer = &mut self.buffers[self.buf_idx];
+                let (first, last) = ex::Command { count, ..cmd }.lines(buffer, &self.options)?;
+                buffer.shift_range(first, last, cmd.name == Name::ShiftRight, times);
+            }
+            Name::Put => {
+                let name = match cmd.arg.chars().next() {
+                    Some(c) if cmd.arg.len() > c.len_utf8() => return Err(ex::Error::TrailingCharacters(cmd.arg.clone())),
+                    Some(c) => c,
+                    None => '"',
+                };
+                // whatever was yanked goes in as lines
+                let lines = match self.registers.get(name) {
+                    Some(Register::Lines(lines)) => lines.clone(),
+                    Some(Register::Chars(text)) => text.split('\n').map(String::from).collect(),
+                    None => return Err(ex::Error::Message(format!("Nothing in register {}", name))),
+                };
+                let buffer = &mut self.buffers[self.buf_idx];
+                let line = cmd.line(buffer, &self.options)?;
+                let after = if cmd.bang { line.saturating_sub(1) } else { line };
+                buffer.put_lines(after, lines);
+            }
+            Name::Mark => {
+                let mark = match cmd.arg.chars().next() {
+                    Some(c) if cmd.arg.len() > c.len_utf8() => return Err(ex::Error::TrailingCharacters(cmd.arg.clone())),
+                    Some(c) if c.is_ascii_lowercase() || c == '\'' || c == '`' => c,
+                    Some(_) => return Err(ex::Error::Message(String::from("E191: Argument must be a letter or forward/backward quote"))),
+                    None => return Err(ex::Error::Message(String::from("E471: Argument required"))),
+                };
+                let buffer = &mut self.buffers[self.buf_idx];
+                let (_, last) = cmd.lines(buffer, &self.options)?;
+                buffer.set_mark(mark, (0, last));
+            }
+            Name::Normal => {
+                if cmd.range.is_empty() {
+                    self.normal(&cmd.arg);
+                } else {
+                    let (first, last) = cmd.lines(self.active().unwrap(), &self.options)?;
+                    for l in first..last + 1 {
+                        // what's typed may leave fewer lines than there were
+                        if l >= self.active().unwrap().lines.len() {
+                            break;
+                        }
+                        self.active_mut().unwrap().goto_line(l);
+                        self.normal(&cmd.arg);
+                    }
+                }
+            }
+            Name::Hex => {
+                self.active_mut().unwrap().toggle_hex().map_err(|e| ex::Error::Message(e.to_string()))?;
+            }
+            Name::UndoList => {
+                let now = undo::now();
+                let leaves = self.active().unwrap().undo_list();
+                let list: Vec<String> = leaves.iter().map(|leaf| {
+                    format!("{} {} {}", leaf.seq, leaf.changes, ago(now.saturating_sub(leaf.time)))
+                }).collect();
+                self.status = if list.is_empty() {
+                    String::from("Nothing to undo")
+                } else {
+                    format!("number changes when: {}", list.join(", "))
+                };
+            }
+        }
+        Ok(())
+    }
+
+    // Runs `command` with the point on each of `lines` in turn, which are
+    // marked so they can be found wherever earlier commands moved them to,
+    // or skipped if they deleted them. It all comes undone in one step,
+    // and stops at the first error.
+    fn global(&mut self, lines: Vec<usize>, command: &str) -> ex::Result<()> {
+        let idx = self.buf_idx;
+        self.global = true;
+        self.buffers[idx].group_changes(true);
+        self.buffers[idx].mark_lines(lines);
+        let mut result = Ok(());
+        // the command may close the buffer, or open another in its place
+        while let Some(l) = self.buffers.get_mut(idx).and_then(Buffer::next_marked) {
+            self.buffers[idx].goto_line(l);
+            result = ex::parse(command).and_then(|cmd| self.do_ex(cmd));
+            if result.is_err() {
+                break;
+            }
+        }
+        if let Some(buffer) = self.buffers.get_mut(idx) {
+            buffer.mark_lines(Vec::new());
+            buffer.group_changes(false);
+        }
+        self.global = false;
+        result
+    }
+
+    // Types `keys` in normal mode, then gives up on anything they leave
+    // unfinished, as Esc would.
+    fn normal(&mut self, keys: &str) {
+        for c in keys.chars() {
+            self.handle_key(Key::Char(c));
+        }
+        match self.mode {
+            Mode::Normal => {
+                self.builder.cancel();
+                self.change.clear();
+            }
+            _ => self.handle_key(Key::Esc),
+        }
+    }
+
+    // Runs a :s over lines `first` to `last`, or starts asking about each
+    // match with `c`.
+    fn substitute(&mut self, sub: Substitute, first: usize, last: usize) -> ex::Result<()> {
+        let no_previous = || ex::Error::Message(String::from(NO_PREVIOUS));
+        let (pattern, replacement, flags) = match sub.with {
+            Some((pattern, replacement)) => {
+                // an empty pattern is the last one searched for
+                let pattern = if pattern.is_empty() {
+                    self.last_search.as_ref().ok_or_else(no_previous)?.pattern.clone()
+                } else {
+                    pattern
+                };
+                let previous = self.last_substitute.as_ref().map_or("", |last| &last.1[..]);
+                (pattern, substitute::with_previous(&replacement, previous), sub.flags)
+            }
+            None => {
+                let last = self.last_substitute.clone().ok_or_else(no_previous)?;
+                (last.0, last.1, sub.flags)
+            }
+        };
+        let flags = match self.last_substitute {
+            Some((_, _, last)) if flags.keep => flags.after(last),
+            _ => flags,
+        };
+        self.last_substitute = Some((pattern.clone(), replacement.clone(), flags));
+        let forward = self.last_search.as_ref().is_none_or(|last| last.forward);
+        self.set_last_search(Search { pattern: pattern.clone(), forward, offset: Offset::Start(0), smartcase: true });
+        self.highlight = true;
+
+        let (ignorecase, smartcase) = match flags.ignorecase {
+            Some(ignorecase) => (ignorecase, false),
+            None => (self.options.ignorecase, self.options.smartcase),
+        };
+        let re = search::compile(&pattern, ignorecase, smartcase).map_err(ex::Error::Message)?;
+        let buffer = &mut self.buffers[self.buf_idx];
+        if flags.count_only {
+            let counts: Vec<usize> = (first..last + 1)
+                .map(|l| &buffer.lines[l])
+                .map(|line| if flags.global { re.find_iter(line).count() } else { re.is_match(line) as usize })
+                .filter(|&n| n > 0)
+                .collect();
+            if counts.is_empty() {
+                return Err(ex::Error::PatternNotFound(pattern));
+            }
+            let total = counts.iter().sum();
+            self.status = format!("{} on {}", plural(total, "match", "matches"), plural(counts.len(), "line", "lines"));
+            return Ok(());
+        }
+        let mut run = Substitution::new(re, replacement, flags.global, first, last, buffer);
+        // there's no stopping to ask in the middle of a :g
+        if flags.confirm && !self.global {
+            if run.next(buffer).is_none() {
+                return Err(ex::Error::PatternNotFound(pattern));
+            }
+            self.confirm = Some(run);
+            self.confirm_next();
+            return Ok(());
+        }
+        while run.next(buffer).is_some() {
+            run.replace();
+        }
+        // :g runs it on lines that may well not have the pattern
+        if run.count == 0 && !self.global {
+            return Err(ex::Error::PatternNotFound(pattern));
+        }
+        self.end_substitute(run);
+        Ok(())
+    }
+
+    // Shows the next match a :s with `c` has come to and asks what to do
+    // with it, or finishes once there are no more.
+    fn confirm_next(&mut self) {
+        let found = {
+            let buffer = &mut self.buffers[self.buf_idx];
+            let found = self.confirm.as_mut().unwrap().next(buffer);
+            if let Some((start, _)) = found {
+                buffer.show(start);
+            }
+            found
+        };
+        match found {
+            Some(found) => {
+                self.incmatch = Some(found);
+                let with = self.last_substitute.as_ref().map_or("", |last| &last.1[..]);
+                self.status = format!("replace with {} (y/n/a/q/l)?", with);
+            }
+            None => {
+                let run = self.confirm.take().unwrap();
+                self.status.clear();
+                self.end_substitute(run);
+            }
+        }
+    }
+
+    // y replaces the match and n leaves it, a replaces it and all the rest,
+    // q stops and l stops after replacing it.
+    fn answer_substitute(&mut self, key: Key) {
+        {
+            let buffer = &mut self.buffers[self.buf_idx];
+            let run = self.confirm.as_mut().unwrap();
+            match key {
+                Key::Char('y') => {
+                    run.replace();
+                    run.show(buffer);
+                }
+                Key::Char('n') => run.skip(),
+                Key::Char('a') => {
+                    run.replace();
+                    while run.next(buffer).is_some() {
+                        run.replace();
+                    }
+                }
+                Key::Char('l') => {
+                    run.replace();
+                    run.stop(buffer);
+                }
+                Key::Char('q') | Key::Esc => run.stop(buffer),
+                _ => return,
+            }
+        }
+        self.confirm_next();
+    }
+
+    // Leaves the point on the last line changed, with everything the :s
+    // did as one undo step.
+    fn end_substitute(&mut self, run: Substitution) {
+        self.incmatch = None;
+        let buffer = &mut self.buffers[self.buf_idx];
+        if let Some(l) = run.last_changed {
+            buffer.goto_line(l);
+            buffer.checkpoint();
+        }
+        if run.count > 1 {
+            self.status = format!("{} on {}", plural(run.count, "substitution", "substitutions"),
+                                  plural(run.lines, "line", "lines"));
+        }
+    }
+
     fn do_insert_key(&mut self, key: Key) {
+        self.change.push(key);
         match key {
             Key::Esc => {
                 self.mode = Mode::Normal;
+                self.finish_change();
             }
             Key::Enter => {
                 self.active_mut().unwrap().newline();
@@ -234,30 +1294,65 @@ impl State {
         }
     }
 
+    fn handle_key(&mut self, key: Key) {
+        if !self.prompts.is_empty() {
+            return self.answer_swap(key);
+        }
+        if self.confirm.is_some() {
+            return self.answer_substitute(key);
+        }
+        self.typed += 1;
+        if self.options.updatecount > 0 && self.typed >= self.options.updatecount {
+            self.sync_swaps();
+        }
+        match self.mode {
+            Mode::Normal => {
+                self.do_cmd_key(key)
+            }
+            Mode::Colon => {
+                self.do_colon_key(key)
+            }
+            Mode::Search(forward) => {
+                self.do_search_key(forward, key)
+            }
+            Mode::Insert => {
+                self.status = format!("{:?}", key);
+                self.do_insert_key(key)
+            }
+        }
+    }
+
     pub fn edit(&mut self, rustbox: &RustBox) {
         if self.buffers.is_empty() {
-            self.buffers.push(Buffer::new_empty(self.width, self.height-2))
+            let mut buffer = Buffer::new_empty(self.width, self.height-2);
+            buffer.set_layout(self.options.layout());
+            self.buffers.push(buffer);
         }
-        while self.buffers.len() > 0 {
-            rustbox.draw(&self);
-            match rustbox.poll_event(false) {
+        // wake up every so often to see if a signal's been caught
+        let tick = Duration::from_millis(250);
+        let mut idle = Duration::from_millis(0);
+        let mut changed = true;
+        while !self.buffers.is_empty() && crash::caught_signal().is_none() {
+            if changed {
+                rustbox.draw(self);
+            }
+            changed = true;
+            match rustbox.peek_event(tick, false) {
                 Ok(Event::KeyEvent(key)) => {
-                    match self.mode {
-                        Mode::Normal => {
-                            self.do_cmd_key(key)
-                        }
-                        Mode::Colon => {
-                            self.do_colon_key(key)
-                        }
-                        Mode::Insert => {
-                            self.status = format!("{:?}", key);
-                            self.do_insert_key(key)
-                        }
-                    }
+                    idle = Duration::from_millis(0);
+                    self.handle_key(Key::from(key));
                 },
                 Ok(Event::ResizeEvent(w, h)) => {
                     self.resize(w as usize, h as usize);
                 },
+                Ok(Event::NoEvent) => {
+                    // bring swap files up to date once typing stops for a bit
+                    idle += tick;
+                    changed = self.typed > 0 && idle >= Duration::from_millis(self.options.updatetime as u64);
+                    if changed {
+                        self.sync_swaps();
+                    }
+                },
                 Err(e) => panic!("{}", e),
                 other => {
                     self.status = format!("{:?}", other);
@@ -265,14 +1360,53 @@ impl State {
             }
         }
     }
+}
 
-    fn get_count(&mut self) -> usize {
-        let cnt = match self.count.parse::<usize>() {
-            Ok(c) => c,
-            Err(_) =>   1
-        };
-        self.count.clear();
-        cnt
+const NO_PREVIOUS: &str = "E35: No previous regular expression";
+
+// how high the match counter counts before it gives up
+const MAX_COUNT: usize = 99;
+
+// A number for the match counter, which only goes so high.
+fn count(n: usize) -> String {
+    if n > MAX_COUNT {
+        format!(">{}", MAX_COUNT)
+    } else {
+        n.to_string()
+    }
+}
+
+fn plural(n: usize, one: &str, many: &str) -> String {
+    format!("{} {}", n, if n == 1 { one } else { many })
+}
+
+// The parts of a line to highlight, as where they start and end and
+// whether it's the match incsearch is showing: every match of `re`, if
+// hlsearch is showing any, and the incsearch match if it's on line `l`.
+fn highlights(line: &str, l: usize, re: Option<&Regex>, incmatch: Option<(Coord, Coord)>) -> Vec<(usize, usize, bool)> {
+    let mut spans: Vec<(usize, usize, bool)> = match re {
+        Some(re) => re.find_iter(line).filter(|m| m.end() > m.start()).map(|m| (m.start(), m.end(), false)).collect(),
+        None => Vec::new(),
+    };
+    if let Some(((start, sl), (end, _))) = incmatch {
+        if sl == l {
+            spans.retain(|&(s, e, _)| e <= start || s >= end);
+            spans.push((start, end, true));
+        }
+    }
+    spans
+}
+
+// How long ago something happened, the way `:undolist` shows it.
+fn ago(secs: u64) -> String {
+    if secs < 100 {
+        format!("{}s ago", secs)
+    } else if secs < 100 * 60 {
+        format!("{}m ago", secs / 60)
+    } else if secs < 48 * 60 * 60 {
+        format!("{}h ago", secs / 3600)
+    } else {
+        format!("{}d ago", secs / 86400)
     }
 }
 
@@ -287,18 +1421,39 @@ impl VexDisplay for rustbox::RustBox {
 
         let active = state.active().unwrap();
         let (x, y) = active.point();
-        let (w, h) = active.window();
+        let (_, h) = active.window();
 
         let offset = active.offset();
-        let other = String::from("~");
+        let tabstop = active.tabstop();
 
-        for i in 0..h {
-            let line = active.lines.get(i+offset).unwrap_or(&other);
+        let re = state.highlight_regex();
+        let mut row = 0;
+        let mut l = offset;
+        while row < h {
             // let num = format!("{:2}", i+offset);
-            let text = line.replace("\t", "    ");
             // self.print(0, i + 1, rustbox::RB_BOLD, Color::Default, Color::Default, &num);
-            let formatted = format!("{: <1$}", text, w);
-            self.print(0, i + 1, rustbox::RB_NORMAL, Color::Default, Color::Default, &formatted);
+            match active.lines.get(l) {
+                Some(line) => {
+                    let rows = active.rows(l);
+                    // like vim, a wrapped line that doesn't fit at the bottom
+                    // is left out and its rows marked with `@`
+                    if row + rows.len() > h && l > offset {
+                        for y in row..h {
+                            self.print(0, y + 1, rustbox::RB_NORMAL, Color::Blue, Color::Default, "@");
+                        }
+                        break;
+                    }
+                    let shown = cmp::min(rows.len(), h - row);
+                    let spans = highlights(line, l, re, state.incmatch);
+                    draw_line(self, row + 1, line, &rows[..shown], active, &state.options, &spans);
+                    row += rows.len();
+                }
+                None => {
+                    self.print(0, row + 1, rustbox::RB_NORMAL, Color::Default, Color::Default, "~");
+                    row += 1;
+                }
+            }
+            l += 1;
         }
 
         let mut idx = 0;
@@ -309,28 +1464,803 @@ impl VexDisplay for rustbox::RustBox {
             } else {
                 (Color::Default, Color::Default)
             };
-            self.print(idx, 0, rustbox::RB_NORMAL, fg, bg, &name);
+            self.print(idx, 0, rustbox::RB_NORMAL, fg, bg, name);
             idx += 1 + name.len();
         }
     
         match state.mode {
-            Mode::Colon => {
+            Mode::Colon | Mode::Search(_) => {
+                let prompt = match state.mode {
+                    Mode::Search(true) => "/",
+                    Mode::Search(false) => "?",
+                    _ => ":",
+                };
                 let last_line = self.height() - 1;
-                self.print(0, last_line, rustbox::RB_BOLD, Color::Default, Color::Default, ":");
+                self.print(0, last_line, rustbox::RB_BOLD, Color::Default, Color::Default, prompt);
                 self.print(1, last_line, rustbox::RB_NORMAL, Color::Default, Color::Default, &state.colon);
                 self.set_cursor(state.colon.len() as isize + 1, last_line as isize);
             }
             _ =>  {
                 let len = active.lines.len();
-                let status_line = format!("{} {}L {}", active.name(), len, state.status);
+                let line = active.lines.get(y).unwrap();
+                let x = columns::snap(line, x);
+                let col = columns::display_col(line, x, tabstop);
+                // like vim's ruler: the character count, and the screen
+                // column too when they differ
+                let chars = columns::grapheme_index(line, x) + 1;
+                let ruler = if chars == col + 1 {
+                    format!("{},{}", y + 1, chars)
+                } else {
+                    format!("{},{}-{}", y + 1, chars, col + 1)
+                };
+                let status_line = format!("{} {}L {} {}", active.name(), len, ruler, state.status);
                 self.print(0, self.height() - 1, rustbox::RB_NORMAL, Color::Default, Color::Default, &status_line);
 
-                let x_ = active.lines.get(y).unwrap().len();
-                let x__ = cmp::min(x_, x);
-                self.set_cursor(x__ as isize, (y - offset) as isize + 1);
+                let (x, y) = active.cursor();
+                self.set_cursor(x as isize, y as isize + 1);
             }
         }
 
         self.present();
     }
 }
+
+// Draws the rows of a line starting at screen row `top`, putting each
+// grapheme in the cell its display column says so wide characters and
+// tabs push the rest of the line along. termbox has no way to draw
+// combining marks, so only the first character of a cluster is shown.
+// With `list` on, tabs, trailing spaces and the line's end are drawn with
+// `listchars`. Search matches in `spans` are highlighted, the one
+// incsearch is showing in reverse.
+fn draw_line(rb: &RustBox, top: usize, line: &str, rows: &[Row], buffer: &Buffer, options: &Options,
+             spans: &[(usize, usize, bool)]) {
+    let paint = |x, y, fg, ch, hl: Option<bool>| match hl {
+        Some(true) => rb.print_char(x, y, rustbox::RB_REVERSE, Color::Default, Color::Default, ch),
+        Some(false) => rb.print_char(x, y, rustbox::RB_NORMAL, Color::Black, Color::Yellow, ch),
+        None => rb.print_char(x, y, rustbox::RB_NORMAL, fg, Color::Default, ch),
+    };
+    let normal = |x, y, ch, hl| paint(x, y, Color::Default, ch, hl);
+    let special = |x, y, ch, hl| paint(x, y, Color::Blue, ch, hl);
+    let layout = buffer.layout();
+    let (w, _) = buffer.window();
+    let leftcol = buffer.leftcol();
+    let chars = &options.listchars;
+    let trailing = line.trim_end_matches(' ').len();
+    let mut at = 0;
+    let mut r = 0;
+    for (i, g) in UnicodeSegmentation::grapheme_indices(line, true) {
+        while i >= rows[r].end {
+            r += 1;
+            if r == rows.len() {
+                // the rest didn't fit on screen
+                return;
+            }
+            rb.print(0, top + r, rustbox::RB_NORMAL, Color::Blue, Color::Default, &layout.showbreak);
+        }
+        let width = columns::width(g, at, layout.tabstop);
+        let x = at - rows[r].col + layout.indent(r);
+        at += width;
+        if x < leftcol {
+            continue;
+        }
+        let (x, y) = (x - leftcol, top + r);
+        if x + width > w {
+            if layout.wrap {
+                continue;
+            }
+            return;
+        }
+        let hl = spans.iter().find(|&&(start, end, _)| i >= start && i < end).map(|&(_, _, current)| current);
+        match (g, options.list) {
+            ("\t", true) => {
+                if let Some((first, rest)) = chars.tab {
+                    special(x, y, first, hl);
+                    for x in x + 1..x + width {
+                        special(x, y, rest, hl);
+                    }
+                }
+            }
+            // a highlighted tab still needs something to show it
+            ("\t", false) if hl.is_some() => {
+                for x in x..x + width {
+                    normal(x, y, ' ', hl);
+                }
+            }
+            ("\t", false) => {}
+            (" ", true) if i >= trailing && chars.trail.is_some() => {
+                special(x, y, chars.trail.unwrap(), hl);
+            }
+            ("\u{a0}", true) if chars.nbsp.is_some() => {
+                special(x, y, chars.nbsp.unwrap(), hl);
+            }
+            _ if width > 1 && columns::control(g).is_some() => {
+                for (j, ch) in columns::control(g).unwrap().chars().enumerate() {
+                    special(x + j, y, ch, hl);
+                }
+            }
+            _ => {
+                if let Some(ch) = g.chars().next() {
+                    normal(x, y, ch, hl);
+                }
+            }
+        }
+    }
+    if let (true, Some(eol)) = (options.list, chars.eol) {
+        let x = at - rows[r].col + layout.indent(r);
+        if x >= leftcol && x - leftcol < w {
+            special(x - leftcol, top + r, eol, None);
+        }
+    }
+}
+
+#[cfg(test)]
+fn type_keys(state: &mut State, keys: &str) {
+    for c in keys.chars() {
+        let key = match c {
+            '\x1b' => Key::Esc,
+            '\n' => Key::Enter,
+            c => Key::Char(c),
+        };
+        state.handle_key(key);
+    }
+}
+
+#[test]
+fn dot_repeats_last_change() {
+    let mut state = State::new(80, 24);
+    state.buffers.push(Buffer::new_empty(80, 22));
+    type_keys(&mut state, "ione\ntwo\nthree\x1b");
+    type_keys(&mut state, "1Gdd.");
+    assert_eq!(state.active().unwrap().lines, vec!["three"]);
+
+    type_keys(&mut state, "$ax\x1b..");
+    assert_eq!(state.active().unwrap().lines, vec!["threexxx"]);
+
+    type_keys(&mut state, "uu");
+    assert_eq!(state.active().unwrap().lines, vec!["threex"]);
+
+    // a count given to `.` replaces the one the change had, and sticks
+    state.active_mut().unwrap().lines = ["a b c d e f g h"].iter().map(|l| l.to_string()).collect();
+    type_keys(&mut state, "0dw3.");
+    assert_eq!(state.active().unwrap().lines, vec!["e f g h"]);
+    type_keys(&mut state, ".");
+    assert_eq!(state.active().unwrap().lines, vec!["h"]);
+    state.active_mut().unwrap().lines = ["a b c d e f g h"].iter().map(|l| l.to_string()).collect();
+    type_keys(&mut state, "0d2w.");
+    assert_eq!(state.active().unwrap().lines, vec!["e f g h"]);
+    type_keys(&mut state, "1.");
+    assert_eq!(state.active().unwrap().lines, vec!["f g h"]);
+}
+
+#[test]
+fn set_tabstop() {
+    let mut state = State::new(80, 24);
+    state.buffers.push(Buffer::new_empty(80, 22));
+    type_keys(&mut state, "i\tx\nabcdefghij\x1b");
+    // screen column 4 is in the middle of an eight column tab
+    type_keys(&mut state, "0llllk");
+    assert_eq!(state.active().unwrap().point(), (0, 0));
+
+    // but just past a four column one
+    type_keys(&mut state, ":set ts=4\n");
+    assert_eq!(state.active().unwrap().tabstop(), 4);
+    type_keys(&mut state, "j0llllk");
+    assert_eq!(state.active().unwrap().point(), (1, 0));
+
+    type_keys(&mut state, ":set ts?\n");
+    assert_eq!(state.status, "tabstop=4");
+    type_keys(&mut state, ":set ts=0\n");
+    assert_eq!(state.status, "Argument must be positive: ts=0");
+}
+
+#[test]
+fn colon_ranges_and_errors() {
+    let mut state = State::new(80, 24);
+    state.buffers.push(Buffer::new_empty(80, 22));
+    type_keys(&mut state, "ione\n  two\nthree\x1b");
+    type_keys(&mut state, ":/two/\n");
+    assert_eq!(state.active().unwrap().point(), (2, 1));
+    type_keys(&mut state, ":$\n");
+    assert_eq!(state.active().unwrap().point(), (0, 2));
+
+    type_keys(&mut state, ":9\n");
+    assert_eq!(state.status, "E16: Invalid range");
+    type_keys(&mut state, ":1,2q\n");
+    assert_eq!(state.status, "E481: No range allowed");
+    type_keys(&mut state, ":frob\n");
+    assert_eq!(state.status, "E492: Not an editor command: frob");
+    assert_eq!(state.buffers.len(), 1);
+}
+
+#[test]
+fn write_and_quit() {
+    use std::env;
+    use std::fs;
+
+    let dir = env::temp_dir().join(format!("vex-write-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("notes.txt");
+    let other = dir.join("other.txt");
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(&other);
+
+    let mut state = State::new(80, 24);
+    state.buffers.push(Buffer::new_empty(80, 22));
+    type_keys(&mut state, "ione\ntwo\nthree\x1b");
+    type_keys(&mut state, ":q\n");
+    assert_eq!(state.status, "E37: No write since last change (add ! to override)");
+    type_keys(&mut state, ":w\n");
+    assert_eq!(state.status, "E32: No file name");
+
+    type_keys(&mut state, &format!(":w {}\n", path.display()));
+    assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo\nthree\n");
+    assert_eq!(state.status, format!("\"{}\" 3L, 14B written", path.display()));
+    assert!(!state.active().unwrap().is_modified());
+
+    type_keys(&mut state, ":2,3w\n");
+    assert_eq!(state.status, "E140: Use ! to write partial buffer");
+    type_keys(&mut state, &format!(":2,3w {}\n", other.display()));
+    type_keys(&mut state, &format!(":1w >> {}\n", other.display()));
+    assert_eq!(fs::read_to_string(&other).unwrap(), "two\nthree\none\n");
+    type_keys(&mut state, &format!(":w {}\n", other.display()));
+    assert_eq!(state.status, "E13: File exists (add ! to override)");
+
+    // undoing back to what was written leaves nothing to write
+    type_keys(&mut state, "ddu");
+    assert!(!state.active().unwrap().is_modified());
+    type_keys(&mut state, "dd:qa\n");
+    assert_eq!(state.status, "E162: No write since last change for buffer \"notes.txt\"");
+    type_keys(&mut state, "ZZ");
+    assert!(state.buffers.is_empty());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+
+    state.buffers.push(Buffer::new_empty(80, 22));
+    type_keys(&mut state, "ione\x1bZQ");
+    assert!(state.buffers.is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn keep_line_endings_and_encoding() {
+    use std::env;
+    use std::fs;
+
+    let path = env::temp_dir().join(format!("vex-dos-{}.txt", ::std::process::id()));
+    fs::write(&path, "one\r\ntwo").unwrap();
+    let mut state = State::new(80, 24);
+    state.open(&path, false);
+    assert_eq!(state.status, format!("\"{}\" [dos] [noeol] 2L, 8B", path.display()));
+    assert_eq!(state.active().unwrap().lines, vec!["one", "two"]);
+
+    type_keys(&mut state, ":w!\n");
+    assert_eq!(fs::read(&path).unwrap(), b"one\r\ntwo");
+
+    type_keys(&mut state, ":set ff=unix eol\n");
+    assert!(state.active().unwrap().is_modified());
+    type_keys(&mut state, ":q\n");
+    assert_eq!(state.buffers.len(), 1);
+    type_keys(&mut state, ":wq\n");
+    assert!(state.buffers.is_empty());
+    assert_eq!(fs::read(&path).unwrap(), b"one\ntwo\n");
+
+    // Latin-1, read in again as UTF-8 it doesn't decode
+    fs::write(&path, b"caf\xe9\n").unwrap();
+    state.open(&path, false);
+    assert_eq!(state.active().unwrap().lines, vec!["caf\u{e9}"]);
+    type_keys(&mut state, ":e ++enc=utf-8\n");
+    assert_eq!(state.status, format!("\"{}\" [CONVERSION ERROR] 1L, 5B", path.display()));
+    assert_eq!(state.active().unwrap().lines, vec!["caf\u{fffd}"]);
+    // which isn't written back over the bytes it couldn't read
+    type_keys(&mut state, ":w\n");
+    assert_eq!(state.status, "E513: Write error, conversion failed (add ! to override)");
+    assert_eq!(fs::read(&path).unwrap(), b"caf\xe9\n");
+    type_keys(&mut state, ":e ++enc=latin1\n");
+    type_keys(&mut state, "$ax\x1b:w\n");
+    assert_eq!(fs::read(&path).unwrap(), b"caf\xe9x\n");
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn binary_files() {
+    use std::env;
+    use std::fs;
+
+    let path = env::temp_dir().join(format!("vex-bin-{}", ::std::process::id()));
+    let bytes: Vec<u8> = (0..=255).collect();
+    fs::write(&path, &bytes).unwrap();
+    let mut state = State::new(80, 24);
+    state.open(&path, true);
+    assert!(state.status.contains("[binary] [noeol]"));
+    type_keys(&mut state, ":w\n");
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+
+    // change the first byte through the hex dump
+    type_keys(&mut state, ":hex\n");
+    assert_eq!(state.active().unwrap().lines.len(), 16);
+    assert!(state.active().unwrap().lines[0].starts_with("00000000: 0001 0203"));
+    type_keys(&mut state, "0lllllllllldlif\x1b:w\n");
+    let mut expected = bytes.clone();
+    expected[0] = 0xf0;
+    assert_eq!(fs::read(&path).unwrap(), expected);
+
+    type_keys(&mut state, ":hex\n");
+    assert_eq!(state.active().unwrap().lines[0], "\u{f0}\u{1}\u{2}\u{3}\u{4}\u{5}\u{6}\u{7}\u{8}\t");
+    type_keys(&mut state, ":e!\n");
+    assert!(state.active().unwrap().format.binary);
+
+    // undoing :hex goes back to writing the text as it is
+    fs::write(&path, "key: cafe babe\n").unwrap();
+    type_keys(&mut state, ":e!\n:hex\nu");
+    assert_eq!(state.active().unwrap().lines, vec!["key: cafe babe"]);
+    assert!(!state.active().unwrap().format.hex);
+    type_keys(&mut state, ":w\n");
+    assert_eq!(fs::read(&path).unwrap(), b"key: cafe babe\n");
+    // and redoing it back to the dump
+    state.handle_key(Key::Ctrl('r'));
+    assert!(state.active().unwrap().format.hex);
+    assert!(state.active().unwrap().lines[0].starts_with("00000000: 6b65"));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn swap_files() {
+    use std::env;
+    use std::fs;
+
+    let dir = env::temp_dir().join(format!("vex-swap-state-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("notes.txt");
+    let swap = dir.join(".notes.txt.swp");
+    fs::write(&path, "one\ntwo\n").unwrap();
+    // left behind by a vex that's no longer running
+    fs::write(&swap, "vex swap file\npid: 4000000000\nfile: notes.txt\n\none\ntwo\nthree\n").unwrap();
+
+    let mut state = State::new(80, 24);
+    state.open(&path, false);
+    assert!(state.status.starts_with("Swap file \""));
+    assert!(state.status.contains("(D)elete it"));
+    // the first key answers the question
+    type_keys(&mut state, "o");
+    assert!(state.active().unwrap().readonly);
+    type_keys(&mut state, ":w\n");
+    assert!(state.status.starts_with("E45"));
+    type_keys(&mut state, ":q\n");
+    assert!(swap.exists());
+
+    let mut state = State::new(80, 24);
+    state.recover(&path, false);
+    assert_eq!(state.active().unwrap().lines, vec!["one", "two", "three"]);
+    assert!(state.active().unwrap().is_modified());
+    type_keys(&mut state, "ggdd");
+    state.sync_swaps();
+    assert_eq!(Swap::read(&swap).unwrap().lines, vec!["two", "three"]);
+    type_keys(&mut state, ":wq\n");
+    assert_eq!(fs::read_to_string(&path).unwrap(), "two\nthree\n");
+    assert!(!swap.exists());
+
+    let mut state = State::new(80, 24);
+    state.recover(&path, false);
+    assert!(state.status.starts_with("E305"));
+    type_keys(&mut state, ":q\n");
+
+    // the swap file is there as soon as the file's open, so a second vex
+    // asks about it
+    let mut first = State::new(80, 24);
+    first.open(&path, false);
+    assert_eq!(Swap::read(&swap).unwrap().pid, ::std::process::id());
+    let mut second = State::new(80, 24);
+    second.open(&path, false);
+    assert!(second.status.starts_with("Swap file \""));
+    type_keys(&mut second, "q");
+    type_keys(&mut first, ":q\n");
+    assert!(!swap.exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn preserve_modified_buffers() {
+    use std::env;
+    use std::fs;
+
+    let dir = env::temp_dir().join(format!("vex-preserve-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let notes = dir.join("notes.txt");
+    let todo = dir.join("todo.txt");
+    fs::write(&notes, "one\n").unwrap();
+    fs::write(&todo, "two\r\n").unwrap();
+
+    let mut state = State::new(80, 24);
+    state.open(&notes, false);
+    state.open(&todo, false);
+    state.options_mut().swapfile = false;
+    state.open(&dir.join("plans.txt"), false);
+    let swap = dir.join(".notes.txt.swp");
+    assert!(state.preserve().is_empty());
+    assert!(!swap.exists());
+    state.set_swap(0, swap.clone());
+
+    // the first is kept in its swap file, the last beside itself
+    for idx in 0..3 {
+        state.buf_idx = idx;
+        type_keys(&mut state, "ggdd");
+    }
+    let saved = state.preserve();
+    assert_eq!(saved.len(), 3);
+    assert!(saved[0].ends_with(&format!("recover it with vex -r {}", notes.display())));
+    assert!(Swap::read(&swap).is_ok());
+    let recovered = dir.join(format!("plans.txt.{}.recover", ::std::process::id()));
+    assert_eq!(saved[2], format!("\"plans.txt\" saved in \"{}\"", recovered.display()));
+    assert_eq!(fs::read(&recovered).unwrap(), b"");
+
+    // a buffer with no name goes to the temp directory, in a file of its
+    // own even if someone's put something where it would go
+    let planted = env::temp_dir().join(format!("vex.{}.recover", ::std::process::id()));
+    let victim = dir.join("victim");
+    fs::write(&victim, "safe\n").unwrap();
+    #[cfg(unix)]
+    ::std::os::unix::fs::symlink(&victim, &planted).unwrap();
+    #[cfg(not(unix))]
+    fs::write(&planted, "").unwrap();
+    state.buffers.truncate(0);
+    state.buffers.push(Buffer::new_empty(80, 22));
+    state.buf_idx = 0;
+    type_keys(&mut state, "ipeek\x1b");
+    let saved = state.preserve();
+    let recovered = env::temp_dir().join(format!("vex.{}.1.recover", ::std::process::id()));
+    assert_eq!(saved, vec![format!("\"[No Name]\" saved in \"{}\"", recovered.display())]);
+    assert_eq!(fs::read(&recovered).unwrap(), b"peek\n");
+    assert_eq!(fs::read(&victim).unwrap(), b"safe\n");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&recovered).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+    fs::remove_file(&planted).unwrap();
+    fs::remove_file(&recovered).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn search_motions() {
+    use editor::registers::Register;
+
+    let mut state = State::new(80, 24);
+    state.buffers.push(Buffer::new_empty(80, 22));
+    type_keys(&mut state, "ione foo\ntwo Foo bar\nthree foo_bar foo\x1bgg");
+    let point = |state: &State| state.active().unwrap().point();
+
+    type_keys(&mut state, "/fo\n");
+    assert_eq!(point(&state), (4, 0));
+    type_keys(&mut state, "n");
+    assert_eq!(point(&state), (6, 2));
+    type_keys(&mut state, "2n");
+    assert_eq!(point(&state), (4, 0));
+    assert_eq!(state.status, "search hit BOTTOM, continuing at TOP [1/3]");
+    type_keys(&mut state, "N");
+    assert_eq!(point(&state), (14, 2));
+    type_keys(&mut state, "?o\\w*?e\n");
+    assert_eq!(point(&state), (12, 2));
+
+    // case follows ignorecase and smartcase
+    type_keys(&mut state, "gg:set ic\n/foo\nn");
+    assert_eq!(point(&state), (4, 1));
+    type_keys(&mut state, ":set scs\ngg/Foo\nn");
+    assert_eq!(point(&state), (4, 1));
+    assert_eq!(state.status, "search hit BOTTOM, continuing at TOP [1/1]");
+    type_keys(&mut state, ":set nows noic\n/one\n");
+    assert_eq!(state.status, "E385: search hit BOTTOM without match for: one");
+
+    // whole words under the point
+    type_keys(&mut state, ":set ws\ngg*");
+    assert_eq!(point(&state), (0, 0));
+    assert_eq!(state.status, "search hit BOTTOM, continuing at TOP [1/1]");
+    type_keys(&mut state, "3G$#");
+    assert_eq!(point(&state), (4, 0));
+    type_keys(&mut state, "*");
+    assert_eq!(point(&state), (14, 2));
+
+    // offsets, and searches as operator targets
+    type_keys(&mut state, "/bar/e+99999999999999999\n");
+    assert_eq!(point(&state), (10, 1));
+    type_keys(&mut state, "/one/+9223372036854775807\n");
+    assert_eq!(point(&state), (0, 2));
+    type_keys(&mut state, "?two?s-9223372036854775808\n");
+    assert_eq!(point(&state), (0, 1));
+    type_keys(&mut state, "/bar/e-1\n");
+    assert_eq!(point(&state), (9, 1));
+    type_keys(&mut state, "0d/bar/e\n");
+    assert_eq!(state.active().unwrap().lines[1], "");
+    type_keys(&mut state, "ggd/three/-\n");
+    assert_eq!(state.active().unwrap().lines, vec!["three foo_bar foo"]);
+    type_keys(&mut state, "ugg0y//e\n");
+    assert_eq!(state.registers.get('"'), Some(&Register::Chars(String::from("one foo\n\nthree"))));
+
+    // Up brings back the searches typed before
+    type_keys(&mut state, "/");
+    state.handle_key(Key::Up);
+    assert_eq!(state.colon, "/e");
+    state.handle_key(Key::Up);
+    assert_eq!(state.colon, "three/-");
+    state.handle_key(Key::Down);
+    state.handle_key(Key::Down);
+    assert_eq!(state.colon, "");
+    type_keys(&mut state, "\x1b");
+    assert!(state.builder.is_empty());
+}
+
+#[test]
+fn incsearch_and_hlsearch() {
+    let mut state = State::new(80, 6);
+    state.buffers.push(Buffer::new_empty(80, 4));
+    let text: Vec<String> = (0..20).map(|n| format!("line {}", n)).collect();
+    state.active_mut().unwrap().lines = text.into_iter().collect();
+    type_keys(&mut state, ":set is hls\n");
+
+    // the window follows what's typed, and goes back if it's given up on
+    type_keys(&mut state, "/line 1");
+    assert_eq!(state.active().unwrap().point(), (0, 1));
+    type_keys(&mut state, "5");
+    assert_eq!(state.active().unwrap().point(), (0, 15));
+    assert_eq!(state.active().unwrap().offset(), 12);
+    assert_eq!(state.incmatch, Some(((0, 15), (7, 15))));
+    type_keys(&mut state, "\x1b");
+    assert_eq!(state.active().unwrap().view(), ((0, 0), 0));
+    assert_eq!(state.incmatch, None);
+    assert!(state.highlight_regex().is_none());
+
+    type_keys(&mut state, "/e 1\n");
+    assert_eq!(state.active().unwrap().point(), (3, 1));
+    assert_eq!(state.status, "/e 1 [1/11]");
+    let re = state.highlight_regex();
+    assert_eq!(highlights("line 11", 11, re, None), vec![(3, 6, false)]);
+    assert_eq!(highlights("line 11", 11, re, Some(((2, 11), (6, 11)))), vec![(2, 6, true)]);
+    type_keys(&mut state, ":noh\n");
+    assert!(state.highlight_regex().is_none());
+    type_keys(&mut state, "n");
+    assert_eq!(state.status, "/e 1 [2/11]");
+    assert!(state.highlight_regex().is_some());
+
+    // the patterns of :s and :g show up as they're typed too
+    type_keys(&mut state, ":%s/line 7");
+    assert_eq!(state.incmatch, Some(((0, 7), (6, 7))));
+    assert_eq!(state.active().unwrap().point(), (0, 7));
+    type_keys(&mut state, "\x1b");
+    assert_eq!(state.active().unwrap().point(), (3, 10));
+    assert_eq!(state.incmatch, None);
+    type_keys(&mut state, ":g/ 9/");
+    assert_eq!(state.incmatch, Some(((4, 9), (6, 9))));
+    type_keys(&mut state, "d\n");
+    assert_eq!(state.active().unwrap().lines.len(), 19);
+
+    let buffer = state.active().unwrap();
+    let re = search::compile("e", false, false).unwrap();
+    assert_eq!(buffer.count_matches(&re, (3, 1), 10), (2, 11));
+    assert_eq!(count(100), ">99");
+
+    // `*` leaves smartcase out, for `n` and the highlighting alike
+    type_keys(&mut state, "gg");
+    state.active_mut().unwrap().lines = ["Foo foo FOO"].iter().map(|l| l.to_string()).collect();
+    type_keys(&mut state, ":set ic scs\n0*");
+    assert_eq!(state.active().unwrap().point(), (4, 0));
+    type_keys(&mut state, "n");
+    assert_eq!(state.active().unwrap().point(), (8, 0));
+    let re = state.highlight_regex();
+    assert_eq!(highlights("Foo foo FOO", 0, re, None), vec![(0, 3, false), (4, 7, false), (8, 11, false)]);
+    type_keys(&mut state, ":set noic\n");
+    assert_eq!(highlights("Foo foo FOO", 0, state.highlight_regex(), None), vec![(0, 3, false)]);
+}
+
+#[test]
+fn substitutes() {
+    let mut state = State::new(80, 10);
+    state.buffers.push(Buffer::new_empty(80, 8));
+    state.active_mut().unwrap().lines = ["foo bar foo", "Foo foo", "baz"].iter().map(|l| l.to_string()).collect();
+    let original = vec!["foo bar foo", "Foo foo", "baz"];
+    type_keys(&mut state, "&");
+    assert_eq!(state.status, NO_PREVIOUS);
+
+    type_keys(&mut state, ":s/foo/X/\n");
+    assert_eq!(state.active().unwrap().lines, vec!["X bar foo", "Foo foo", "baz"]);
+    type_keys(&mut state, "&");
+    assert_eq!(state.active().unwrap().lines[0], "X bar X");
+    type_keys(&mut state, "uu");
+    assert_eq!(state.active().unwrap().lines, original);
+
+    // all of a :s is one undo step
+    type_keys(&mut state, ":%s/(f)(o+)/\\2\\1/g\n");
+    assert_eq!(state.active().unwrap().lines, vec!["oof bar oof", "Foo oof", "baz"]);
+    assert_eq!(state.status, "3 substitutions on 2 lines");
+    type_keys(&mut state, "u");
+    assert_eq!(state.active().unwrap().lines, original);
+    type_keys(&mut state, ":%s/foo/<\\U&>/gi\n");
+    assert_eq!(state.active().unwrap().lines, vec!["<FOO> bar <FOO>", "<FOO> <FOO>", "baz"]);
+    type_keys(&mut state, "u:1s/ /\\r/g\n");
+    assert_eq!(state.active().unwrap().lines, vec!["foo", "bar", "foo", "Foo foo", "baz"]);
+    assert_eq!(state.active().unwrap().point(), (0, 2));
+    type_keys(&mut state, "u");
+    assert_eq!(state.active().unwrap().lines, original);
+
+    type_keys(&mut state, ":%s/foo//gn\n");
+    assert_eq!(state.status, "3 matches on 2 lines");
+    type_keys(&mut state, ":s/zzz/y/\n");
+    assert_eq!(state.status, "E486: Pattern not found: zzz");
+    assert_eq!(state.active().unwrap().lines, original);
+    // a count runs to the last line, however big it is
+    type_keys(&mut state, ":2s/o/0/g 18446744073709551615\n");
+    assert_eq!(state.active().unwrap().lines, vec!["foo bar foo", "F00 f00", "baz"]);
+    type_keys(&mut state, "u");
+
+    // confirming each match
+    type_keys(&mut state, ":%s/foo/X/gc\n");
+    assert_eq!(state.status, "replace with X (y/n/a/q/l)?");
+    assert_eq!(state.incmatch, Some(((0, 0), (3, 0))));
+    type_keys(&mut state, "y");
+    assert_eq!(state.active().unwrap().lines[0], "X bar foo");
+    assert_eq!(state.incmatch, Some(((6, 0), (9, 0))));
+    type_keys(&mut state, "n");
+    assert_eq!(state.incmatch, Some(((4, 1), (7, 1))));
+    type_keys(&mut state, "y");
+    assert_eq!(state.active().unwrap().lines, vec!["X bar foo", "Foo X", "baz"]);
+    assert!(state.confirm.is_none() && state.incmatch.is_none());
+    type_keys(&mut state, "u");
+    assert_eq!(state.active().unwrap().lines, original);
+    type_keys(&mut state, ":%s/foo/X/gc\nnl");
+    assert_eq!(state.active().unwrap().lines, vec!["foo bar X", "Foo foo", "baz"]);
+    type_keys(&mut state, "u:%s/o/0/gc\nyq");
+    assert_eq!(state.active().unwrap().lines, vec!["f0o bar foo", "Foo foo", "baz"]);
+    type_keys(&mut state, "u:%s/o/0/gc\na");
+    assert_eq!(state.active().unwrap().lines, vec!["f00 bar f00", "F00 f00", "baz"]);
+    type_keys(&mut state, "u");
+
+    // `:&&` keeps the flags, c included, `:&` doesn't, and `g&` is
+    // `:%s//~/&`
+    type_keys(&mut state, ":2&&\na");
+    assert_eq!(state.active().unwrap().lines[1], "F00 f00");
+    type_keys(&mut state, "u:2&\n");
+    assert_eq!(state.active().unwrap().lines[1], "F0o foo");
+    type_keys(&mut state, "ug&");
+    assert_eq!(state.active().unwrap().lines, vec!["f0o bar foo", "F0o foo", "baz"]);
+    type_keys(&mut state, "u:1s/bar/~~/\n");
+    assert_eq!(state.active().unwrap().lines[0], "foo 00 foo");
+}
+
+#[test]
+fn global_commands() {
+    let mut state = State::new(80, 10);
+    state.buffers.push(Buffer::new_empty(80, 8));
+    let text = vec!["a x", "b", "c x", "x x", "d"];
+    state.active_mut().unwrap().lines = text.iter().map(|l| l.to_string()).collect();
+
+    type_keys(&mut state, ":g/x/s/x/y/\n");
+    assert_eq!(state.active().unwrap().lines, vec!["a y", "b", "c y", "y x", "d"]);
+    assert_eq!(state.active().unwrap().point(), (0, 3));
+    type_keys(&mut state, "u:v/x/s/$/!/\n");
+    assert_eq!(state.active().unwrap().lines, vec!["a x", "b!", "c x", "x x", "d!"]);
+    type_keys(&mut state, "u:3,$g!/x/s/$/?/\n");
+    assert_eq!(state.active().unwrap().lines, vec!["a x", "b", "c x", "x x", "d?"]);
+    type_keys(&mut state, "u");
+
+    // lines deleted or moved along the way are still found, or skipped
+    type_keys(&mut state, ":g/x/normal jdd\n");
+    assert_eq!(state.active().unwrap().lines, vec!["a x", "c x", "d"]);
+    type_keys(&mut state, "u");
+    assert_eq!(state.active().unwrap().lines, text);
+    type_keys(&mut state, ":g/^/normal ddggP\n");
+    assert_eq!(state.active().unwrap().lines, vec!["d", "x x", "c x", "b", "a x"]);
+    type_keys(&mut state, "u:2,3normal ihi\n");
+    assert_eq!(state.active().unwrap().lines, vec!["a x", "hib", "hic x", "x x", "d"]);
+    type_keys(&mut state, "uu");
+
+    type_keys(&mut state, ":g/z/s//y/\n");
+    assert_eq!(state.status, "E486: Pattern not found: z");
+    type_keys(&mut state, ":v/./s/x/y/\n");
+    assert_eq!(state.status, "Pattern found in every line: .");
+    type_keys(&mut state, ":g/x/g/b/s//y/\n");
+    assert_eq!(state.status, "E147: Cannot do :global recursive");
+    assert_eq!(state.active().unwrap().lines, text);
+}
+
+#[test]
+fn line_commands() {
+    let mut state = State::new(80, 10);
+    state.buffers.push(Buffer::new_empty(80, 8));
+    let text = vec!["one", "two", "three", "four", "five"];
+    state.active_mut().unwrap().lines = text.iter().map(|l| l.to_string()).collect();
+
+    type_keys(&mut state, ":2,3d\n");
+    assert_eq!(state.active().unwrap().lines, vec!["one", "four", "five"]);
+    assert_eq!(state.active().unwrap().point(), (0, 1));
+    type_keys(&mut state, ":pu\n");
+    assert_eq!(state.active().unwrap().lines, vec!["one", "four", "two", "three", "five"]);
+    assert_eq!(state.active().unwrap().point(), (0, 3));
+    type_keys(&mut state, "uu:1d a 2\n:$pu a\n");
+    assert_eq!(state.active().unwrap().lines, vec!["three", "four", "five", "one", "two"]);
+    type_keys(&mut state, "uu:4,5y b\n:0pu b\n");
+    assert_eq!(state.active().unwrap().lines, vec!["four", "five", "one", "two", "three", "four", "five"]);
+    type_keys(&mut state, ":pu! z\n");
+    assert_eq!(state.status, "Nothing in register z");
+    type_keys(&mut state, "u");
+    assert_eq!(state.active().unwrap().lines, text);
+
+    type_keys(&mut state, ":1m$\n");
+    assert_eq!(state.active().unwrap().lines, vec!["two", "three", "four", "five", "one"]);
+    type_keys(&mut state, ":m0\n");
+    assert_eq!(state.active().unwrap().lines, text);
+    type_keys(&mut state, ":2,4m3\n");
+    assert_eq!(state.status, "E134: Cannot move a range of lines into itself");
+    type_keys(&mut state, ":1,2t$\n");
+    assert_eq!(state.active().unwrap().lines, vec!["one", "two", "three", "four", "five", "one", "two"]);
+    type_keys(&mut state, "u:2co0\n");
+    assert_eq!(state.active().unwrap().lines, vec!["two", "one", "two", "three", "four", "five"]);
+    type_keys(&mut state, "u");
+
+    // :g keeps up with lines as they're moved
+    type_keys(&mut state, ":g/^/m0\n");
+    assert_eq!(state.active().unwrap().lines, vec!["five", "four", "three", "two", "one"]);
+    type_keys(&mut state, "u:g/o/t$\n");
+    assert_eq!(state.active().unwrap().lines, vec!["one", "two", "three", "four", "five", "one", "two", "four"]);
+    type_keys(&mut state, "u:v/e/d\n");
+    assert_eq!(state.active().unwrap().lines, vec!["one", "three", "five"]);
+    type_keys(&mut state, "u");
+
+    // marks go with their lines
+    type_keys(&mut state, ":3ka\n:mark b\n:'a,'a+1m0\n");
+    assert_eq!(state.active().unwrap().lines, vec!["three", "four", "one", "two", "five"]);
+    type_keys(&mut state, ":'ad\n:'b\n");
+    assert_eq!(state.active().unwrap().lines, vec!["four", "one", "two", "five"]);
+    assert_eq!(state.active().unwrap().point(), (0, 2));
+    type_keys(&mut state, ":k1\n");
+    assert_eq!(state.status, "E191: Argument must be a letter or forward/backward quote");
+    type_keys(&mut state, ":k\n");
+    assert_eq!(state.status, "E471: Argument required");
+
+    state.active_mut().unwrap().lines = ["a", "  b", ")c", "", "d"].iter().map(|l| l.to_string()).collect();
+    type_keys(&mut state, ":1,3j\n");
+    assert_eq!(state.active().unwrap().lines, vec!["a b)c", "", "d"]);
+    assert_eq!(state.active().unwrap().point(), (3, 0));
+    type_keys(&mut state, "u:1j!\n");
+    assert_eq!(state.active().unwrap().lines[0], "a  b");
+    type_keys(&mut state, "u:1j 3\n");
+    assert_eq!(state.active().unwrap().lines[0], "a b)c");
+    type_keys(&mut state, "u:4j\n");
+    assert_eq!(state.active().unwrap().lines, vec!["a", "  b", ")c", "d"]);
+    type_keys(&mut state, "u:2j 18446744073709551615\n");
+    assert_eq!(state.active().unwrap().lines, vec!["a", "  b)c d"]);
+
+    type_keys(&mut state, "u:2>> 2\n");
+    assert_eq!(state.active().unwrap().lines, vec!["a", "\t\t  b", "\t\t)c", "", "d"]);
+    type_keys(&mut state, ":%<\n");
+    assert_eq!(state.active().unwrap().lines, vec!["a", "\t  b", "\t)c", "", "d"]);
+    type_keys(&mut state, ":2> 99999999999999999999999\n");
+    assert_eq!(state.active().unwrap().lines, vec!["a", "\t\t  b", "\t\t)c", "", "\td"]);
+    type_keys(&mut state, ":1< 18446744073709551615\n");
+    assert_eq!(state.active().unwrap().lines, vec!["a", "\t  b", "\t)c", "", "d"]);
+}
+
+#[test]
+fn find_motions() {
+    let mut state = State::new(80, 10);
+    state.buffers.push(Buffer::new_empty(80, 8));
+    state.active_mut().unwrap().lines = ["call(a, b, c)"].iter().map(|l| l.to_string()).collect();
+
+    type_keys(&mut state, "2f,");
+    assert_eq!(state.active().unwrap().point(), (9, 0));
+    type_keys(&mut state, ",");
+    assert_eq!(state.active().unwrap().point(), (6, 0));
+    type_keys(&mut state, "F(");
+    assert_eq!(state.active().unwrap().point(), (4, 0));
+    // failed finds don't move
+    type_keys(&mut state, ";fz");
+    assert_eq!(state.active().unwrap().point(), (4, 0));
+
+    type_keys(&mut state, "0f(ldt)");
+    assert_eq!(state.active().unwrap().lines, vec!["call()"]);
+    type_keys(&mut state, "u0cf(f(\x1b");
+    assert_eq!(state.active().unwrap().lines, vec!["f(a, b, c)"]);
+    // `;` after `t` doesn't get stuck in front of the next one
+    type_keys(&mut state, "0t,;");
+    assert_eq!(state.active().unwrap().point(), (5, 0));
+    // and a failed find leaves the text alone
+    type_keys(&mut state, "dfz");
+    assert_eq!(state.active().unwrap().lines, vec!["f(a, b, c)"]);
+}
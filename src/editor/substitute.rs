@@ -0,0 +1,333 @@
+use super::Coord;
+
+use editor::buffer::Buffer;
+use editor::search::{until, Captures, Regex};
+
+// `:s/pattern/replacement/[flags] [count]`, taken apart. Any character
+// that isn't a letter, digit, space, `\`, `"`, `|` or `&` can stand in for
+// the `/`s.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Substitute {
+    // the pattern, empty for the last one searched for, and the
+    // replacement; None for the last substitute's, as in `:s g` or `:&&`
+    pub with: Option<(String, String)>,
+    pub flags: Flags,
+    pub count: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Flags {
+    // `&`: the flags of the last substitute, then any of these
+    pub keep: bool,
+    // `g`: every match in the line rather than the first
+    pub global: bool,
+    // `c`: ask before each one
+    pub confirm: bool,
+    // `i` ignores case and `I` doesn't, whatever ignorecase says
+    pub ignorecase: Option<bool>,
+    // `n`: only count the matches
+    pub count_only: bool,
+}
+
+impl Flags {
+    // Adds these flags to `last`'s, for `&`.
+    pub fn after(self, last: Flags) -> Flags {
+        Flags {
+            keep: false,
+            global: self.global || last.global,
+            confirm: self.confirm || last.confirm,
+            ignorecase: self.ignorecase.or(last.ignorecase),
+            count_only: self.count_only || last.count_only,
+        }
+    }
+}
+
+impl Substitute {
+    // Parses what follows `:s` or `:&`.
+    pub fn parse(arg: &str) -> Result<Substitute, String> {
+        let delim = match arg.chars().next() {
+            Some(c) if !c.is_alphanumeric() && !c.is_whitespace() && !"\\\"|&".contains(c) => c,
+            _ => {
+                let (flags, count) = flags(arg)?;
+                return Ok(Substitute { with: None, flags, count });
+            }
+        };
+        let rest = &arg[delim.len_utf8()..];
+        let (pattern, rest) = until(rest, delim);
+        let (replacement, rest) = match rest {
+            Some(rest) => until(rest, delim),
+            None => (String::new(), None),
+        };
+        let (flags, count) = flags(rest.unwrap_or(""))?;
+        Ok(Substitute { with: Some((pattern, replacement)), flags, count })
+    }
+}
+
+fn flags(s: &str) -> Result<(Flags, Option<usize>), String> {
+    let mut flags = Flags::default();
+    let mut chars = s.char_indices().peekable();
+    if let Some(&(_, '&')) = chars.peek() {
+        flags.keep = true;
+        chars.next();
+    }
+    let mut rest = "";
+    for (i, c) in chars {
+        match c {
+            'g' => flags.global = !flags.global,
+            'c' => flags.confirm = true,
+            'i' => flags.ignorecase = Some(true),
+            'I' => flags.ignorecase = Some(false),
+            'n' => flags.count_only = true,
+            _ => {
+                rest = &s[i..];
+                break;
+            }
+        }
+    }
+    let rest = rest.trim();
+    let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    if digits < rest.len() {
+        return Err(format!("E488: Trailing characters: {}", &rest[digits..]));
+    }
+    if rest.is_empty() {
+        return Ok((flags, None));
+    }
+    // a count too big to hold runs to the last line like any other big one
+    match rest.parse() {
+        Ok(0) => Err(String::from("E939: Positive count required")),
+        Ok(n) => Ok((flags, Some(n))),
+        Err(_) => Ok((flags, Some(usize::MAX))),
+    }
+}
+
+// Puts the last replacement in place of each `~`, as vi does.
+pub fn with_previous(replacement: &str, previous: &str) -> String {
+    let mut out = String::new();
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '~' => out.push_str(previous),
+            '\\' => {
+                out.push('\\');
+                out.extend(chars.next());
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// What a match becomes: `&` and `\0` are the whole match and `\1` to `\9`
+// its groups; `\r` and `\n` break the line; `\u` and `\l` change the case
+// of the next character, and `\U` and `\L` of everything up to `\E` or
+// `\e`. A backslash makes anything else itself, `\&` and `\\` included.
+pub fn expand(replacement: &str, caps: &Captures) -> String {
+    fn push(out: &mut String, text: &str, one: &mut Option<bool>, all: Option<bool>) {
+        for c in text.chars() {
+            match one.take().or(all) {
+                Some(true) => out.extend(c.to_uppercase()),
+                Some(false) => out.extend(c.to_lowercase()),
+                None => out.push(c),
+            }
+        }
+    }
+    let mut out = String::new();
+    // the case for the next character, and for every character
+    let mut one: Option<bool> = None;
+    let mut all: Option<bool> = None;
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => push(&mut out, caps.get(0).map_or("", |m| m.as_str()), &mut one, all),
+            '\\' => {
+                match chars.next() {
+                    Some(d) if d.is_ascii_digit() => {
+                        let group = caps.get(d.to_digit(10).unwrap() as usize).map_or("", |m| m.as_str());
+                        push(&mut out, group, &mut one, all);
+                    }
+                    Some('r') | Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('u') => one = Some(true),
+                    Some('l') => one = Some(false),
+                    Some('U') => all = Some(true),
+                    Some('L') => all = Some(false),
+                    Some('E') | Some('e') => all = None,
+                    Some(c) => push(&mut out, &c.to_string(), &mut one, all),
+                    None => out.push('\\'),
+                }
+            }
+            c => push(&mut out, &c.to_string(), &mut one, all),
+        }
+    }
+    out
+}
+
+// A `:s` working its way through its lines a match at a time, so `c` can
+// ask about each one. Lines are only written back once something in them
+// has been replaced.
+pub struct Substitution {
+    re: Regex,
+    replacement: String,
+    global: bool,
+    // the line being worked through, how many buffer lines it's become
+    // so far, and the last line to do
+    l: usize,
+    written: usize,
+    last: usize,
+    // the line as it was, with its matches and what each becomes
+    original: String,
+    matches: Vec<(usize, usize, String)>,
+    next: usize,
+    // the new line, up to `pos` in the original
+    done: String,
+    pos: usize,
+    // substitutions made, on how many lines, the last of which it was
+    pub count: usize,
+    pub lines: usize,
+    pub last_changed: Option<usize>,
+}
+
+impl Substitution {
+    pub fn new(re: Regex, replacement: String, global: bool, first: usize, last: usize, buffer: &Buffer) -> Substitution {
+        let mut sub = Substitution {
+            re,
+            replacement,
+            global,
+            l: first,
+            written: 1,
+            last,
+            original: String::new(),
+            matches: Vec::new(),
+            next: 0,
+            done: String::new(),
+            pos: 0,
+            count: 0,
+            lines: 0,
+            last_changed: None,
+        };
+        sub.load(buffer);
+        sub
+    }
+
+    fn load(&mut self, buffer: &Buffer) {
+        self.original = buffer.lines[self.l].clone();
+        let take = if self.global { usize::MAX } else { 1 };
+        let replacement = &self.replacement;
+        self.matches = self.re.captures_iter(&self.original).take(take).map(|caps| {
+            let m = caps.get(0).unwrap();
+            (m.start(), m.end(), expand(replacement, &caps))
+        }).collect();
+        self.next = 0;
+        self.written = 1;
+        self.done.clear();
+        self.pos = 0;
+    }
+
+    // Where the next match is in the buffer as it now stands, moving on
+    // through the lines as each runs out, or None once they all have.
+    pub fn next(&mut self, buffer: &mut Buffer) -> Option<(Coord, Coord)> {
+        loop {
+            if self.l > self.last {
+                return None;
+            }
+            if self.next < self.matches.len() {
+                let (start, end, _) = self.matches[self.next];
+                // how far into its last line the new text has got
+                let l = self.l + self.done.matches('\n').count();
+                let tail = self.done.len() - self.done.rfind('\n').map_or(0, |i| i + 1);
+                return Some(((tail + start - self.pos, l), (tail + end - self.pos, l)));
+            }
+            self.finish_line(buffer);
+            if self.l <= self.last {
+                self.load(buffer);
+            }
+        }
+    }
+
+    // Replaces the match `next` found, or leaves it as it is.
+    pub fn replace(&mut self) {
+        let (start, end, ref with) = self.matches[self.next];
+        self.done.push_str(&self.original[self.pos..start]);
+        self.done.push_str(with);
+        self.pos = end;
+        self.next += 1;
+        self.count += 1;
+        if self.last_changed != Some(self.l) {
+            self.last_changed = Some(self.l);
+            self.lines += 1;
+        }
+    }
+
+    pub fn skip(&mut self) {
+        let end = self.matches[self.next].1;
+        self.done.push_str(&self.original[self.pos..end]);
+        self.pos = end;
+        self.next += 1;
+    }
+
+    // Leaves the rest of the matches alone.
+    pub fn stop(&mut self, buffer: &mut Buffer) {
+        self.next = self.matches.len();
+        self.finish_line(buffer);
+        self.l = self.last + 1;
+    }
+
+    // Writes the line back as far as it's got, so what's been replaced so
+    // far shows.
+    pub fn show(&mut self, buffer: &mut Buffer) {
+        if self.last_changed == Some(self.l) {
+            let text = format!("{}{}", self.done, &self.original[self.pos..]);
+            let lines: Vec<String> = text.split('\n').map(String::from).collect();
+            let count = lines.len();
+            buffer.change_lines(self.l, self.written, lines);
+            self.written = count;
+        }
+    }
+
+    fn finish_line(&mut self, buffer: &mut Buffer) {
+        self.show(buffer);
+        // a line broken in two pushes the rest down
+        if self.last_changed == Some(self.l) {
+            self.last_changed = Some(self.l + self.written - 1);
+        }
+        self.last += self.written - 1;
+        self.l += self.written;
+    }
+}
+
+#[cfg(test)]
+fn expand_with(replacement: &str, pattern: &str, text: &str) -> String {
+    let re = Regex::new(pattern).unwrap();
+    expand(replacement, &re.captures(text).unwrap())
+}
+
+#[test]
+fn parse_substitutes() {
+    let with = |pattern: &str, replacement: &str| Some((pattern.to_owned(), replacement.to_owned()));
+    let sub = Substitute::parse("/a\\/b/c\\/d/gI 3").unwrap();
+    assert_eq!(sub.with, with("a/b", "c/d"));
+    assert_eq!(sub.flags, Flags { global: true, ignorecase: Some(false), ..Flags::default() });
+    assert_eq!(sub.count, Some(3));
+    assert_eq!(Substitute::parse("/a/b/ 99999999999999999999999").unwrap().count, Some(usize::MAX));
+
+    assert_eq!(Substitute::parse("#x#\\#&").unwrap().with, with("x", "#&"));
+    assert_eq!(Substitute::parse("/x").unwrap().with, with("x", ""));
+    let sub = Substitute::parse("/x/y/&c").unwrap();
+    assert!(sub.flags.keep && sub.flags.confirm);
+
+    assert_eq!(Substitute::parse("").unwrap(), Substitute { with: None, flags: Flags::default(), count: None });
+    let sub = Substitute::parse("&g 2").unwrap();
+    assert_eq!((sub.with, sub.flags, sub.count), (None, Flags { keep: true, global: true, ..Flags::default() }, Some(2)));
+    assert!(Substitute::parse("/x/y/q").is_err());
+    assert!(Substitute::parse("/x/y/ 0").is_err());
+}
+
+#[test]
+fn expand_replacements() {
+    assert_eq!(expand_with("<&>", "b+", "abbc"), "<bb>");
+    assert_eq!(expand_with("\\2-\\1 \\&", "(a)(b)", "ab"), "b-a &");
+    assert_eq!(expand_with("\\u\\1 \\U\\2\\E!\\2", "(\\w+) (\\w+)", "one two"), "One TWO!two");
+    assert_eq!(expand_with("\\L\\uHELLO", "x", "x"), "Hello");
+    assert_eq!(expand_with("a\\rb\\nc", "x", "x"), "a\nb\nc");
+    assert_eq!(with_previous("~!\\~", "ab"), "ab!\\~");
+}
@@ -0,0 +1,185 @@
+use std::env;
+use std::fs;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::SystemTime;
+
+use editor::file;
+
+// A swap file keeps a copy of a buffer's text while it's being edited, so
+// it can be got back if vex dies before the buffer is written. It starts
+// with a few `name: value` lines saying who wrote it and for what, then an
+// empty line, then the text.
+pub struct Swap {
+    // the vex that wrote it, which may still be running
+    pub pid: u32,
+    // the file it's a copy of
+    pub file: PathBuf,
+    pub lines: Vec<String>,
+}
+
+const MAGIC: &str = "vex swap file";
+
+impl Swap {
+    // Writes a swap file for this process's copy of `file`, readable by no
+    // more people than the file is.
+    pub fn write<'a, I>(path: &Path, file: &Path, lines: I) -> io::Result<()>
+        where I: Iterator<Item=&'a String>
+    {
+        let full = env::current_dir().map(|cwd| cwd.join(file)).unwrap_or_else(|_| file.to_path_buf());
+        // saving over it keeps the mode it starts with
+        let mode = fs::metadata(file).map_or(0o600, |meta| file::mode(&meta) & 0o666);
+        match file::create(path, mode) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+            _ => {}
+        }
+        file::save(path, false, |w| {
+            write!(w, "{}\npid: {}\nfile: {}\n\n", MAGIC, process::id(), full.display())?;
+            for line in lines {
+                w.write_all(line.as_bytes())?;
+                w.write_all(b"\n")?;
+            }
+            Ok(0)
+        }).map(|_| ())
+    }
+
+    pub fn read(path: &Path) -> io::Result<Swap> {
+        let bad = || io::Error::new(io::ErrorKind::InvalidData, "not a swap file");
+        // not `lines()`, which would take a carriage return off the end
+        let mut lines = BufReader::new(fs::File::open(path)?).split(b'\n').map(|line| {
+            line.and_then(|l| String::from_utf8(l).map_err(|_| bad()))
+        });
+        if lines.next().is_none_or(|l| l.ok().is_none_or(|l| l != MAGIC)) {
+            return Err(bad());
+        }
+        let mut swap = Swap { pid: 0, file: PathBuf::new(), lines: Vec::new() };
+        for line in lines.by_ref() {
+            let line = line?;
+            if line.is_empty() {
+                break;
+            }
+            let mut parts = line.splitn(2, ": ");
+            match (parts.next(), parts.next()) {
+                (Some("pid"), Some(pid)) => swap.pid = pid.parse().map_err(|_| bad())?,
+                (Some("file"), Some(file)) => swap.file = PathBuf::from(file),
+                // whatever a later version adds
+                _ => {}
+            }
+        }
+        swap.lines = lines.collect::<io::Result<_>>()?;
+        if swap.lines.is_empty() {
+            swap.lines.push(String::new());
+        }
+        Ok(swap)
+    }
+
+    // Whether the vex that wrote this is still going, in which case the
+    // file's being edited somewhere else rather than left behind by a crash.
+    pub fn is_running(&self) -> bool {
+        self.pid == process::id() || running(self.pid)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn running(pid: u32) -> bool {
+    Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+// Without a way to tell, better to assume it might be.
+#[cfg(not(target_os = "linux"))]
+fn running(_: u32) -> bool {
+    true
+}
+
+// The swap files in `dir`, in order of name.
+pub fn find(dir: &Path) -> Vec<PathBuf> {
+    let mut found: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(_) => Vec::new(),
+    };
+    found.retain(|p| p.extension().is_some_and(|ext| ext == "swp" || ext == "swo"));
+    found.sort();
+    found
+}
+
+// What `vex -r` prints: the swap files in `dirs` and what's in each.
+pub fn list(dirs: &[PathBuf]) -> String {
+    let mut out = String::from("Swap files found:\n");
+    let mut n = 0;
+    for dir in dirs {
+        out.push_str(&format!("   In {}:\n", dir.display()));
+        let found = find(dir);
+        if found.is_empty() {
+            out.push_str("      -- none --\n");
+        }
+        for path in found {
+            n += 1;
+            out.push_str(&format!("{}.    {}\n", n, path.file_name().unwrap_or_default().to_string_lossy()));
+            match Swap::read(&path) {
+                Ok(swap) => {
+                    out.push_str(&format!("         file name: {}\n", swap.file.display()));
+                    let running = if swap.is_running() { " (STILL RUNNING)" } else { "" };
+                    out.push_str(&format!("        process ID: {}{}\n", swap.pid, running));
+                }
+                Err(e) => out.push_str(&format!("         {}\n", e)),
+            }
+            let age = fs::metadata(&path).and_then(|m| m.modified()).ok()
+                .and_then(|t| SystemTime::now().duration_since(t).ok());
+            if let Some(age) = age {
+                out.push_str(&format!("             dated: {} ago\n", age.as_secs()));
+            }
+        }
+    }
+    out
+}
+
+#[test]
+fn write_and_read() {
+    let dir = env::temp_dir().join(format!("vex-swap-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(".notes.txt.swp");
+    let lines = vec![String::from("one"), String::new(), String::from("three\r")];
+    Swap::write(&path, Path::new("/home/me/notes.txt"), lines.iter()).unwrap();
+
+    let swap = Swap::read(&path).unwrap();
+    assert_eq!(swap.pid, process::id());
+    assert_eq!(swap.file, PathBuf::from("/home/me/notes.txt"));
+    assert_eq!(swap.lines, lines);
+    assert!(swap.is_running());
+
+    assert_eq!(find(&dir), vec![path.clone()]);
+    let listed = list(::std::slice::from_ref(&dir));
+    assert!(listed.contains("1.    .notes.txt.swp\n         file name: /home/me/notes.txt\n"));
+    assert!(listed.contains("(STILL RUNNING)"));
+
+    fs::write(&path, "not a swap file\n").unwrap();
+    assert!(Swap::read(&path).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn keeps_files_private() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = env::temp_dir().join(format!("vex-swap-mode-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("secret.txt");
+    fs::write(&file, "hunter2\n").unwrap();
+    fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+    let lines = [String::from("hunter2")];
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+    let path = dir.join(".secret.txt.swp");
+    Swap::write(&path, &file, lines.iter()).unwrap();
+    assert_eq!(mode(&path), 0o600);
+    Swap::write(&path, &file, lines.iter()).unwrap();
+    assert_eq!(mode(&path), 0o600);
+    // and for a file that isn't there yet
+    let path = dir.join(".new.txt.swp");
+    Swap::write(&path, &dir.join("new.txt"), lines.iter()).unwrap();
+    assert_eq!(mode(&path), 0o600);
+    fs::remove_dir_all(&dir).unwrap();
+}
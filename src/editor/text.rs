@@ -0,0 +1,410 @@
+use std::fmt;
+use std::iter::FromIterator;
+use std::mem;
+use std::ops::Index;
+
+// how many lines a leaf holds before it splits in two
+const MAX_LINES: usize = 256;
+// how many children a branch holds before it splits in two
+const MAX_CHILDREN: usize = 16;
+
+// A B+ tree of lines. Every leaf is at the same depth and each branch
+// knows how many lines are under each of its children, so finding,
+// inserting or removing a line only touches one path from the root:
+// O(log n) no matter where in the file it happens.
+#[derive(Clone)]
+enum Node {
+    Leaf(Vec<String>),
+    // children along with how many lines are under each
+    Branch(Vec<Node>, Vec<usize>),
+}
+
+impl Node {
+    fn len(&self) -> usize {
+        match *self {
+            Node::Leaf(ref lines) => lines.len(),
+            Node::Branch(_, ref counts) => counts.iter().sum(),
+        }
+    }
+
+    // Small nodes get merged into a neighbour to keep the tree shallow.
+    fn is_small(&self) -> bool {
+        match *self {
+            Node::Leaf(ref lines) => lines.len() < MAX_LINES / 4,
+            Node::Branch(ref children, _) => children.len() < MAX_CHILDREN / 4,
+        }
+    }
+
+    fn get(&self, i: usize) -> Option<&String> {
+        match *self {
+            Node::Leaf(ref lines) => lines.get(i),
+            Node::Branch(ref children, ref counts) => {
+                locate(counts, i).and_then(|(c, i)| children[c].get(i))
+            }
+        }
+    }
+
+    // Inserts a line, handing back the new right half if this node had to
+    // split to make room.
+    fn insert(&mut self, i: usize, line: String) -> Option<Node> {
+        match *self {
+            Node::Leaf(ref mut lines) => {
+                lines.insert(i, line);
+                if lines.len() > MAX_LINES {
+                    let half = lines.len() / 2;
+                    return Some(Node::Leaf(lines.split_off(half)));
+                }
+                None
+            }
+            Node::Branch(ref mut children, ref mut counts) => {
+                // inserting at the very end goes into the last child
+                let (c, i) = locate(counts, i).unwrap_or_else(|| {
+                    let last = counts.len() - 1;
+                    (last, counts[last])
+                });
+                counts[c] += 1;
+                if let Some(right) = children[c].insert(i, line) {
+                    let moved = right.len();
+                    counts[c] -= moved;
+                    children.insert(c + 1, right);
+                    counts.insert(c + 1, moved);
+                }
+                if children.len() > MAX_CHILDREN {
+                    let half = children.len() / 2;
+                    return Some(Node::Branch(children.split_off(half), counts.split_off(half)));
+                }
+                None
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn get_mut(&mut self, i: usize) -> Option<&mut String> {
+        match *self {
+            Node::Leaf(ref mut lines) => lines.get_mut(i),
+            Node::Branch(ref mut children, ref counts) => {
+                match locate(counts, i) {
+                    Some((c, i)) => children[c].get_mut(i),
+                    None => None,
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, i: usize) -> String {
+        match *self {
+            Node::Leaf(ref mut lines) => lines.remove(i),
+            Node::Branch(ref mut children, ref mut counts) => {
+                let (c, i) = locate(counts, i).expect("line out of range");
+                let line = children[c].remove(i);
+                counts[c] -= 1;
+                if children[c].is_small() && children.len() > 1 {
+                    merge(children, counts, c);
+                }
+                line
+            }
+        }
+    }
+
+    // Adds a sibling's contents to the end of this node, splitting the
+    // result if it got too big.
+    fn append(&mut self, other: Node) -> Option<Node> {
+        match (self, other) {
+            (&mut Node::Leaf(ref mut lines), Node::Leaf(more)) => {
+                lines.extend(more);
+                if lines.len() > MAX_LINES {
+                    let half = lines.len() / 2;
+                    return Some(Node::Leaf(lines.split_off(half)));
+                }
+                None
+            }
+            (&mut Node::Branch(ref mut children, ref mut counts), Node::Branch(more, more_counts)) => {
+                children.extend(more);
+                counts.extend(more_counts);
+                if children.len() > MAX_CHILDREN {
+                    let half = children.len() / 2;
+                    return Some(Node::Branch(children.split_off(half), counts.split_off(half)));
+                }
+                None
+            }
+            _ => unreachable!("siblings are always at the same depth")
+        }
+    }
+}
+
+// Which child line `i` is under, and where it is within that child.
+fn locate(counts: &[usize], mut i: usize) -> Option<(usize, usize)> {
+    for (c, &n) in counts.iter().enumerate() {
+        if i < n {
+            return Some((c, i));
+        }
+        i -= n;
+    }
+    None
+}
+
+// Merges child `c` with a neighbour.
+fn merge(children: &mut Vec<Node>, counts: &mut Vec<usize>, c: usize) {
+    let (left, right) = if c + 1 < children.len() { (c, c + 1) } else { (c - 1, c) };
+    let node = children.remove(right);
+    counts[left] += counts.remove(right);
+    if let Some(split) = children[left].append(node) {
+        let moved = split.len();
+        counts[left] -= moved;
+        children.insert(left + 1, split);
+        counts.insert(left + 1, moved);
+    }
+}
+
+// The lines of a buffer.
+#[derive(Clone)]
+pub struct Text {
+    root: Node,
+    len: usize,
+}
+
+impl Text {
+    pub fn new() -> Text {
+        Text {
+            root: Node::Leaf(Vec::new()),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, i: usize) -> Option<&String> {
+        self.root.get(i)
+    }
+
+    // Edits go through `splice` so they can be undone; tests poke at
+    // lines directly.
+    #[cfg(test)]
+    pub fn get_mut(&mut self, i: usize) -> Option<&mut String> {
+        self.root.get_mut(i)
+    }
+
+    pub fn insert(&mut self, i: usize, line: String) {
+        assert!(i <= self.len, "line out of range");
+        if let Some(right) = self.root.insert(i, line) {
+            let left = mem::replace(&mut self.root, Node::Leaf(Vec::new()));
+            let counts = vec![left.len(), right.len()];
+            self.root = Node::Branch(vec![left, right], counts);
+        }
+        self.len += 1;
+    }
+
+    pub fn push_back(&mut self, line: String) {
+        let len = self.len;
+        self.insert(len, line);
+    }
+
+    pub fn remove(&mut self, i: usize) -> String {
+        assert!(i < self.len, "line out of range");
+        let line = self.root.remove(i);
+        self.len -= 1;
+        // a root with one child is just that child
+        let only = match self.root {
+            Node::Branch(ref mut children, _) if children.len() == 1 => children.pop(),
+            _ => None,
+        };
+        if let Some(child) = only {
+            self.root = child;
+        }
+        line
+    }
+
+    // Replaces `count` lines from `first` on with `lines`, returning the
+    // ones that were there.
+    pub fn splice(&mut self, first: usize, count: usize, lines: Vec<String>) -> Vec<String> {
+        let removed = (0..count).map(|_| self.remove(first)).collect();
+        for (i, line) in lines.into_iter().enumerate() {
+            self.insert(first + i, line);
+        }
+        removed
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        self.iter_at(0)
+    }
+
+    // Iterates from line `i` on without walking the lines before it.
+    pub fn iter_at<'a>(&'a self, mut i: usize) -> Iter<'a> {
+        let mut stack = Vec::new();
+        if i >= self.len {
+            return Iter { stack };
+        }
+        let mut node = &self.root;
+        loop {
+            match *node {
+                Node::Leaf(_) => {
+                    stack.push((node, i));
+                    return Iter { stack };
+                }
+                Node::Branch(ref children, ref counts) => {
+                    let (c, j) = locate(counts, i).unwrap();
+                    stack.push((node, c + 1));
+                    node = &children[c];
+                    i = j;
+                }
+            }
+        }
+    }
+}
+
+pub struct Iter<'a> {
+    // the nodes on the way down to the next line, each with the index of
+    // the next child (or line) to visit
+    stack: Vec<(&'a Node, usize)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a String;
+
+    fn next(&mut self) -> Option<&'a String> {
+        loop {
+            let (node, i) = self.stack.pop()?;
+            match *node {
+                Node::Leaf(ref lines) => {
+                    if i < lines.len() {
+                        self.stack.push((node, i + 1));
+                        return Some(&lines[i]);
+                    }
+                }
+                Node::Branch(ref children, _) => {
+                    if i < children.len() {
+                        self.stack.push((node, i + 1));
+                        self.stack.push((&children[i], 0));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl FromIterator<String> for Text {
+    // Builds the tree bottom up with half full nodes, leaving room to
+    // edit without splitting straight away.
+    fn from_iter<I: IntoIterator<Item=String>>(iter: I) -> Text {
+        let mut level = Vec::new();
+        let mut leaf = Vec::with_capacity(MAX_LINES / 2);
+        let mut len = 0;
+        for line in iter {
+            leaf.push(line);
+            len += 1;
+            if leaf.len() == MAX_LINES / 2 {
+                level.push(Node::Leaf(mem::replace(&mut leaf, Vec::with_capacity(MAX_LINES / 2))));
+            }
+        }
+        if !leaf.is_empty() || level.is_empty() {
+            level.push(Node::Leaf(leaf));
+        }
+        while level.len() > 1 {
+            let mut parents = Vec::new();
+            let mut nodes = level.into_iter().peekable();
+            while nodes.peek().is_some() {
+                let children: Vec<Node> = nodes.by_ref().take(MAX_CHILDREN / 2).collect();
+                let counts = children.iter().map(|c| c.len()).collect();
+                parents.push(Node::Branch(children, counts));
+            }
+            level = parents;
+        }
+        Text {
+            root: level.pop().unwrap(),
+            len,
+        }
+    }
+}
+
+impl Index<usize> for Text {
+    type Output = String;
+
+    fn index(&self, i: usize) -> &String {
+        self.get(i).expect("line out of range")
+    }
+}
+
+impl<'a> PartialEq<Vec<&'a str>> for Text {
+    fn eq(&self, other: &Vec<&'a str>) -> bool {
+        self.len == other.len() && self.iter().zip(other.iter()).all(|(a, b)| a == b)
+    }
+}
+
+impl fmt::Debug for Text {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+fn numbered(n: usize) -> Text {
+    (0..n).map(|i| i.to_string()).collect()
+}
+
+#[test]
+fn insert_and_remove() {
+    let mut text = numbered(1000);
+    assert_eq!(text.len(), 1000);
+    assert_eq!(text[999], "999");
+
+    // enough inserts in one place to split a few leaves and branches
+    for i in 0..5000 {
+        text.insert(500, format!("new {}", i));
+    }
+    assert_eq!(text.len(), 6000);
+    assert_eq!(text[499], "499");
+    assert_eq!(text[500], "new 4999");
+    assert_eq!(text[5499], "new 0");
+    assert_eq!(text[5500], "500");
+
+    let removed = text.splice(500, 5000, vec!["x".to_owned()]);
+    assert_eq!(removed.len(), 5000);
+    assert_eq!(removed[0], "new 4999");
+    assert_eq!(text.len(), 1001);
+    assert_eq!(text[500], "x");
+    assert_eq!(text[501], "500");
+
+    while text.len() > 1 {
+        text.remove(0);
+    }
+    assert_eq!(text, vec!["999"]);
+}
+
+#[test]
+fn iterate() {
+    let text = numbered(2000);
+    let all: Vec<usize> = text.iter().map(|l| l.parse().unwrap()).collect();
+    assert_eq!(all, (0..2000).collect::<Vec<_>>());
+
+    let rest: Vec<&String> = text.iter_at(1990).collect();
+    assert_eq!(rest.len(), 10);
+    assert_eq!(rest[0], "1990");
+    assert_eq!(text.iter_at(2000).next(), None);
+
+    let empty = Text::new();
+    assert_eq!(empty.iter().next(), None);
+    assert_eq!(empty.len(), 0);
+}
+
+#[test]
+#[ignore] // timing only means anything under cargo test --release -- --ignored
+fn million_line_edits_stay_fast() {
+    use std::time::{Duration, Instant};
+
+    let mut text = numbered(1000000);
+    let start = Instant::now();
+    // hop around the file the way someone editing it would
+    let mut at = 500000;
+    for i in 0..20000 {
+        at = (at * 7919 + i) % text.len();
+        text.insert(at, String::from("inserted"));
+        text.remove((at + 1000) % text.len());
+        assert!(text.get(at).is_some());
+    }
+    let elapsed = start.elapsed();
+    assert_eq!(text.len(), 1000000);
+    // a VecDeque shuffles half a million lines around on each of these
+    assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+}
@@ -0,0 +1,538 @@
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::Coord;
+
+// A single reversible edit: starting at line `first`, the lines in
+// `removed` were replaced with the lines in `inserted`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub first: usize,
+    pub removed: Vec<String>,
+    pub inserted: Vec<String>,
+}
+
+// Everything one normal mode command or insert session changed, and
+// where the point was before it started.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub changes: Vec<Change>,
+    pub point: Coord,
+    // whether it swapped the text for a hex dump or back, which undoing
+    // or redoing it does again
+    pub hex: bool,
+}
+
+// How far to travel through the history with `:earlier` and `:later`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Travel {
+    Changes(usize),
+    Seconds(u64),
+}
+
+impl Travel {
+    // Parses the argument to `:earlier`/`:later`: a count of changes, or
+    // a time like `10s`, `5m`, `2h` or `1d`. No argument means one change.
+    pub fn parse(arg: &str) -> Option<Travel> {
+        let arg = arg.trim();
+        if arg.is_empty() {
+            return Some(Travel::Changes(1));
+        }
+        let (num, unit) = match arg.find(|c: char| !c.is_ascii_digit()) {
+            Some(i) => arg.split_at(i),
+            None => (arg, ""),
+        };
+        let n = match num.parse::<u64>() {
+            Ok(n) => n,
+            Err(_) => return None,
+        };
+        let secs = match unit {
+            "" => return Some(Travel::Changes(n as usize)),
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 60 * 60 * 24,
+            _ => return None,
+        };
+        // too far back to mean anything
+        n.checked_mul(secs).map(Travel::Seconds)
+    }
+}
+
+// One move the buffer has to make to get from one state to another.
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    Undo(Group),
+    Redo(Group),
+}
+
+// A leaf of the tree, as `:undolist` shows it.
+#[derive(Debug, PartialEq)]
+pub struct Leaf {
+    pub seq: usize,
+    pub changes: usize,
+    pub time: u64,
+}
+
+// A state of the text. The root is the text as loaded; every other node
+// is the state after applying `group` to its parent. A node's index in
+// the history is also its change number.
+#[derive(Debug)]
+struct Node {
+    group: Group,
+    parent: usize,
+    // the child redo goes to, which is whichever was visited last
+    redo: Option<usize>,
+    // seconds since the epoch
+    time: u64,
+}
+
+// Undo history kept as a tree, so undoing and then making a new change
+// starts a new branch instead of throwing away the old one.
+#[derive(Debug)]
+pub struct History {
+    nodes: Vec<Node>,
+    current: usize,
+    pending: Option<Group>,
+    // the line `U` restores and what it held before the latest run of
+    // changes to it
+    line: Option<(usize, String)>,
+}
+
+impl History {
+    pub fn new() -> History {
+        let root = Node {
+            group: Group { changes: Vec::new(), point: (0, 0), hex: false },
+            parent: 0,
+            redo: None,
+            time: now(),
+        };
+        History {
+            nodes: vec![root],
+            current: 0,
+            pending: None,
+            line: None,
+        }
+    }
+
+    // Adds a change to the group in progress, starting one (from `point`)
+    // if need be.
+    pub fn record(&mut self, change: Change, point: Coord) {
+        let group = self.pending.get_or_insert(Group { changes: Vec::new(), point, hex: false });
+        if let Some(last) = group.changes.last_mut() {
+            // typing on one line turns into a single change to it
+            if last.first == change.first && last.inserted == change.removed {
+                last.inserted = change.inserted;
+                return;
+            }
+        }
+        group.changes.push(change);
+    }
+
+    // Marks the group in progress as the switch to or from a hex dump.
+    pub fn record_hex(&mut self) {
+        if let Some(ref mut group) = self.pending {
+            group.hex = true;
+        }
+    }
+
+    // Whether there are changes that haven't been committed yet.
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    // Closes the group in progress so the next change starts a new one.
+    pub fn commit(&mut self) {
+        let group = match self.pending.take() {
+            Some(group) => group,
+            None => return,
+        };
+        self.line = match (single_line(&group), self.line.take()) {
+            (Some(l), Some((prev, text))) if l == prev => Some((prev, text)),
+            (Some(l), _) => Some((l, group.changes[0].removed[0].clone())),
+            (None, _) => None,
+        };
+        let seq = self.nodes.len();
+        self.nodes.push(Node {
+            group,
+            parent: self.current,
+            redo: None,
+            time: now(),
+        });
+        self.nodes[self.current].redo = Some(seq);
+        self.current = seq;
+    }
+
+    pub fn undo(&mut self) -> Option<Group> {
+        self.commit();
+        self.line = None;
+        if self.current == 0 {
+            return None;
+        }
+        let node = self.current;
+        let parent = self.nodes[node].parent;
+        self.nodes[parent].redo = Some(node);
+        self.current = parent;
+        Some(self.nodes[node].group.clone())
+    }
+
+    pub fn redo(&mut self) -> Option<Group> {
+        self.commit();
+        self.line = None;
+        let child = self.nodes[self.current].redo?;
+        self.current = child;
+        Some(self.nodes[child].group.clone())
+    }
+
+    pub fn line(&mut self) -> Option<(usize, String)> {
+        self.commit();
+        self.line.take()
+    }
+
+    // The change number of the current state; 0 is the original text.
+    pub fn seq(&self) -> usize {
+        self.current
+    }
+
+    // Goes back through states in the order they were made, regardless of
+    // which branch they're on.
+    pub fn earlier(&mut self, travel: Travel) -> Vec<Step> {
+        self.commit();
+        let target = match travel {
+            Travel::Changes(n) => self.current.saturating_sub(n),
+            Travel::Seconds(secs) => {
+                let time = self.nodes[self.current].time.saturating_sub(secs);
+                (0..self.current).rev().find(|&n| self.nodes[n].time <= time).unwrap_or(0)
+            }
+        };
+        self.jump(target)
+    }
+
+    pub fn later(&mut self, travel: Travel) -> Vec<Step> {
+        self.commit();
+        let last = self.nodes.len() - 1;
+        let target = match travel {
+            Travel::Changes(n) => cmp::min(last, self.current + n),
+            Travel::Seconds(secs) => {
+                let time = self.nodes[self.current].time + secs;
+                (self.current..last + 1).rev().find(|&n| self.nodes[n].time <= time).unwrap_or(self.current)
+            }
+        };
+        self.jump(target)
+    }
+
+    // The tips of every branch, oldest first.
+    pub fn leaves(&self) -> Vec<Leaf> {
+        let mut parents = vec![false; self.nodes.len()];
+        for node in self.nodes.iter().skip(1) {
+            parents[node.parent] = true;
+        }
+        (1..self.nodes.len()).filter(|&n| !parents[n]).map(|n| {
+            Leaf { seq: n, changes: self.path(n).len() - 1, time: self.nodes[n].time }
+        }).collect()
+    }
+
+    // The steps from the current state to `target`: up the tree to where
+    // the two branches meet, then back down.
+    fn jump(&mut self, target: usize) -> Vec<Step> {
+        self.line = None;
+        let from = self.path(self.current);
+        let to = self.path(target);
+        let common = from.iter().zip(to.iter()).take_while(|&(a, b)| a == b).count();
+
+        let mut steps = Vec::new();
+        for &node in from[common..].iter().rev() {
+            let parent = self.nodes[node].parent;
+            self.nodes[parent].redo = Some(node);
+            steps.push(Step::Undo(self.nodes[node].group.clone()));
+        }
+        for &node in to[common..].iter() {
+            let parent = self.nodes[node].parent;
+            self.nodes[parent].redo = Some(node);
+            steps.push(Step::Redo(self.nodes[node].group.clone()));
+        }
+        self.current = target;
+        steps
+    }
+
+    // The nodes from the root down to `node`, inclusive.
+    fn path(&self, mut node: usize) -> Vec<usize> {
+        let mut path = vec![node];
+        while node != 0 {
+            node = self.nodes[node].parent;
+            path.push(node);
+        }
+        path.reverse();
+        path
+    }
+}
+
+// Undo files start with this so we don't go reading anything else.
+const MAGIC: &str = "VexUndo 1";
+
+impl History {
+    // Saves the committed history along with a hash of the text it leads
+    // up to, so it can be checked against the file before it's reused.
+    //
+    // After a header line, each node is a line of numbers
+    //     parent time redo point.col point.line #changes hex
+    // and each change is a line
+    //     first #removed #inserted
+    // followed by the removed and then the inserted lines themselves.
+    pub fn write<W: Write>(&self, w: &mut W, hash: u64) -> io::Result<()> {
+        writeln!(w, "{}", MAGIC)?;
+        writeln!(w, "{:x} {} {}", hash, self.current, self.nodes.len())?;
+        for node in self.nodes.iter() {
+            let redo = node.redo.map_or(String::from("-"), |n| n.to_string());
+            let (col, line) = node.group.point;
+            writeln!(w, "{} {} {} {} {} {} {}", node.parent, node.time, redo, col, line,
+                          node.group.changes.len(), node.group.hex as u8)?;
+            for change in node.group.changes.iter() {
+                writeln!(w, "{} {} {}", change.first, change.removed.len(), change.inserted.len())?;
+                for text in change.removed.iter().chain(change.inserted.iter()) {
+                    writeln!(w, "{}", text)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Loads a history written by `write`, or None if it was saved for some
+    // other text than the one with this hash.
+    pub fn read<R: BufRead>(r: R, hash: u64) -> io::Result<Option<History>> {
+        // not `lines()`, which would take a carriage return off the end
+        let mut lines = r.split(b'\n').map(|line| {
+            line.and_then(|l| String::from_utf8(l).map_err(|_| invalid("not utf-8")))
+        });
+        let mut next = move || lines.next().unwrap_or_else(|| Err(invalid("unexpected end of file")));
+
+        if next()? != MAGIC {
+            return Err(invalid("not an undo file"));
+        }
+        let header = next()?;
+        let header: Vec<&str> = header.split(' ').collect();
+        if header.len() != 3 {
+            return Err(invalid("bad header"));
+        }
+        if u64::from_str_radix(header[0], 16).ok() != Some(hash) {
+            return Ok(None);
+        }
+        let current = number(header[1])?;
+        let count = number(header[2])?;
+
+        let mut nodes = Vec::with_capacity(count);
+        for _ in 0..count {
+            let line = next()?;
+            let fields: Vec<&str> = line.split(' ').collect();
+            // files from before `:hex` was undoable don't say
+            let hex = match fields.get(6) {
+                None | Some(&"0") => false,
+                Some(&"1") => true,
+                Some(_) => return Err(invalid("bad node")),
+            };
+            if fields.len() != 6 && fields.len() != 7 {
+                return Err(invalid("bad node"));
+            }
+            let redo = match fields[2] {
+                "-" => None,
+                n => Some(number(n)?),
+            };
+            let mut changes = Vec::new();
+            for _ in 0..number(fields[5])? {
+                let line = next()?;
+                let sizes: Vec<&str> = line.split(' ').collect();
+                if sizes.len() != 3 {
+                    return Err(invalid("bad change"));
+                }
+                let mut removed = Vec::new();
+                for _ in 0..number(sizes[1])? {
+                    removed.push(next()?);
+                }
+                let mut inserted = Vec::new();
+                for _ in 0..number(sizes[2])? {
+                    inserted.push(next()?);
+                }
+                changes.push(Change { first: number(sizes[0])?, removed, inserted });
+            }
+            nodes.push(Node {
+                group: Group { changes, point: (number(fields[3])?, number(fields[4])?), hex },
+                parent: number(fields[0])?,
+                redo,
+                time: number(fields[1])? as u64,
+            });
+        }
+        let sane = current < nodes.len() && nodes.iter().enumerate().all(|(i, node)| {
+            (i == 0 || node.parent < i) && node.redo.is_none_or(|r| r > i && r < nodes.len())
+        });
+        if !sane {
+            return Err(invalid("bad tree"));
+        }
+        Ok(Some(History {
+            nodes,
+            current,
+            pending: None,
+            line: None,
+        }))
+    }
+}
+
+// A hash of the text to tie an undo file to, FNV-1a over every line.
+pub fn hash_lines<'a, I>(lines: I) -> u64 where I: Iterator<Item=&'a String> {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for line in lines {
+        for b in line.bytes().chain(Some(b'\n')) {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+fn number(s: &str) -> io::Result<usize> {
+    s.parse().map_err(|_| invalid("bad number"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// The line a group changed, if it only changed one.
+fn single_line(group: &Group) -> Option<usize> {
+    let first = group.changes[0].first;
+    let same = group.changes.iter().all(|c| {
+        c.first == first && c.removed.len() == 1 && c.inserted.len() == 1
+    });
+    if same { Some(first) } else { None }
+}
+
+#[cfg(test)]
+fn change(first: usize, removed: &[&str], inserted: &[&str]) -> Change {
+    Change {
+        first,
+        removed: removed.iter().map(|s| s.to_string()).collect(),
+        inserted: inserted.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+#[test]
+fn typing_merges_into_one_change() {
+    let mut history = History::new();
+    history.record(change(0, &[""], &["a"]), (0, 0));
+    history.record(change(0, &["a"], &["ab"]), (1, 0));
+    history.commit();
+
+    let group = history.undo().unwrap();
+    assert_eq!(group.changes, vec![change(0, &[""], &["ab"])]);
+    assert_eq!(group.point, (0, 0));
+    assert_eq!(history.undo(), None);
+    assert_eq!(history.redo(), Some(group));
+}
+
+#[test]
+fn new_changes_branch() {
+    let mut history = History::new();
+    history.record(change(0, &["a"], &["b"]), (0, 0));
+    history.commit();
+    history.undo();
+    history.record(change(0, &["a"], &["c"]), (0, 0));
+    history.commit();
+    assert_eq!(history.redo(), None);
+    assert_eq!(history.seq(), 2);
+
+    // but the old branch is still there, one step back in time
+    let steps = history.earlier(Travel::Changes(1));
+    assert_eq!(steps, vec![
+        Step::Undo(Group { changes: vec![change(0, &["a"], &["c"])], point: (0, 0), hex: false }),
+        Step::Redo(Group { changes: vec![change(0, &["a"], &["b"])], point: (0, 0), hex: false }),
+    ]);
+    assert_eq!(history.seq(), 1);
+    // and redo now follows the branch we came from
+    history.undo();
+    assert_eq!(history.redo().unwrap().changes, vec![change(0, &["a"], &["b"])]);
+
+    let leaves: Vec<usize> = history.leaves().iter().map(|l| l.seq).collect();
+    assert_eq!(leaves, vec![1, 2]);
+}
+
+#[test]
+fn travel_by_time() {
+    let mut history = History::new();
+    for (i, t) in [100, 200, 300].iter().enumerate() {
+        history.record(change(0, &[""], &["x"]), (i, 0));
+        history.commit();
+        history.nodes[i + 1].time = *t;
+    }
+    history.nodes[0].time = 50;
+
+    assert_eq!(history.earlier(Travel::Seconds(150)).len(), 2);
+    assert_eq!(history.seq(), 1);
+    assert_eq!(history.earlier(Travel::Seconds(1000)).len(), 1);
+    assert_eq!(history.seq(), 0);
+    assert_eq!(history.later(Travel::Seconds(200)).len(), 2);
+    assert_eq!(history.seq(), 2);
+    assert_eq!(history.later(Travel::Changes(10)).len(), 1);
+    assert_eq!(history.seq(), 3);
+}
+
+#[test]
+fn line_undo_spans_commands() {
+    let mut history = History::new();
+    history.record(change(2, &["one"], &["on"]), (0, 2));
+    history.commit();
+    history.record(change(2, &["on"], &["o"]), (0, 2));
+    history.commit();
+    assert_eq!(history.line(), Some((2, "one".to_owned())));
+
+    history.record(change(2, &["o"], &["o", ""]), (0, 2));
+    history.commit();
+    assert_eq!(history.line(), None);
+}
+
+#[test]
+fn parse_travel() {
+    assert_eq!(Travel::parse(""), Some(Travel::Changes(1)));
+    assert_eq!(Travel::parse("3"), Some(Travel::Changes(3)));
+    assert_eq!(Travel::parse("10m"), Some(Travel::Seconds(600)));
+    assert_eq!(Travel::parse(" 1d "), Some(Travel::Seconds(86400)));
+    assert_eq!(Travel::parse("10x"), None);
+    assert_eq!(Travel::parse("m"), None);
+    assert_eq!(Travel::parse("999999999999999999d"), None);
+}
+
+#[test]
+fn write_and_read() {
+    let mut history = History::new();
+    history.record(change(0, &["a"], &["b", ""]), (1, 0));
+    history.commit();
+    history.undo();
+    history.record(change(0, &["a"], &["c d"]), (0, 0));
+    history.commit();
+
+    let mut file = Vec::new();
+    history.write(&mut file, 42).unwrap();
+
+    assert!(History::read(&file[..], 7).unwrap().is_none());
+    let mut read = History::read(&file[..], 42).unwrap().unwrap();
+    assert_eq!(read.seq(), 2);
+    assert_eq!(read.undo(), history.undo());
+    assert_eq!(read.earlier(Travel::Changes(1)), history.earlier(Travel::Changes(1)));
+
+    let truncated = &file[..file.len() - "c d\n".len()];
+    assert!(History::read(truncated, 42).is_err());
+
+    // lines keep their carriage returns and whatever else a binary file has
+    let mut history = History::new();
+    history.record(change(0, &["a\r"], &["b\r", "\u{0}\u{ff}"]), (0, 0));
+    history.record_hex();
+    history.commit();
+    let mut file = Vec::new();
+    history.write(&mut file, 42).unwrap();
+    let mut read = History::read(&file[..], 42).unwrap().unwrap();
+    assert_eq!(read.undo(), history.undo());
+}
@@ -4,11 +4,14 @@ extern crate getopts;
 
 use getopts::Options;
 use std::env;
-use std::path::Path;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process;
 
 use self::rustbox::{RustBox};
 
-use vex::editor::{State};
+use vex::editor::{catch_panics, catch_signals, caught_signal, list_swaps, panic_message, State};
 
 fn main() {
 	let args: Vec<String> = env::args().collect();
@@ -16,25 +19,65 @@ fn main() {
 
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this help menu");
+    opts.optflag("b", "binary", "edit files as binary, keeping every byte as it is");
+    opts.optflag("r", "", "list swap files, or recover the files named from theirs");
+    opts.optflag("", "undofile", "keep undo history in files across sessions");
+    opts.optopt("", "undodir", "keep undo files in DIR (implies --undofile)", "DIR");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
-        Err(f) => { panic!(f.to_string()) }
+        Err(f) => { panic!("{}", f.to_string()) }
     };
     if matches.opt_present("h") {
         print_usage(&program, opts);
         return;
     }
+    if matches.opt_present("r") && matches.free.is_empty() {
+        print!("{}", list_swaps(&[PathBuf::from(".")]));
+        return;
+    }
 
+    catch_signals();
     let rustbox = match RustBox::init(Default::default()) {
         Result::Ok(v) => v,
         Result::Err(e) => panic!("{}", e),
     };
+    catch_panics();
 
 	let mut state = State::new(rustbox.width(), rustbox.height());
-	for name in matches.free {
-		state.open(Path::new(&name));
+	{
+		let options = state.options_mut();
+		options.undodir = matches.opt_str("undodir").map(PathBuf::from);
+		options.undofile = matches.opt_present("undofile") || options.undodir.is_some();
+	}
+	let binary = matches.opt_present("b");
+	let recover = matches.opt_present("r");
+	let edited = panic::catch_unwind(AssertUnwindSafe(|| {
+		for name in &matches.free {
+			if recover {
+				state.recover(Path::new(name), binary);
+			} else {
+				state.open(Path::new(name), binary);
+			}
+		}
+		state.edit(&rustbox);
+	}));
+	// put the terminal back before saying anything
+	drop(rustbox);
+	let _ = panic::take_hook();
+
+	let why = match edited {
+		Ok(()) => caught_signal().map(|signal| format!("Caught deadly signal {}", signal)),
+		Err(_) => Some(panic_message().unwrap_or_else(|| String::from("panicked"))),
+	};
+	if let Some(why) = why {
+		// stderr may be gone along with the terminal
+		let mut stderr = io::stderr();
+		let _ = writeln!(stderr, "vex: {}", why);
+		for saved in state.preserve() {
+			let _ = writeln!(stderr, "vex: {}", saved);
+		}
+		process::exit(1);
 	}
-	state.edit(&rustbox);
 }
 
 fn print_usage(program: &str, opts: Options) {